@@ -0,0 +1,455 @@
+//! Access log of the HTTP requests. Entries are queued to a thread that formats and
+//! writes them in batches, so that a request only pays for the queueing.
+
+use crate::date;
+use anyhow::Result;
+use hyper::{Method, Version};
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::os::unix::net::UnixDatagram;
+use std::path::PathBuf;
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+
+/// Entries that can be queued before new ones are dropped
+const QUEUE_SIZE: usize = 65536;
+/// Size from which a batch is written without waiting for the queue to empty
+const BATCH_SIZE: usize = 64 * 1024;
+const SYSLOG_PATH: &str = "/dev/log";
+/// Facility `daemon` with severity `info`, as defined in RFC 5424
+const SYSLOG_PRIORITY: u8 = 3 * 8 + 6;
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AccessLogFormat {
+	/// Common Log Format
+	Common,
+	/// Combined Log Format, which adds the referer and user agent
+	Combined,
+	/// One JSON object per line, with all fields
+	Json,
+}
+
+/// One HTTP request.
+pub struct Entry {
+	pub time: SystemTime,
+	/// Address of the client, after the trusted proxy headers
+	pub client: IpAddr,
+	/// Address of the listener
+	pub listener: Arc<str>,
+	pub method: Method,
+	/// Path and query
+	pub target: String,
+	pub version: Version,
+	pub tls_version: Option<&'static str>,
+	pub status: u16,
+	/// Size of the response body
+	pub bytes: u64,
+	/// Time from receiving the request head until the response was ready
+	pub latency: Duration,
+	pub referer: Option<String>,
+	pub user_agent: Option<String>,
+	/// Fingerprints of the TLS ClientHello, only set if they are to be logged
+	pub ja3: Option<String>,
+	pub ja4: Option<String>,
+}
+
+enum Message {
+	Entry(Box<Entry>),
+	/// Wakes the writer to reopen the file
+	Reopen,
+	/// Closes the queue, after which the writer finishes what was queued before and exits
+	Close,
+}
+
+pub struct AccessLog {
+	tx: mpsc::Sender<Message>,
+	/// Checked by the writer after every batch, in case the queue was full
+	reopen: Arc<AtomicBool>,
+	fingerprints: bool,
+	/// Taken when the log is closed
+	writer: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl AccessLog {
+	/// Opens the output, which is `stdout`, `syslog` or a file path, and starts the writer thread.
+	/// `fingerprints` adds the JA3 and JA4 fingerprints to the entries, which are only
+	/// written in the JSON format.
+	pub fn start(output: &str, format: AccessLogFormat, fingerprints: bool) -> Result<Self> {
+		let output = Output::open(output)?;
+		let (tx, rx) = mpsc::channel(QUEUE_SIZE);
+		let reopen = Arc::new(AtomicBool::new(false));
+
+		let writer_reopen = reopen.clone();
+		let writer = thread::Builder::new()
+			.name("access-log".to_string())
+			.spawn(move || write_entries(rx, output, format, &writer_reopen))?;
+
+		Ok(AccessLog {
+			tx,
+			reopen,
+			fingerprints,
+			writer: Mutex::new(Some(writer)),
+		})
+	}
+
+	/// Whether the entries should have the fingerprints of the TLS client.
+	pub fn fingerprints(&self) -> bool {
+		self.fingerprints
+	}
+
+	/// Queues an entry. Returns `false` if the queue is full and the entry was dropped.
+	pub fn log(&self, entry: Entry) -> bool {
+		self.tx.try_send(Message::Entry(Box::new(entry))).is_ok()
+	}
+
+	/// Opens the log file again, after it was moved away by logrotate. Does not wait
+	/// for the writer, which picks this up after its current batch.
+	pub fn reopen(&self) {
+		self.reopen.store(true, Ordering::Relaxed);
+		let _ = self.tx.try_send(Message::Reopen);
+	}
+
+	/// Closes the queue and waits until the writer has written and flushed everything
+	/// that was queued. Entries logged afterwards are dropped.
+	pub async fn close(&self) {
+		// Waits for room in the queue rather than dropping the message, since the writer
+		// only exits once it sees it
+		let _ = self.tx.send(Message::Close).await;
+
+		let writer = self.writer.lock().unwrap().take();
+		if let Some(writer) = writer {
+			let _ = tokio::task::spawn_blocking(move || writer.join()).await;
+		}
+	}
+}
+
+enum Output {
+	Stdout(io::Stdout),
+	File { path: PathBuf, file: File },
+	Syslog { socket: UnixDatagram, failed: bool },
+}
+
+impl Output {
+	fn open(output: &str) -> Result<Self> {
+		match output {
+			"stdout" | "-" => Ok(Output::Stdout(io::stdout())),
+			"syslog" => {
+				let socket = UnixDatagram::unbound()?;
+				socket.connect(SYSLOG_PATH).map_err(|e| {
+					anyhow::Error::msg(format!(
+						"failed to connect to syslog at {}: {}",
+						SYSLOG_PATH, e
+					))
+				})?;
+				Ok(Output::Syslog {
+					socket,
+					failed: false,
+				})
+			}
+			path => {
+				let path = PathBuf::from(path);
+				let file = open_file(&path).map_err(|e| {
+					anyhow::Error::msg(format!(
+						"failed to open access log {}: {}",
+						path.display(),
+						e
+					))
+				})?;
+				Ok(Output::File { path, file })
+			}
+		}
+	}
+
+	/// Writes a batch of lines.
+	fn write(&mut self, lines: &[u8]) {
+		let res = match self {
+			Output::Stdout(stdout) => stdout.lock().write_all(lines),
+			Output::File { file, .. } => file.write_all(lines),
+			Output::Syslog { socket, failed } => {
+				// Every line is a message of its own
+				for line in lines.split(|b| *b == b'\n').filter(|line| !line.is_empty()) {
+					let mut message =
+						format!("<{}>wut-server[{}]: ", SYSLOG_PRIORITY, process::id())
+							.into_bytes();
+					message.extend_from_slice(line);
+
+					// Messages are lost while syslog is restarted, which is only reported once
+					match socket.send(&message) {
+						Ok(_) => *failed = false,
+						Err(e) if !*failed => {
+							*failed = true;
+							warn!("Failed to send access log to syslog: {}", e);
+						}
+						Err(_) => {}
+					}
+				}
+				Ok(())
+			}
+		};
+
+		if let Err(e) = res {
+			warn!("Failed to write access log: {}", e);
+		}
+	}
+
+	fn flush(&mut self) {
+		let res = match self {
+			Output::Stdout(stdout) => stdout.lock().flush(),
+			Output::File { file, .. } => file.flush(),
+			Output::Syslog { .. } => Ok(()),
+		};
+
+		if let Err(e) = res {
+			warn!("Failed to write access log: {}", e);
+		}
+	}
+
+	fn reopen(&mut self) {
+		if let Output::File { path, file } = self {
+			match open_file(path) {
+				Ok(new_file) => *file = new_file,
+				Err(e) => warn!("Failed to reopen access log {}: {}", path.display(), e),
+			}
+		}
+	}
+}
+
+fn open_file(path: &PathBuf) -> io::Result<File> {
+	OpenOptions::new().create(true).append(true).open(path)
+}
+
+fn write_entries(
+	mut rx: mpsc::Receiver<Message>,
+	mut output: Output,
+	format: AccessLogFormat,
+	reopen: &AtomicBool,
+) {
+	let mut lines = Vec::new();
+
+	while let Some(message) = rx.blocking_recv() {
+		// Everything that was queued in the meantime goes into the same write
+		let mut next = Some(message);
+		while let Some(message) = next {
+			match message {
+				Message::Entry(entry) => format_entry(&mut lines, &entry, format),
+				Message::Reopen => {}
+				// The rest of the queue is still received until it is empty
+				Message::Close => rx.close(),
+			}
+
+			next = match lines.len() < BATCH_SIZE {
+				true => rx.try_recv().ok(),
+				false => None,
+			};
+		}
+
+		output.write(&lines);
+		lines.clear();
+
+		if reopen.swap(false, Ordering::Relaxed) {
+			output.reopen();
+		}
+	}
+
+	output.flush();
+}
+
+#[derive(Serialize)]
+struct JsonEntry<'a> {
+	time: String,
+	client: IpAddr,
+	listener: &'a str,
+	method: &'a str,
+	path: &'a str,
+	version: String,
+	tls_version: Option<&'static str>,
+	status: u16,
+	bytes: u64,
+	latency_us: u128,
+	referer: Option<&'a str>,
+	user_agent: Option<&'a str>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	ja3: Option<&'a str>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	ja4: Option<&'a str>,
+}
+
+fn format_entry(out: &mut Vec<u8>, entry: &Entry, format: AccessLogFormat) {
+	if format == AccessLogFormat::Json {
+		let json = JsonEntry {
+			time: rfc3339(entry.time),
+			client: entry.client,
+			listener: &entry.listener,
+			method: entry.method.as_str(),
+			path: &entry.target,
+			version: format!("{:?}", entry.version),
+			tls_version: entry.tls_version,
+			status: entry.status,
+			bytes: entry.bytes,
+			latency_us: entry.latency.as_micros(),
+			referer: entry.referer.as_deref(),
+			user_agent: entry.user_agent.as_deref(),
+			ja3: entry.ja3.as_deref(),
+			ja4: entry.ja4.as_deref(),
+		};
+		let _ = serde_json::to_writer(&mut *out, &json);
+		out.push(b'\n');
+		return;
+	}
+
+	// host ident authuser [date] "request" status bytes
+	let _ = write!(
+		out,
+		"{} - - [{}] \"{} {} {:?}\" {} {}",
+		entry.client,
+		clf_time(entry.time),
+		entry.method,
+		escape(&entry.target),
+		entry.version,
+		entry.status,
+		entry.bytes
+	);
+
+	if format == AccessLogFormat::Combined {
+		let _ = write!(
+			out,
+			" \"{}\" \"{}\"",
+			escape(entry.referer.as_deref().unwrap_or("-")),
+			escape(entry.user_agent.as_deref().unwrap_or("-"))
+		);
+	}
+
+	out.push(b'\n');
+}
+
+/// Escapes quotes, backslashes and control characters, so that client supplied text
+/// cannot end a field or forge a line.
+fn escape(text: &str) -> String {
+	let mut escaped = String::with_capacity(text.len());
+	for c in text.chars() {
+		match c {
+			'"' => escaped.push_str("\\\""),
+			'\\' => escaped.push_str("\\\\"),
+			c if c.is_control() => escaped.push_str(&format!("\\x{:02x}", c as u32)),
+			c => escaped.push(c),
+		}
+	}
+	escaped
+}
+
+/// The date and time in UTC, as in `2024-01-31T13:05:09.123Z`.
+pub fn rfc3339(time: SystemTime) -> String {
+	let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+	let (year, month, day, hour, minute, second) = date::utc(since_epoch.as_secs());
+	format!(
+		"{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+		year,
+		month,
+		day,
+		hour,
+		minute,
+		second,
+		since_epoch.subsec_millis()
+	)
+}
+
+/// The date and time in UTC, as in `31/Jan/2024:13:05:09 +0000`.
+fn clf_time(time: SystemTime) -> String {
+	const MONTHS: [&str; 12] = [
+		"Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+	];
+
+	let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+	let (year, month, day, hour, minute, second) = date::utc(since_epoch.as_secs());
+	format!(
+		"{:02}/{}/{:04}:{:02}:{:02}:{:02} +0000",
+		day,
+		MONTHS[month as usize - 1],
+		year,
+		hour,
+		minute,
+		second
+	)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn entry() -> Entry {
+		Entry {
+			time: UNIX_EPOCH + Duration::from_millis(951868799123),
+			client: "192.0.2.1".parse().unwrap(),
+			listener: Arc::from("[::]:443"),
+			method: Method::GET,
+			target: "/json?a=\"b\"".to_string(),
+			version: Version::HTTP_2,
+			tls_version: Some("TLSv1.3"),
+			status: 200,
+			bytes: 58,
+			latency: Duration::from_micros(70),
+			referer: None,
+			user_agent: Some("curl/7.88.1".to_string()),
+			ja3: None,
+			ja4: None,
+		}
+	}
+
+	fn format(entry: &Entry, format: AccessLogFormat) -> String {
+		let mut out = Vec::new();
+		format_entry(&mut out, entry, format);
+		String::from_utf8(out).unwrap()
+	}
+
+	#[test]
+	fn combined() {
+		assert_eq!(
+			format(&entry(), AccessLogFormat::Combined),
+			"192.0.2.1 - - [29/Feb/2000:23:59:59 +0000] \"GET /json?a=\\\"b\\\" HTTP/2.0\" 200 58 \
+			 \"-\" \"curl/7.88.1\"\n"
+		);
+	}
+
+	#[tokio::test(flavor = "multi_thread")]
+	async fn close_writes_queued_entries() {
+		let path = std::env::temp_dir().join(format!("wut-access-log-{}", process::id()));
+		let _ = std::fs::remove_file(&path);
+
+		let log = AccessLog::start(path.to_str().unwrap(), AccessLogFormat::Common, false).unwrap();
+		for _ in 0..1000 {
+			assert!(log.log(entry()));
+		}
+		log.close().await;
+
+		// The queue is closed
+		assert!(!log.log(entry()));
+
+		let written = std::fs::read_to_string(&path).unwrap();
+		std::fs::remove_file(&path).unwrap();
+		assert_eq!(written.lines().count(), 1000);
+	}
+
+	#[test]
+	fn json_fingerprints() {
+		let line = format(&entry(), AccessLogFormat::Json);
+		assert!(line.starts_with("{\"time\":\"2000-02-29T23:59:59.123Z\""));
+		assert!(!line.contains("ja3") && !line.contains("ja4"));
+
+		let entry = Entry {
+			ja3: Some("771,4865,0,29,0".to_string()),
+			ja4: Some("t13i0101h2_aaaaaaaaaaaa_bbbbbbbbbbbb".to_string()),
+			..entry()
+		};
+		let line = format(&entry, AccessLogFormat::Json);
+		assert!(line.ends_with(
+			"\"ja3\":\"771,4865,0,29,0\",\"ja4\":\"t13i0101h2_aaaaaaaaaaaa_bbbbbbbbbbbb\"}\n"
+		));
+	}
+}
@@ -0,0 +1,588 @@
+use crate::date;
+use crate::error;
+use crate::tls::{self, CertPair, CertResolver};
+use anyhow::{Context, Result};
+use hyper::{Body, Request, Response, StatusCode};
+use instant_acme::{
+	Account, AccountCredentials, AuthorizationStatus, ChallengeType, HttpClient, Identifier,
+	NewAccount, NewOrder, OrderStatus,
+};
+use rcgen::{CertificateParams, CustomExtension, DistinguishedName};
+use rustls::sign::CertifiedKey;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::{fs, io};
+use tokio::time;
+
+/// Renew certificates that expire within this time.
+const RENEW_BEFORE: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// How often the certificate is checked for renewal.
+const CHECK_INTERVAL: Duration = Duration::from_secs(12 * 60 * 60);
+
+/// How long to wait before retrying a failed issuance.
+const RETRY_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+const HTTP01_PATH_PREFIX: &str = "/.well-known/acme-challenge/";
+
+/// Key authorizations for pending HTTP-01 challenges, indexed by token.
+pub type Http01Tokens = RwLock<HashMap<String, String>>;
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AcmeChallenge {
+	/// Served on the TLS listeners, which must be reachable on port 443
+	TlsAlpn01,
+	/// Served on the --acme-http-bind listener, which must be reachable on port 80
+	Http01,
+}
+
+pub struct AcmeConfig {
+	pub domains: Vec<String>,
+	pub contact: Vec<String>,
+	pub directory_url: String,
+	/// Extra root certificate for the ACME server, e.g. for a local test CA.
+	pub root_cert: Option<String>,
+	pub state_dir: String,
+	pub challenge: AcmeChallenge,
+}
+
+impl AcmeConfig {
+	/// The certificate and key pair written by the ACME client.
+	pub fn cert_pair(&self) -> CertPair {
+		let dir = Path::new(&self.state_dir);
+		CertPair {
+			cert_path: dir.join("fullchain.pem").to_string_lossy().into_owned(),
+			key_path: dir.join("privkey.pem").to_string_lossy().into_owned(),
+		}
+	}
+
+	fn account_path(&self) -> String {
+		Path::new(&self.state_dir)
+			.join("account.json")
+			.to_string_lossy()
+			.into_owned()
+	}
+}
+
+/// Obtains and renews the certificate for the configured domains, and swaps it into the resolver.
+pub async fn run_acme(config: AcmeConfig, resolver: Arc<CertResolver>, tokens: Arc<Http01Tokens>) {
+	loop {
+		let wait = match renew_if_needed(&config, &resolver, &tokens).await {
+			Ok(wait) => wait,
+			Err(e) => {
+				error!("ACME certificate issuance failed: {:#}", e);
+				RETRY_INTERVAL
+			}
+		};
+
+		time::sleep(wait).await;
+	}
+}
+
+/// Issues a new certificate if needed and returns the time until the next check.
+async fn renew_if_needed(
+	config: &AcmeConfig,
+	resolver: &CertResolver,
+	tokens: &Http01Tokens,
+) -> Result<Duration> {
+	let pair = config.cert_pair();
+
+	if let Some(not_after) = current_cert_not_after(config, &pair) {
+		let remaining = not_after
+			.duration_since(SystemTime::now())
+			.unwrap_or_default();
+
+		if remaining > RENEW_BEFORE {
+			return Ok((remaining - RENEW_BEFORE).min(CHECK_INTERVAL));
+		}
+		info!("ACME certificate expires soon. Renewing...");
+	} else {
+		info!(
+			"Requesting ACME certificate for {}",
+			config.domains.join(", ")
+		);
+	}
+
+	issue(config, resolver, tokens).await?;
+	resolver.reload()?;
+	info!("ACME certificate issued for {}", config.domains.join(", "));
+
+	Ok(CHECK_INTERVAL)
+}
+
+/// Returns the expiry of the stored certificate, if it exists and covers all configured domains.
+fn current_cert_not_after(config: &AcmeConfig, pair: &CertPair) -> Option<SystemTime> {
+	let certs = tls::load_certs(&pair.cert_path).ok()?;
+	let cert = certs.first()?;
+
+	let names = tls::cert_dns_names(cert).ok()?;
+	let covers_all = config
+		.domains
+		.iter()
+		.all(|d| names.contains(&d.to_ascii_lowercase()));
+
+	if !covers_all {
+		return None;
+	}
+
+	cert_not_after(&cert.0)
+}
+
+async fn issue(config: &AcmeConfig, resolver: &CertResolver, tokens: &Http01Tokens) -> Result<()> {
+	fs::create_dir_all(&config.state_dir)
+		.with_context(|| format!("failed to create ACME state directory {}", config.state_dir))?;
+
+	let account = load_or_create_account(config).await?;
+
+	let identifiers: Vec<Identifier> = config
+		.domains
+		.iter()
+		.map(|d| Identifier::Dns(d.clone()))
+		.collect();
+	let mut order = account
+		.new_order(&NewOrder {
+			identifiers: &identifiers,
+		})
+		.await?;
+
+	let challenge_type = match config.challenge {
+		AcmeChallenge::TlsAlpn01 => ChallengeType::TlsAlpn01,
+		AcmeChallenge::Http01 => ChallengeType::Http01,
+	};
+
+	let mut pending = Vec::new();
+
+	for authorization in order.authorizations().await? {
+		match authorization.status {
+			AuthorizationStatus::Pending => {}
+			AuthorizationStatus::Valid => continue,
+			status => anyhow::bail!("unexpected authorization status {:?}", status),
+		}
+
+		let Identifier::Dns(domain) = &authorization.identifier;
+
+		let challenge = authorization
+			.challenges
+			.iter()
+			.find(|c| c.r#type == challenge_type)
+			.with_context(|| format!("no {:?} challenge offered for {}", challenge_type, domain))?;
+
+		let key_authorization = order.key_authorization(challenge);
+
+		match config.challenge {
+			AcmeChallenge::TlsAlpn01 => {
+				let certified_key =
+					alpn_challenge_cert(domain, key_authorization.digest().as_ref())?;
+				resolver.set_alpn_challenge(domain, certified_key);
+			}
+			AcmeChallenge::Http01 => {
+				tokens.write().unwrap().insert(
+					challenge.token.clone(),
+					key_authorization.as_str().to_string(),
+				);
+			}
+		}
+
+		pending.push((
+			domain.clone(),
+			challenge.token.clone(),
+			challenge.url.clone(),
+		));
+	}
+
+	for (_, _, url) in &pending {
+		order.set_challenge_ready(url).await?;
+	}
+
+	let result = wait_for_order(&mut order).await;
+
+	for (domain, token, _) in &pending {
+		resolver.clear_alpn_challenge(domain);
+		tokens.write().unwrap().remove(token);
+	}
+
+	result?;
+
+	let mut params = CertificateParams::new(config.domains.clone());
+	params.distinguished_name = DistinguishedName::new();
+	let cert = rcgen::Certificate::from_params(params)?;
+
+	order.finalize(&cert.serialize_request_der()?).await?;
+
+	let mut tries = 0;
+	let cert_chain_pem = loop {
+		if let Some(pem) = order.certificate().await? {
+			break pem;
+		}
+		tries += 1;
+		if tries > 10 {
+			anyhow::bail!("timed out waiting for the certificate to be issued");
+		}
+		time::sleep(Duration::from_secs(1)).await;
+	};
+
+	write_pair(
+		&config.cert_pair(),
+		cert_chain_pem.as_bytes(),
+		cert.serialize_private_key_pem().as_bytes(),
+	)?;
+
+	Ok(())
+}
+
+/// Polls the order until the ACME server has validated all challenges.
+async fn wait_for_order(order: &mut instant_acme::Order) -> Result<()> {
+	let mut delay = Duration::from_millis(250);
+
+	for _ in 0..10 {
+		time::sleep(delay).await;
+
+		let state = order.refresh().await?;
+		match state.status {
+			OrderStatus::Ready | OrderStatus::Valid => return Ok(()),
+			OrderStatus::Invalid => {
+				anyhow::bail!("order is invalid: {:?}", state.error)
+			}
+			OrderStatus::Pending | OrderStatus::Processing => {}
+		}
+
+		delay *= 2;
+	}
+
+	anyhow::bail!("timed out waiting for the challenges to be validated")
+}
+
+async fn load_or_create_account(config: &AcmeConfig) -> Result<Account> {
+	let account_path = config.account_path();
+
+	if let Ok(json) = fs::read_to_string(&account_path) {
+		let credentials: AccountCredentials = serde_json::from_str(&json)
+			.with_context(|| format!("failed to parse {}", account_path))?;
+		return Ok(Account::from_credentials_and_http(credentials, http_client(config)?).await?);
+	}
+
+	let contact: Vec<String> = config
+		.contact
+		.iter()
+		.map(|c| format!("mailto:{}", c))
+		.collect();
+	let contact: Vec<&str> = contact.iter().map(String::as_str).collect();
+
+	let (account, credentials) = Account::create_with_http(
+		&NewAccount {
+			contact: &contact,
+			terms_of_service_agreed: true,
+			only_return_existing: false,
+		},
+		&config.directory_url,
+		None,
+		http_client(config)?,
+	)
+	.await?;
+
+	write_private(
+		&account_path,
+		serde_json::to_string(&credentials)?.as_bytes(),
+	)?;
+	info!("Created ACME account at {}", config.directory_url);
+
+	Ok(account)
+}
+
+fn http_client(config: &AcmeConfig) -> Result<Box<dyn HttpClient>> {
+	let builder = hyper_rustls::HttpsConnectorBuilder::new();
+
+	let builder = match &config.root_cert {
+		None => builder.with_native_roots(),
+		Some(path) => {
+			let mut roots = rustls::RootCertStore::empty();
+			for cert in tls::load_certs(path)? {
+				roots
+					.add(&cert)
+					.with_context(|| format!("invalid root certificate in {}", path))?;
+			}
+			builder.with_tls_config(
+				rustls::ClientConfig::builder()
+					.with_safe_defaults()
+					.with_root_certificates(roots)
+					.with_no_client_auth(),
+			)
+		}
+	};
+
+	let connector = builder.https_only().enable_http1().build();
+	Ok(Box::new(hyper::Client::builder().build(connector)))
+}
+
+/// Creates the self-signed certificate for a TLS-ALPN-01 challenge (RFC 8737).
+fn alpn_challenge_cert(domain: &str, digest: &[u8]) -> Result<CertifiedKey> {
+	let mut params = CertificateParams::new(vec![domain.to_string()]);
+	params.distinguished_name = DistinguishedName::new();
+	params.custom_extensions = vec![CustomExtension::new_acme_identifier(digest)];
+	let cert = rcgen::Certificate::from_params(params)?;
+
+	let key = rustls::PrivateKey(cert.serialize_private_key_der());
+	let signing_key = rustls::sign::any_supported_type(&key)?;
+
+	Ok(CertifiedKey::new(
+		vec![rustls::Certificate(cert.serialize_der()?)],
+		signing_key,
+	))
+}
+
+/// Atomically replaces a file with one that is only readable by the owner.
+fn write_private(path: &str, contents: &[u8]) -> io::Result<()> {
+	let tmp_path = write_private_tmp(path, contents)?;
+	fs::rename(&tmp_path, path)
+}
+
+/// Replaces a certificate and its key. Both are written out in full before either is
+/// renamed into place, so that a failed write leaves the previous pair untouched.
+fn write_pair(pair: &CertPair, cert_pem: &[u8], key_pem: &[u8]) -> io::Result<()> {
+	let key_tmp_path = write_private_tmp(&pair.key_path, key_pem)?;
+	let cert_tmp_path = match write_private_tmp(&pair.cert_path, cert_pem) {
+		Ok(path) => path,
+		Err(e) => {
+			let _ = fs::remove_file(&key_tmp_path);
+			return Err(e);
+		}
+	};
+
+	fs::rename(&key_tmp_path, &pair.key_path)?;
+	fs::rename(&cert_tmp_path, &pair.cert_path)?;
+
+	// Makes the renames durable together
+	if let Some(dir) = Path::new(&pair.cert_path)
+		.parent()
+		.filter(|dir| !dir.as_os_str().is_empty())
+	{
+		fs::File::open(dir)?.sync_all()?;
+	}
+	Ok(())
+}
+
+/// Writes the contents for `path` to a temporary file next to it, only readable by the
+/// owner, and returns the path of the temporary file.
+fn write_private_tmp(path: &str, contents: &[u8]) -> io::Result<String> {
+	use std::io::Write;
+	use std::os::unix::fs::OpenOptionsExt;
+
+	let tmp_path = format!("{}.tmp", path);
+
+	let res = fs::OpenOptions::new()
+		.write(true)
+		.create(true)
+		.truncate(true)
+		.mode(0o600)
+		.open(&tmp_path)
+		.and_then(|mut file| {
+			file.write_all(contents)?;
+			file.sync_all()
+		});
+
+	match res {
+		Ok(()) => Ok(tmp_path),
+		Err(e) => {
+			let _ = fs::remove_file(&tmp_path);
+			Err(error(format!("failed to write {}: {}", tmp_path, e)))
+		}
+	}
+}
+
+pub fn is_http01_request(req: &Request<Body>) -> bool {
+	req.uri().path().starts_with(HTTP01_PATH_PREFIX)
+}
+
+/// Answers an HTTP-01 challenge request with its key authorization, or 404 for unknown tokens.
+pub fn http01_response(tokens: &Http01Tokens, req: &Request<Body>) -> Response<Body> {
+	let key_authorization = req
+		.uri()
+		.path()
+		.strip_prefix(HTTP01_PATH_PREFIX)
+		.and_then(|token| tokens.read().unwrap().get(token).cloned());
+
+	match key_authorization {
+		Some(key_authorization) => Response::new(Body::from(key_authorization)),
+		None => {
+			let mut response = Response::new(Body::empty());
+			*response.status_mut() = StatusCode::NOT_FOUND;
+			response
+		}
+	}
+}
+
+/// Extracts the notAfter time from a DER encoded X.509 certificate.
+fn cert_not_after(der: &[u8]) -> Option<SystemTime> {
+	// Certificate ::= SEQUENCE { tbsCertificate, ... }
+	let (_, certificate, _) = der_read(der)?;
+	let (_, mut tbs, _) = der_read(certificate)?;
+
+	// Skip the optional [0] version, serialNumber, signature and issuer
+	let (tag, _, rest) = der_read(tbs)?;
+	if tag == 0xa0 {
+		tbs = rest;
+	}
+	for _ in 0..3 {
+		tbs = der_read(tbs)?.2;
+	}
+
+	// Validity ::= SEQUENCE { notBefore Time, notAfter Time }
+	let (_, validity, _) = der_read(tbs)?;
+	let (_, _, validity) = der_read(validity)?;
+	let (tag, not_after, _) = der_read(validity)?;
+
+	let time = std::str::from_utf8(not_after).ok()?;
+	let time = match tag {
+		// UTCTime, YYMMDDHHMMSSZ
+		0x17 => {
+			let year: i64 = time.get(0..2)?.parse().ok()?;
+			let century = if year >= 50 { "19" } else { "20" };
+			format!("{}{}", century, time)
+		}
+		// GeneralizedTime, YYYYMMDDHHMMSSZ
+		0x18 => time.to_string(),
+		_ => return None,
+	};
+
+	let field = |range: std::ops::Range<usize>| -> Option<i64> { time.get(range)?.parse().ok() };
+	let days = date::days_from_civil(field(0..4)?, field(4..6)?, field(6..8)?);
+	let secs = days * 86400 + field(8..10)? * 3600 + field(10..12)? * 60 + field(12..14)?;
+
+	Some(UNIX_EPOCH + Duration::from_secs(u64::try_from(secs).ok()?))
+}
+
+/// Reads a DER element and returns its tag, contents and the remaining input.
+fn der_read(input: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+	let tag = *input.first()?;
+	let first_len = *input.get(1)? as usize;
+
+	let (len, header_len) = if first_len < 0x80 {
+		(first_len, 2)
+	} else {
+		let num_bytes = first_len & 0x7f;
+		if num_bytes == 0 || num_bytes > 4 {
+			return None;
+		}
+		let len = input
+			.get(2..2 + num_bytes)?
+			.iter()
+			.fold(0usize, |len, b| (len << 8) | *b as usize);
+		(len, 2 + num_bytes)
+	};
+
+	let contents = input.get(header_len..header_len + len)?;
+	Some((tag, contents, &input[header_len + len..]))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use rcgen::date_time_ymd;
+
+	fn cert_der(not_after: Option<(i32, u8, u8)>, names: Vec<String>) -> Vec<u8> {
+		let mut params = CertificateParams::new(names);
+		if let Some((year, month, day)) = not_after {
+			params.not_after = date_time_ymd(year, month, day);
+		}
+		rcgen::Certificate::from_params(params)
+			.unwrap()
+			.serialize_der()
+			.unwrap()
+	}
+
+	fn time(year: i64, month: i64, day: i64) -> SystemTime {
+		UNIX_EPOCH + Duration::from_secs(date::days_from_civil(year, month, day) as u64 * 86400)
+	}
+
+	#[test]
+	fn not_after() {
+		// UTCTime, with the century taken from the year
+		let der = cert_der(Some((2030, 6, 15)), vec!["example.com".into()]);
+		assert_eq!(cert_not_after(&der), Some(time(2030, 6, 15)));
+		let der = cert_der(Some((1999, 12, 31)), vec!["example.com".into()]);
+		assert_eq!(cert_not_after(&der), Some(time(1999, 12, 31)));
+
+		// GeneralizedTime from 2050 on
+		let der = cert_der(Some((2050, 1, 1)), vec!["example.com".into()]);
+		assert_eq!(cert_not_after(&der), Some(time(2050, 1, 1)));
+		let der = cert_der(None, vec!["example.com".into()]);
+		assert_eq!(cert_not_after(&der), Some(time(4096, 1, 1)));
+	}
+
+	#[test]
+	fn not_after_long_form_lengths() {
+		// Enough names that the certificate and its fields need lengths of two bytes
+		let names = (0..100)
+			.map(|i| format!("host-{}.example.com", i))
+			.collect();
+		let der = cert_der(Some((2030, 6, 15)), names);
+		assert!(der.len() > 0xff);
+		assert_eq!(der[1], 0x82);
+		assert_eq!(cert_not_after(&der), Some(time(2030, 6, 15)));
+	}
+
+	#[test]
+	fn not_after_truncated() {
+		let der = cert_der(Some((2030, 6, 15)), vec!["example.com".into()]);
+		for len in 0..der.len() {
+			assert_eq!(cert_not_after(&der[..len]), None, "{}", len);
+		}
+		assert_eq!(cert_not_after(b"not a certificate"), None);
+	}
+
+	#[test]
+	fn der_lengths() {
+		assert_eq!(
+			der_read(&[0x04, 2, 1, 2, 3]),
+			Some((0x04, &[1, 2][..], &[3][..]))
+		);
+		assert_eq!(
+			der_read(&[0x04, 0x81, 1, 7]),
+			Some((0x04, &[7][..], &[][..]))
+		);
+
+		let mut long = vec![0x04, 0x82, 0x01, 0x00];
+		long.extend_from_slice(&[0xaa; 0x100]);
+		let (_, contents, rest) = der_read(&long).unwrap();
+		assert_eq!((contents.len(), rest.len()), (0x100, 0));
+
+		// Indefinite and overlong lengths
+		assert_eq!(der_read(&[0x30, 0x80, 0, 0]), None);
+		assert_eq!(der_read(&[0x30, 0x85, 0, 0, 0, 0, 1, 0]), None);
+		assert_eq!(der_read(&[0x30, 0x84, 0xff, 0xff, 0xff, 0xff]), None);
+
+		// Cut off in the header or the contents
+		assert_eq!(der_read(&[]), None);
+		assert_eq!(der_read(&[0x30]), None);
+		assert_eq!(der_read(&[0x30, 0x82, 0x01]), None);
+		assert_eq!(der_read(&[0x04, 3, 1, 2]), None);
+	}
+
+	#[test]
+	fn write_pair_replaces_both() {
+		let dir = std::env::temp_dir().join(format!("wut-acme-{}", std::process::id()));
+		fs::create_dir_all(&dir).unwrap();
+		let pair = CertPair {
+			cert_path: dir.join("fullchain.pem").to_string_lossy().into_owned(),
+			key_path: dir.join("privkey.pem").to_string_lossy().into_owned(),
+		};
+
+		write_pair(&pair, b"cert 1", b"key 1").unwrap();
+		write_pair(&pair, b"cert 2", b"key 2").unwrap();
+		assert_eq!(fs::read(&pair.cert_path).unwrap(), b"cert 2");
+		assert_eq!(fs::read(&pair.key_path).unwrap(), b"key 2");
+
+		// A certificate that cannot be written leaves the previous pair in place
+		fs::create_dir(format!("{}.tmp", pair.cert_path)).unwrap();
+		assert!(write_pair(&pair, b"cert 3", b"key 3").is_err());
+		assert_eq!(fs::read(&pair.cert_path).unwrap(), b"cert 2");
+		assert_eq!(fs::read(&pair.key_path).unwrap(), b"key 2");
+		assert!(!Path::new(&format!("{}.tmp", pair.key_path)).exists());
+
+		fs::remove_dir_all(&dir).unwrap();
+	}
+}
@@ -0,0 +1,56 @@
+//! The admin listener, which is kept apart from the echo listeners so that
+//! monitoring does not show up as client traffic.
+
+use crate::listener::ServerState;
+use crate::metrics::{OPENMETRICS_TYPE, PROMETHEUS_TYPE};
+use crate::router::Router;
+use crate::service::{json_response, status_response, text_response};
+use hyper::header::{HeaderValue, ACCEPT, ALLOW, CONTENT_TYPE};
+use hyper::{Body, Request, Response, StatusCode};
+use std::sync::atomic::Ordering;
+
+pub fn handle(state: &ServerState, req: &Request<Body>) -> Response<Body> {
+	if !Router::allows(req.method()) {
+		let mut response = status_response(StatusCode::METHOD_NOT_ALLOWED);
+		response
+			.headers_mut()
+			.insert(ALLOW, HeaderValue::from_static("GET, HEAD"));
+		return response;
+	}
+
+	match req.uri().path() {
+		"/metrics" => metrics_response(state, req),
+		"/healthz" => text_response("ok"),
+		"/readyz" => match state.ready.load(Ordering::Relaxed) {
+			true => text_response("ready"),
+			// Load balancers stop sending new clients while the open connections finish
+			false => {
+				let mut response = text_response("not ready");
+				*response.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+				response
+			}
+		},
+		"/stats" => json_response(&state.metrics.stats()),
+		"/config" => json_response(&state.settings().effective_config),
+		_ => status_response(StatusCode::NOT_FOUND),
+	}
+}
+
+fn metrics_response(state: &ServerState, req: &Request<Body>) -> Response<Body> {
+	// Prometheus asks for OpenMetrics if it is configured to prefer it
+	let openmetrics = req
+		.headers()
+		.get(ACCEPT)
+		.and_then(|accept| accept.to_str().ok())
+		.is_some_and(|accept| accept.contains("application/openmetrics-text"));
+
+	let content_type = match openmetrics {
+		true => OPENMETRICS_TYPE,
+		false => PROMETHEUS_TYPE,
+	};
+
+	Response::builder()
+		.header(CONTENT_TYPE, content_type)
+		.body(Body::from(state.metrics.encode(openmetrics)))
+		.unwrap()
+}
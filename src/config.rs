@@ -0,0 +1,246 @@
+//! TOML configuration file. The keys are the long names of the command line
+//! options, which take precedence over the file.
+
+use crate::access_log::AccessLogFormat;
+use crate::acme::AcmeChallenge;
+use crate::logging::LogFormat;
+use crate::service::ResponseFormat;
+use crate::Args;
+use anyhow::Result;
+use clap::parser::ValueSource;
+use clap::ArgMatches;
+use serde::{Deserialize, Serialize};
+use std::fs;
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct FileConfig {
+	bind: Option<Vec<String>>,
+	bind_http: Option<Vec<String>>,
+	admin_bind: Option<Vec<String>>,
+	bind_stun: Option<Vec<String>>,
+	bind_dns: Option<Vec<String>>,
+	dns_zone: Option<String>,
+	bind_raw_tcp: Option<Vec<String>>,
+	bind_raw_udp: Option<Vec<String>>,
+	http_redirect: Option<bool>,
+	proxy_protocol_trusted: Option<Vec<String>>,
+	trusted_proxy: Option<Vec<String>>,
+	cert_path: Option<Vec<String>>,
+	key_path: Option<Vec<String>>,
+	cert_dir: Option<Vec<String>>,
+	default_cert: Option<String>,
+	acme_domain: Option<Vec<String>>,
+	acme_email: Option<Vec<String>>,
+	acme_directory: Option<String>,
+	acme_root_cert: Option<String>,
+	acme_state_dir: Option<String>,
+	acme_challenge: Option<AcmeChallenge>,
+	acme_http_bind: Option<Vec<String>>,
+	access_log: Option<String>,
+	access_log_format: Option<AccessLogFormat>,
+	access_log_fingerprints: Option<bool>,
+	log_format: Option<LogFormat>,
+	log_interval: Option<u64>,
+	reload_interval: Option<u64>,
+	http2_only: Option<bool>,
+	h2_fingerprint: Option<bool>,
+	http3: Option<bool>,
+	rate_limit: Option<f64>,
+	rate_limit_burst: Option<u32>,
+	#[serde(default)]
+	listener: Vec<ListenerConfig>,
+}
+
+/// A `[[listener]]` table, for settings that differ between listeners.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ListenerConfig {
+	/// Address with optional port, or a `unix:` or `systemd:` address
+	pub bind: String,
+	/// Serve HTTPS, or plain HTTP if disabled
+	#[serde(default = "default_tls")]
+	pub tls: bool,
+	#[serde(default)]
+	pub http2_only: bool,
+	#[serde(default)]
+	pub proxy_protocol: bool,
+	/// Format of responses to requests that do not ask for one
+	pub format: Option<ResponseFormat>,
+	/// Requests per second of each client, instead of the global limit
+	pub rate_limit: Option<f64>,
+	pub rate_limit_burst: Option<u32>,
+	/// Octal permissions of a Unix socket
+	pub mode: Option<String>,
+	pub owner: Option<String>,
+	pub group: Option<String>,
+}
+
+fn default_tls() -> bool {
+	true
+}
+
+impl FileConfig {
+	pub fn load(path: &str) -> Result<Self> {
+		let text = fs::read_to_string(path).map_err(|e| {
+			anyhow::Error::msg(format!("failed to read config file {}: {}", path, e))
+		})?;
+
+		// The error quotes the offending line and names the key
+		toml::from_str(&text)
+			.map_err(|e| anyhow::Error::msg(format!("invalid config file {}: {}", path, e)))
+	}
+
+	/// Fills in the options that were not given on the command line.
+	pub fn apply(self, args: &mut Args, matches: &ArgMatches) {
+		fn set<T>(matches: &ArgMatches, id: &str, arg: &mut T, value: Option<T>) {
+			if matches.value_source(id) != Some(ValueSource::CommandLine) {
+				if let Some(value) = value {
+					*arg = value;
+				}
+			}
+		}
+
+		// The default listeners are only used if there are no listener tables either
+		if matches.value_source("bind") != Some(ValueSource::CommandLine)
+			&& !self.listener.is_empty()
+		{
+			args.bind.clear();
+		}
+		args.listener = self.listener;
+
+		set(matches, "bind", &mut args.bind, self.bind);
+		set(matches, "bind_http", &mut args.bind_http, self.bind_http);
+		set(matches, "admin_bind", &mut args.admin_bind, self.admin_bind);
+		set(matches, "bind_stun", &mut args.bind_stun, self.bind_stun);
+		set(matches, "bind_dns", &mut args.bind_dns, self.bind_dns);
+		set(
+			matches,
+			"dns_zone",
+			&mut args.dns_zone,
+			self.dns_zone.map(Some),
+		);
+		set(
+			matches,
+			"bind_raw_tcp",
+			&mut args.bind_raw_tcp,
+			self.bind_raw_tcp,
+		);
+		set(
+			matches,
+			"bind_raw_udp",
+			&mut args.bind_raw_udp,
+			self.bind_raw_udp,
+		);
+		set(
+			matches,
+			"http_redirect",
+			&mut args.http_redirect,
+			self.http_redirect,
+		);
+		set(
+			matches,
+			"proxy_protocol_trusted",
+			&mut args.proxy_protocol_trusted,
+			self.proxy_protocol_trusted,
+		);
+		set(
+			matches,
+			"trusted_proxy",
+			&mut args.trusted_proxy,
+			self.trusted_proxy,
+		);
+		set(matches, "cert_path", &mut args.cert_path, self.cert_path);
+		set(matches, "key_path", &mut args.key_path, self.key_path);
+		set(matches, "cert_dir", &mut args.cert_dir, self.cert_dir);
+		set(
+			matches,
+			"default_cert",
+			&mut args.default_cert,
+			self.default_cert.map(Some),
+		);
+		set(
+			matches,
+			"acme_domain",
+			&mut args.acme_domain,
+			self.acme_domain,
+		);
+		set(matches, "acme_email", &mut args.acme_email, self.acme_email);
+		set(
+			matches,
+			"acme_directory",
+			&mut args.acme_directory,
+			self.acme_directory,
+		);
+		set(
+			matches,
+			"acme_root_cert",
+			&mut args.acme_root_cert,
+			self.acme_root_cert.map(Some),
+		);
+		set(
+			matches,
+			"acme_state_dir",
+			&mut args.acme_state_dir,
+			self.acme_state_dir,
+		);
+		set(
+			matches,
+			"acme_challenge",
+			&mut args.acme_challenge,
+			self.acme_challenge,
+		);
+		set(
+			matches,
+			"acme_http_bind",
+			&mut args.acme_http_bind,
+			self.acme_http_bind,
+		);
+		set(
+			matches,
+			"access_log",
+			&mut args.access_log,
+			self.access_log.map(Some),
+		);
+		set(
+			matches,
+			"access_log_format",
+			&mut args.access_log_format,
+			self.access_log_format,
+		);
+		set(
+			matches,
+			"access_log_fingerprints",
+			&mut args.access_log_fingerprints,
+			self.access_log_fingerprints,
+		);
+		set(matches, "log_format", &mut args.log_format, self.log_format);
+		set(
+			matches,
+			"log_interval",
+			&mut args.log_interval,
+			self.log_interval,
+		);
+		set(
+			matches,
+			"reload_interval",
+			&mut args.reload_interval,
+			self.reload_interval,
+		);
+		set(matches, "http2_only", &mut args.http2_only, self.http2_only);
+		set(
+			matches,
+			"h2_fingerprint",
+			&mut args.h2_fingerprint,
+			self.h2_fingerprint,
+		);
+		set(matches, "http3", &mut args.http3, self.http3);
+		set(matches, "rate_limit", &mut args.rate_limit, self.rate_limit);
+		set(
+			matches,
+			"rate_limit_burst",
+			&mut args.rate_limit_burst,
+			self.rate_limit_burst.map(Some),
+		);
+	}
+}
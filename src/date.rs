@@ -0,0 +1,117 @@
+//! Conversions between days since the Unix epoch and dates in the proleptic Gregorian
+//! calendar, with the algorithms by Howard Hinnant.
+
+/// Days since 1970-01-01 for a date.
+pub fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+	// Counted from 0000-03-01, so that the leap day is the last day of the year
+	let year = if month <= 2 { year - 1 } else { year };
+	let era = year.div_euclid(400);
+	let year_of_era = year - era * 400;
+	let month_index = (month + 9) % 12;
+	let day_of_year = (153 * month_index + 2) / 5 + day - 1;
+	let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+	era * 146097 + day_of_era - 719468
+}
+
+/// The year, month and day of a day counted from 1970-01-01.
+pub fn civil_from_days(days: i64) -> (i64, i64, i64) {
+	let days = days + 719468;
+	let era = days.div_euclid(146097);
+	let day_of_era = days - era * 146097;
+	let year_of_era =
+		(day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+	let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+	let month_index = (5 * day_of_year + 2) / 153;
+	let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+	let month = if month_index < 10 {
+		month_index + 3
+	} else {
+		month_index - 9
+	};
+
+	(era * 400 + year_of_era + i64::from(month <= 2), month, day)
+}
+
+/// Splits seconds since the Unix epoch into the date and time of day in UTC, as the
+/// year, month, day, hour, minute and second.
+pub fn utc(secs: u64) -> (i64, i64, i64, u64, u64, u64) {
+	let (year, month, day) = civil_from_days((secs / 86400) as i64);
+	let time_of_day = secs % 86400;
+
+	(
+		year,
+		month,
+		day,
+		time_of_day / 3600,
+		time_of_day % 3600 / 60,
+		time_of_day % 60,
+	)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn epoch() {
+		assert_eq!(days_from_civil(1970, 1, 1), 0);
+		assert_eq!(civil_from_days(0), (1970, 1, 1));
+		assert_eq!(utc(0), (1970, 1, 1, 0, 0, 0));
+		assert_eq!(civil_from_days(-1), (1969, 12, 31));
+	}
+
+	#[test]
+	fn leap_years() {
+		// Divisible by 400
+		assert_eq!(days_from_civil(2000, 2, 29), 11016);
+		assert_eq!(civil_from_days(11016), (2000, 2, 29));
+		assert_eq!(civil_from_days(11017), (2000, 3, 1));
+
+		// Divisible by 4
+		let leap_day = days_from_civil(2024, 2, 29);
+		assert_eq!(civil_from_days(leap_day), (2024, 2, 29));
+		assert_eq!(
+			days_from_civil(2024, 3, 1) - days_from_civil(2024, 2, 28),
+			2
+		);
+
+		// Divisible by 100 but not 400
+		assert_eq!(
+			days_from_civil(1900, 3, 1) - days_from_civil(1900, 2, 28),
+			1
+		);
+		assert_eq!(
+			days_from_civil(2100, 3, 1) - days_from_civil(2100, 2, 28),
+			1
+		);
+		assert_eq!(
+			civil_from_days(days_from_civil(2100, 3, 1) - 1),
+			(2100, 2, 28)
+		);
+
+		assert_eq!(
+			days_from_civil(2025, 1, 1) - days_from_civil(2024, 1, 1),
+			366
+		);
+		assert_eq!(
+			days_from_civil(2026, 1, 1) - days_from_civil(2025, 1, 1),
+			365
+		);
+	}
+
+	#[test]
+	fn time_of_day() {
+		// 2000-02-29T23:59:59Z
+		assert_eq!(utc(951868799), (2000, 2, 29, 23, 59, 59));
+		assert_eq!(utc(951868800), (2000, 3, 1, 0, 0, 0));
+	}
+
+	#[test]
+	fn round_trip() {
+		for days in (-800_000..800_000).step_by(7) {
+			let (year, month, day) = civil_from_days(days);
+			assert!((1..=12).contains(&month) && (1..=31).contains(&day));
+			assert_eq!(days_from_civil(year, month, day), days);
+		}
+	}
+}
@@ -0,0 +1,827 @@
+//! An authoritative DNS responder for a single name that answers with the
+//! address the query came from, like `o-o.myaddr.l.google.com`.
+
+use crate::listener::ServerState;
+use crate::metrics::{self, Labels};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::watch;
+use tokio::{select, time};
+
+const HEADER_LEN: usize = 12;
+/// Time a TCP client gets to send its next query
+const TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+/// UDP payload size advertised in responses to EDNS queries
+const EDNS_UDP_SIZE: u16 = 1232;
+
+const FLAG_QR: u16 = 0x8000;
+const FLAG_AA: u16 = 0x0400;
+const FLAG_RD: u16 = 0x0100;
+
+const RCODE_FORMERR: u16 = 1;
+const RCODE_NXDOMAIN: u16 = 3;
+const RCODE_NOTIMP: u16 = 4;
+const RCODE_REFUSED: u16 = 5;
+
+const TYPE_A: u16 = 1;
+const TYPE_TXT: u16 = 16;
+const TYPE_AAAA: u16 = 28;
+const TYPE_OPT: u16 = 41;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+
+const OPTION_CLIENT_SUBNET: u16 = 8;
+
+/// The name that is answered, lowercase and without the trailing dot.
+pub type Zone = Arc<str>;
+
+/// Answers queries over UDP for the zone in `zone_rx`, until its sender is dropped.
+pub async fn serve_udp(
+	socket: UdpSocket,
+	mut zone_rx: watch::Receiver<Zone>,
+	state: Arc<ServerState>,
+) {
+	let mut buf = [0; 4096];
+	let listener = metrics::socket_name(socket.local_addr());
+
+	loop {
+		let (len, peer_addr) = select! {
+			res = socket.recv_from(&mut buf) => match res {
+				Ok(res) => res,
+				Err(e) => {
+					debug!("Failed to receive DNS query: {}", e);
+					time::sleep(Duration::from_millis(10)).await;
+					continue;
+				}
+			},
+			res = zone_rx.changed() => match res {
+				Ok(()) => continue,
+				Err(_) => break,
+			},
+		};
+
+		let zone = zone_rx.borrow().clone();
+		let response = match respond(&buf[..len], peer_addr.ip(), &zone) {
+			Some(response) => response,
+			None => continue,
+		};
+		let labels = Labels {
+			version: "dns-udp",
+			..Labels::new(&listener, peer_addr)
+		};
+		state.metrics.request(&labels, response.len() as u64);
+
+		if let Err(e) = socket.send_to(&response, peer_addr).await {
+			debug!("Failed to send DNS response to {}: {}", peer_addr, e);
+		}
+	}
+}
+
+/// Accepts TCP connections for the zone in `zone_rx`, until its sender is dropped.
+pub async fn serve_tcp(
+	listener: TcpListener,
+	mut zone_rx: watch::Receiver<Zone>,
+	state: Arc<ServerState>,
+) {
+	let name = metrics::socket_name(listener.local_addr());
+
+	loop {
+		let (stream, peer_addr) = select! {
+			res = listener.accept() => match res {
+				Ok(conn) => conn,
+				Err(e) => {
+					debug!("Failed to accept DNS connection: {}", e);
+					time::sleep(Duration::from_millis(10)).await;
+					continue;
+				}
+			},
+			res = zone_rx.changed() => match res {
+				Ok(()) => continue,
+				Err(_) => break,
+			},
+		};
+
+		let labels = Labels {
+			version: "dns-tcp",
+			..Labels::new(&name, peer_addr)
+		};
+		tokio::spawn(handle_tcp(
+			stream,
+			peer_addr,
+			labels,
+			zone_rx.borrow().clone(),
+			state.clone(),
+		));
+	}
+}
+
+/// Answers length-prefixed queries until the client closes the connection or goes idle.
+async fn handle_tcp(
+	mut stream: TcpStream,
+	peer_addr: SocketAddr,
+	labels: Labels,
+	zone: Zone,
+	state: Arc<ServerState>,
+) {
+	loop {
+		let read = async {
+			let len = stream.read_u16().await? as usize;
+			let mut query = vec![0; len];
+			stream.read_exact(&mut query).await?;
+			Ok::<_, std::io::Error>(query)
+		};
+
+		let query = match time::timeout(TCP_IDLE_TIMEOUT, read).await {
+			Ok(Ok(query)) => query,
+			_ => return,
+		};
+
+		let response = match respond(&query, peer_addr.ip(), &zone) {
+			Some(response) => response,
+			None => return,
+		};
+		state.metrics.request(&labels, response.len() as u64);
+
+		let mut framed = Vec::with_capacity(2 + response.len());
+		framed.extend_from_slice(&(response.len() as u16).to_be_bytes());
+		framed.extend_from_slice(&response);
+		if stream.write_all(&framed).await.is_err() {
+			return;
+		}
+	}
+}
+
+/// A parsed query with the raw question section, which is copied into the response.
+struct Query<'a> {
+	id: u16,
+	flags: u16,
+	question: &'a [u8],
+	name: String,
+	qtype: u16,
+	qclass: u16,
+	/// Whether the query had an OPT record
+	edns: bool,
+	client_subnet: Option<ClientSubnet>,
+}
+
+/// An EDNS Client Subnet option (RFC 7871).
+struct ClientSubnet {
+	family: u16,
+	source_prefix: u8,
+	address: Vec<u8>,
+}
+
+impl ClientSubnet {
+	fn addr(&self) -> Option<IpAddr> {
+		match self.family {
+			1 => {
+				let mut octets = [0; 4];
+				octets
+					.get_mut(..self.address.len())?
+					.copy_from_slice(&self.address);
+				Some(octets.into())
+			}
+			2 => {
+				let mut octets = [0; 16];
+				octets
+					.get_mut(..self.address.len())?
+					.copy_from_slice(&self.address);
+				Some(octets.into())
+			}
+			_ => None,
+		}
+	}
+}
+
+/// Builds the response to a query, or returns `None` if it should be dropped.
+///
+/// Responses always fit in the 512 bytes allowed for UDP without EDNS, since
+/// there is at most one short TXT string per answer unless EDNS is in use.
+fn respond(packet: &[u8], source: IpAddr, zone: &str) -> Option<Vec<u8>> {
+	let header = packet.get(..HEADER_LEN)?;
+	let id = u16::from_be_bytes([header[0], header[1]]);
+	let flags = u16::from_be_bytes([header[2], header[3]]);
+
+	// Never answer responses, which could cause loops
+	if flags & FLAG_QR != 0 {
+		return None;
+	}
+
+	let query = match parse_query(packet) {
+		Some(query) => query,
+		None => return Some(error_response(id, flags, RCODE_FORMERR)),
+	};
+
+	// Only standard queries
+	if (query.flags >> 11) & 0xf != 0 {
+		return Some(error_response(id, flags, RCODE_NOTIMP));
+	}
+
+	let name = query.name.to_ascii_lowercase();
+	let rcode = if name == zone {
+		0
+	} else if name.ends_with(&format!(".{}", zone)) {
+		RCODE_NXDOMAIN
+	} else {
+		return Some(error_response(id, flags, RCODE_REFUSED));
+	};
+
+	let source = source.to_canonical();
+	let mut answers = Vec::new();
+
+	if rcode == 0 && query.qclass == CLASS_IN {
+		match (query.qtype, source) {
+			(TYPE_A, IpAddr::V4(ip)) => answers.push((TYPE_A, ip.octets().to_vec())),
+			(TYPE_AAAA, IpAddr::V6(ip)) => answers.push((TYPE_AAAA, ip.octets().to_vec())),
+			(TYPE_TXT | TYPE_ANY, _) => {
+				answers.push((TYPE_TXT, txt(&source.to_string())));
+				if let Some(subnet) = &query.client_subnet {
+					if let Some(addr) = subnet.addr() {
+						let value =
+							format!("edns0-client-subnet {}/{}", addr, subnet.source_prefix);
+						answers.push((TYPE_TXT, txt(&value)));
+					}
+				}
+			}
+			_ => {}
+		}
+	}
+
+	let mut response = Vec::with_capacity(512);
+	response.extend_from_slice(&query.id.to_be_bytes());
+	let flags = FLAG_QR | FLAG_AA | (query.flags & FLAG_RD) | rcode;
+	response.extend_from_slice(&flags.to_be_bytes());
+	response.extend_from_slice(&1u16.to_be_bytes());
+	response.extend_from_slice(&(answers.len() as u16).to_be_bytes());
+	response.extend_from_slice(&0u16.to_be_bytes());
+	response.extend_from_slice(&(query.edns as u16).to_be_bytes());
+	response.extend_from_slice(query.question);
+
+	for (rtype, rdata) in &answers {
+		// Pointer to the name in the question
+		response.extend_from_slice(&0xc00cu16.to_be_bytes());
+		response.extend_from_slice(&rtype.to_be_bytes());
+		response.extend_from_slice(&CLASS_IN.to_be_bytes());
+		// The answer depends on who is asking, so it must not be cached
+		response.extend_from_slice(&0u32.to_be_bytes());
+		response.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+		response.extend_from_slice(rdata);
+	}
+
+	if query.edns {
+		let mut options = Vec::new();
+		if let Some(subnet) = &query.client_subnet {
+			// The scope is the whole source prefix, since the answer depends on it
+			options.extend_from_slice(&OPTION_CLIENT_SUBNET.to_be_bytes());
+			options.extend_from_slice(&(4 + subnet.address.len() as u16).to_be_bytes());
+			options.extend_from_slice(&subnet.family.to_be_bytes());
+			options.push(subnet.source_prefix);
+			options.push(subnet.source_prefix);
+			options.extend_from_slice(&subnet.address);
+		}
+
+		response.push(0);
+		response.extend_from_slice(&TYPE_OPT.to_be_bytes());
+		response.extend_from_slice(&EDNS_UDP_SIZE.to_be_bytes());
+		response.extend_from_slice(&0u32.to_be_bytes());
+		response.extend_from_slice(&(options.len() as u16).to_be_bytes());
+		response.extend_from_slice(&options);
+	}
+
+	Some(response)
+}
+
+/// A response with just a header, for queries that cannot be answered.
+fn error_response(id: u16, query_flags: u16, rcode: u16) -> Vec<u8> {
+	let mut response = Vec::with_capacity(HEADER_LEN);
+	response.extend_from_slice(&id.to_be_bytes());
+	let flags = FLAG_QR | (query_flags & (0x7800 | FLAG_RD)) | rcode;
+	response.extend_from_slice(&flags.to_be_bytes());
+	response.extend_from_slice(&[0; 8]);
+	response
+}
+
+/// TXT record data with a single character string.
+fn txt(value: &str) -> Vec<u8> {
+	let mut rdata = Vec::with_capacity(1 + value.len());
+	rdata.push(value.len() as u8);
+	rdata.extend_from_slice(value.as_bytes());
+	rdata
+}
+
+fn parse_query(packet: &[u8]) -> Option<Query<'_>> {
+	let mut r = Reader { packet, pos: 0 };
+
+	let id = r.u16()?;
+	let flags = r.u16()?;
+	let qdcount = r.u16()?;
+	let ancount = r.u16()?;
+	let nscount = r.u16()?;
+	let arcount = r.u16()?;
+
+	if qdcount != 1 {
+		return None;
+	}
+
+	let question_start = r.pos;
+	let name = r.name()?;
+	let qtype = r.u16()?;
+	let qclass = r.u16()?;
+	let question = &packet[question_start..r.pos];
+
+	let mut query = Query {
+		id,
+		flags,
+		question,
+		name,
+		qtype,
+		qclass,
+		edns: false,
+		client_subnet: None,
+	};
+
+	for i in 0..ancount as usize + nscount as usize + arcount as usize {
+		r.name()?;
+		let rtype = r.u16()?;
+		r.u16()?;
+		r.u32()?;
+		let rdlen = r.u16()? as usize;
+		let rdata = r.bytes(rdlen)?;
+
+		let additional = i >= ancount as usize + nscount as usize;
+		if additional && rtype == TYPE_OPT {
+			query.edns = true;
+			query.client_subnet = parse_client_subnet(rdata);
+		}
+	}
+
+	Some(query)
+}
+
+fn parse_client_subnet(mut options: &[u8]) -> Option<ClientSubnet> {
+	while options.len() >= 4 {
+		let code = u16::from_be_bytes([options[0], options[1]]);
+		let len = u16::from_be_bytes([options[2], options[3]]) as usize;
+		let data = options.get(4..4 + len)?;
+		options = &options[4 + len..];
+
+		if code != OPTION_CLIENT_SUBNET || data.len() < 4 {
+			continue;
+		}
+
+		let source_prefix = data[2];
+		let address = &data[4..];
+		// The address must be truncated to the source prefix
+		if address.len() != (source_prefix as usize).div_ceil(8) {
+			return None;
+		}
+
+		return Some(ClientSubnet {
+			family: u16::from_be_bytes([data[0], data[1]]),
+			source_prefix,
+			address: address.to_vec(),
+		});
+	}
+
+	None
+}
+
+/// Reads big-endian values and names from a DNS message.
+struct Reader<'a> {
+	packet: &'a [u8],
+	pos: usize,
+}
+
+impl<'a> Reader<'a> {
+	fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+		let bytes = self.packet.get(self.pos..self.pos.checked_add(len)?)?;
+		self.pos += len;
+		Some(bytes)
+	}
+
+	fn u16(&mut self) -> Option<u16> {
+		self.bytes(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+	}
+
+	fn u32(&mut self) -> Option<u32> {
+		self.bytes(4)
+			.map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+	}
+
+	/// Reads a possibly compressed name as dotted text without the trailing dot.
+	fn name(&mut self) -> Option<String> {
+		let mut labels = Vec::new();
+		let mut pos = self.pos;
+		// Where reading continues after the name, set at the first pointer
+		let mut end = None;
+		let mut jumps = 0;
+
+		loop {
+			let len = *self.packet.get(pos)? as usize;
+			match len {
+				0 => {
+					pos += 1;
+					break;
+				}
+				len if len & 0xc0 == 0xc0 => {
+					let pointer =
+						u16::from_be_bytes([len as u8, *self.packet.get(pos + 1)?]) & 0x3fff;
+					end.get_or_insert(pos + 2);
+					jumps += 1;
+					if jumps > 16 {
+						return None;
+					}
+					pos = pointer as usize;
+				}
+				len if len & 0xc0 == 0 => {
+					let label = self.packet.get(pos + 1..pos + 1 + len)?;
+					labels.push(String::from_utf8_lossy(label).into_owned());
+					pos += 1 + len;
+				}
+				_ => return None,
+			}
+		}
+
+		self.pos = end.unwrap_or(pos);
+		Some(labels.join("."))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const ZONE: &str = "whoami.example.com";
+
+	fn name(name: &str) -> Vec<u8> {
+		let mut encoded = Vec::new();
+		for label in name.split('.').filter(|label| !label.is_empty()) {
+			encoded.push(label.len() as u8);
+			encoded.extend_from_slice(label.as_bytes());
+		}
+		encoded.push(0);
+		encoded
+	}
+
+	fn header(flags: u16, qdcount: u16, arcount: u16) -> Vec<u8> {
+		let mut header = vec![0x12, 0x34];
+		header.extend_from_slice(&flags.to_be_bytes());
+		header.extend_from_slice(&qdcount.to_be_bytes());
+		header.extend_from_slice(&[0, 0, 0, 0]);
+		header.extend_from_slice(&arcount.to_be_bytes());
+		header
+	}
+
+	fn query(qname: &str, qtype: u16) -> Vec<u8> {
+		let mut packet = header(FLAG_RD, 1, 0);
+		packet.extend_from_slice(&name(qname));
+		packet.extend_from_slice(&qtype.to_be_bytes());
+		packet.extend_from_slice(&CLASS_IN.to_be_bytes());
+		packet
+	}
+
+	/// A query with an OPT record carrying `options`.
+	fn edns_query(qname: &str, qtype: u16, options: &[u8]) -> Vec<u8> {
+		let mut packet = query(qname, qtype);
+		packet[11] = 1;
+		packet.push(0);
+		packet.extend_from_slice(&TYPE_OPT.to_be_bytes());
+		packet.extend_from_slice(&4096u16.to_be_bytes());
+		packet.extend_from_slice(&0u32.to_be_bytes());
+		packet.extend_from_slice(&(options.len() as u16).to_be_bytes());
+		packet.extend_from_slice(options);
+		packet
+	}
+
+	fn client_subnet(family: u16, source_prefix: u8, address: &[u8]) -> Vec<u8> {
+		let mut option = OPTION_CLIENT_SUBNET.to_be_bytes().to_vec();
+		option.extend_from_slice(&(4 + address.len() as u16).to_be_bytes());
+		option.extend_from_slice(&family.to_be_bytes());
+		option.push(source_prefix);
+		option.push(0);
+		option.extend_from_slice(address);
+		option
+	}
+
+	fn respond_from(packet: &[u8], source: &str) -> Option<Vec<u8>> {
+		respond(packet, source.parse().unwrap(), ZONE)
+	}
+
+	fn rcode(response: &[u8]) -> u16 {
+		u16::from_be_bytes([response[2], response[3]]) & 0xf
+	}
+
+	/// Type and data of resource records
+	type Records = Vec<(u16, Vec<u8>)>;
+
+	/// The answers, and the data of the OPT record if there is one.
+	fn answers(response: &[u8]) -> (Records, Option<Vec<u8>>) {
+		let mut r = Reader {
+			packet: response,
+			pos: 0,
+		};
+		r.bytes(4).unwrap();
+		assert_eq!(r.u16(), Some(1));
+		let ancount = r.u16().unwrap();
+		assert_eq!(r.u16(), Some(0));
+		let arcount = r.u16().unwrap();
+
+		r.name().unwrap();
+		r.bytes(4).unwrap();
+
+		let mut records = Vec::new();
+		for _ in 0..ancount + arcount {
+			r.name().unwrap();
+			let rtype = r.u16().unwrap();
+			assert_eq!(
+				r.u16(),
+				Some(if rtype == TYPE_OPT {
+					EDNS_UDP_SIZE
+				} else {
+					CLASS_IN
+				})
+			);
+			assert_eq!(r.u32(), Some(0));
+			let rdlen = r.u16().unwrap() as usize;
+			records.push((rtype, r.bytes(rdlen).unwrap().to_vec()));
+		}
+		assert_eq!(r.pos, response.len());
+
+		let opt = match arcount {
+			0 => None,
+			_ => records.pop().map(|(_, rdata)| rdata),
+		};
+		(records, opt)
+	}
+
+	#[test]
+	fn answers_with_source_address() {
+		let response = respond_from(&query(ZONE, TYPE_A), "192.0.2.1").unwrap();
+		assert_eq!(&response[..2], &[0x12, 0x34]);
+		let flags = u16::from_be_bytes([response[2], response[3]]);
+		assert_eq!(flags, FLAG_QR | FLAG_AA | FLAG_RD);
+		assert_eq!(
+			answers(&response),
+			(vec![(TYPE_A, vec![192, 0, 2, 1])], None)
+		);
+
+		let response = respond_from(&query(ZONE, TYPE_AAAA), "2001:db8::1").unwrap();
+		let ip: IpAddr = "2001:db8::1".parse().unwrap();
+		let IpAddr::V6(ip) = ip else { unreachable!() };
+		assert_eq!(
+			answers(&response).0,
+			vec![(TYPE_AAAA, ip.octets().to_vec())]
+		);
+
+		let response = respond_from(&query(ZONE, TYPE_TXT), "192.0.2.1").unwrap();
+		assert_eq!(answers(&response).0, vec![(TYPE_TXT, txt("192.0.2.1"))]);
+	}
+
+	#[test]
+	fn address_family_mismatch() {
+		// No data, but the name exists
+		let response = respond_from(&query(ZONE, TYPE_AAAA), "192.0.2.1").unwrap();
+		assert_eq!(rcode(&response), 0);
+		assert!(answers(&response).0.is_empty());
+
+		// IPv4-mapped sources of dual-stack sockets are IPv4 clients
+		let response = respond_from(&query(ZONE, TYPE_A), "::ffff:192.0.2.1").unwrap();
+		assert_eq!(answers(&response).0, vec![(TYPE_A, vec![192, 0, 2, 1])]);
+	}
+
+	#[test]
+	fn names_outside_the_zone() {
+		let response = respond_from(&query("WhoAmI.Example.COM", TYPE_A), "192.0.2.1").unwrap();
+		assert_eq!(rcode(&response), 0);
+
+		let response = respond_from(&query("sub.whoami.example.com", TYPE_A), "192.0.2.1").unwrap();
+		assert_eq!(rcode(&response), RCODE_NXDOMAIN);
+		assert!(answers(&response).0.is_empty());
+
+		for qname in [
+			"example.com",
+			"xwhoami.example.com",
+			"whoami.example.org",
+			"",
+		] {
+			let response = respond_from(&query(qname, TYPE_A), "192.0.2.1").unwrap();
+			assert_eq!(rcode(&response), RCODE_REFUSED, "{}", qname);
+			assert_eq!(response.len(), HEADER_LEN);
+		}
+	}
+
+	#[test]
+	fn dropped_and_unsupported() {
+		// Responses are never answered
+		let mut packet = query(ZONE, TYPE_A);
+		packet[2] |= 0x80;
+		assert_eq!(respond_from(&packet, "192.0.2.1"), None);
+
+		// Opcode STATUS
+		let mut packet = query(ZONE, TYPE_A);
+		packet[2] |= 2 << 3;
+		assert_eq!(
+			rcode(&respond_from(&packet, "192.0.2.1").unwrap()),
+			RCODE_NOTIMP
+		);
+
+		// Two questions
+		let mut packet = query(ZONE, TYPE_A);
+		packet[5] = 2;
+		packet.extend_from_within(HEADER_LEN..);
+		assert_eq!(
+			rcode(&respond_from(&packet, "192.0.2.1").unwrap()),
+			RCODE_FORMERR
+		);
+	}
+
+	#[test]
+	fn edns_client_subnet() {
+		let packet = edns_query(ZONE, TYPE_TXT, &client_subnet(1, 24, &[198, 51, 100]));
+		let response = respond_from(&packet, "192.0.2.1").unwrap();
+		let (records, opt) = answers(&response);
+		assert_eq!(
+			records,
+			vec![
+				(TYPE_TXT, txt("192.0.2.1")),
+				(TYPE_TXT, txt("edns0-client-subnet 198.51.100.0/24")),
+			]
+		);
+
+		// The option is echoed with the source prefix as the scope
+		let mut echoed = client_subnet(1, 24, &[198, 51, 100]);
+		echoed[7] = 24;
+		assert_eq!(opt, Some(echoed));
+
+		let packet = edns_query(
+			ZONE,
+			TYPE_TXT,
+			&client_subnet(2, 56, &[0x20, 1, 0xd, 0xb8, 0, 0, 1]),
+		);
+		let response = respond_from(&packet, "192.0.2.1").unwrap();
+		assert_eq!(
+			answers(&response).0[1],
+			(TYPE_TXT, txt("edns0-client-subnet 2001:db8:0:100::/56"))
+		);
+	}
+
+	#[test]
+	fn edns_without_client_subnet() {
+		// Other options are skipped
+		let mut options = vec![0, 10, 0, 8, 1, 2, 3, 4, 5, 6, 7, 8];
+		let response = respond_from(&edns_query(ZONE, TYPE_A, &options), "192.0.2.1").unwrap();
+		assert_eq!(
+			answers(&response),
+			(vec![(TYPE_A, vec![192, 0, 2, 1])], Some(vec![]))
+		);
+
+		options.extend_from_slice(&client_subnet(1, 16, &[198, 51]));
+		let response = respond_from(&edns_query(ZONE, TYPE_TXT, &options), "192.0.2.1").unwrap();
+		assert_eq!(answers(&response).0.len(), 2);
+
+		// Addresses that are not truncated to the source prefix, and unknown families
+		for option in [
+			client_subnet(1, 24, &[198, 51, 100, 0]),
+			client_subnet(1, 33, &[198, 51, 100, 0, 0]),
+			client_subnet(3, 8, &[1]),
+			OPTION_CLIENT_SUBNET.to_be_bytes().to_vec(),
+		] {
+			let response = respond_from(&edns_query(ZONE, TYPE_TXT, &option), "192.0.2.1").unwrap();
+			let (records, opt) = answers(&response);
+			assert_eq!(records.len(), 1, "{:?}", option);
+			assert!(opt.is_some());
+		}
+	}
+
+	#[test]
+	fn compression_pointers() {
+		// "example.com" at 12, then "whoami" followed by a pointer to it
+		let mut packet = header(0, 0, 0);
+		packet.extend_from_slice(&name("example.com"));
+		let second = packet.len();
+		packet.extend_from_slice(b"\x06whoami\xc0\x0c");
+		packet.extend_from_slice(b"\x03sub\xc0");
+		packet.push(second as u8);
+		packet.extend_from_slice(b"rest");
+
+		let mut r = Reader {
+			packet: &packet,
+			pos: HEADER_LEN,
+		};
+		assert_eq!(r.name().as_deref(), Some("example.com"));
+		assert_eq!(r.name().as_deref(), Some("whoami.example.com"));
+		// Reading continues after the first pointer
+		assert_eq!(r.name().as_deref(), Some("sub.whoami.example.com"));
+		assert_eq!(r.bytes(4), Some(&b"rest"[..]));
+
+		// Records after the question may point into it
+		let mut packet = query(ZONE, TYPE_A);
+		packet[11] = 1;
+		packet.extend_from_slice(b"\xc0\x0c");
+		packet.extend_from_slice(&TYPE_A.to_be_bytes());
+		packet.extend_from_slice(&CLASS_IN.to_be_bytes());
+		packet.extend_from_slice(&[0, 0, 0, 0, 0, 4, 192, 0, 2, 1]);
+		let response = respond_from(&packet, "192.0.2.1").unwrap();
+		assert_eq!(
+			answers(&response),
+			(vec![(TYPE_A, vec![192, 0, 2, 1])], None)
+		);
+	}
+
+	#[test]
+	fn compression_loops() {
+		let mut packet = header(0, 1, 0);
+		// Pointing to itself
+		packet.extend_from_slice(b"\xc0\x0c\x00\x01\x00\x01");
+		assert_eq!(
+			rcode(&respond_from(&packet, "192.0.2.1").unwrap()),
+			RCODE_FORMERR
+		);
+
+		// Two labels pointing to each other
+		let mut packet = header(0, 1, 0);
+		packet.extend_from_slice(b"\x01a\xc0\x10\x01b\xc0\x0c\x00\x01\x00\x01");
+		assert_eq!(
+			rcode(&respond_from(&packet, "192.0.2.1").unwrap()),
+			RCODE_FORMERR
+		);
+
+		// A pointer past the end and one that is cut off
+		for name in [&b"\xc0\xff"[..], b"\x01a\xc0"] {
+			let mut packet = header(0, 1, 0);
+			packet.extend_from_slice(name);
+			assert_eq!(
+				rcode(&respond_from(&packet, "192.0.2.1").unwrap()),
+				RCODE_FORMERR
+			);
+		}
+
+		// The reserved label types 0x40 and 0x80
+		for len in [0x41, 0x81] {
+			let mut packet = header(0, 1, 0);
+			packet.extend_from_slice(&[len, b'a', 0, 0, 1, 0, 1]);
+			assert_eq!(
+				rcode(&respond_from(&packet, "192.0.2.1").unwrap()),
+				RCODE_FORMERR
+			);
+		}
+	}
+
+	#[test]
+	fn truncated_packets() {
+		let packet = edns_query(ZONE, TYPE_TXT, &client_subnet(1, 24, &[198, 51, 100]));
+		for len in 0..packet.len() {
+			let response = respond_from(&packet[..len], "192.0.2.1");
+			match len < HEADER_LEN {
+				true => assert_eq!(response, None),
+				false => assert_eq!(rcode(&response.unwrap()), RCODE_FORMERR, "{}", len),
+			}
+		}
+
+		// An option that claims more data than the OPT record has
+		let mut option = client_subnet(1, 24, &[198, 51, 100]);
+		option[3] = 200;
+		let response = respond_from(&edns_query(ZONE, TYPE_TXT, &option), "192.0.2.1").unwrap();
+		assert_eq!(answers(&response).0.len(), 1);
+	}
+
+	#[test]
+	fn malformed_packets_do_not_panic() {
+		// Every value of every byte of a query
+		let packet = edns_query(
+			ZONE,
+			TYPE_TXT,
+			&client_subnet(2, 56, &[0x20, 1, 0xd, 0xb8, 0, 0, 1]),
+		);
+		for i in 0..packet.len() {
+			let mut packet = packet.clone();
+			for value in 0..=u8::MAX {
+				packet[i] = value;
+				respond_from(&packet, "192.0.2.1");
+				respond_from(&packet, "2001:db8::1");
+			}
+		}
+
+		// Random packets, with a fixed seed
+		let mut state = 0x2545_f491_4f6c_dd1du64;
+		for _ in 0..20000 {
+			state ^= state << 13;
+			state ^= state >> 7;
+			state ^= state << 17;
+			let len = (state % 128) as usize;
+			let mut packet: Vec<u8> = (0..len)
+				.map(|i| (state.rotate_left(i as u32 * 8) >> 56) as u8 ^ i as u8)
+				.collect();
+			if packet.len() >= HEADER_LEN {
+				// A query with one question, so that parsing gets past the header
+				packet[2] &= 0x07;
+				packet[4] = 0;
+				packet[5] = 1;
+			}
+			respond_from(&packet, "192.0.2.1");
+		}
+	}
+}
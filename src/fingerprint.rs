@@ -0,0 +1,467 @@
+//! JA3 and JA4 fingerprints of the TLS ClientHello.
+//!
+//! See <https://github.com/salesforce/ja3> and
+//! <https://github.com/FoxIO-LLC/ja4/blob/main/technical_details/JA4.md>.
+
+use md5::Md5;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::fmt::Write;
+use std::io;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+const RECORD_HANDSHAKE: u8 = 22;
+const HANDSHAKE_CLIENT_HELLO: u8 = 1;
+/// Upper bound for the ClientHello, which is far larger than any real one
+const MAX_CLIENT_HELLO_LEN: usize = 64 * 1024;
+
+const EXT_SERVER_NAME: u16 = 0x0000;
+const EXT_SUPPORTED_GROUPS: u16 = 0x000a;
+const EXT_EC_POINT_FORMATS: u16 = 0x000b;
+const EXT_SIGNATURE_ALGORITHMS: u16 = 0x000d;
+const EXT_ALPN: u16 = 0x0010;
+const EXT_SUPPORTED_VERSIONS: u16 = 0x002b;
+
+#[derive(Debug, Serialize)]
+pub struct Fingerprint {
+	pub ja3: String,
+	pub ja3_hash: String,
+	pub ja4: String,
+}
+
+/// Reads TLS records from the stream until the whole ClientHello message has been received.
+///
+/// Returns the handshake message, if the stream starts with one, and all bytes that
+/// were read, which must be replayed to the TLS acceptor.
+pub async fn read_client_hello<S: AsyncRead + Unpin>(
+	stream: &mut S,
+) -> io::Result<(Option<Vec<u8>>, Vec<u8>)> {
+	let mut buf = Vec::with_capacity(2048);
+	let mut message = Vec::new();
+	let mut pos = 0;
+
+	loop {
+		// Consume all complete records in the buffer
+		while buf.len() >= pos + 5 {
+			if buf[pos] != RECORD_HANDSHAKE {
+				return Ok((None, buf));
+			}
+			let len = u16::from_be_bytes([buf[pos + 3], buf[pos + 4]]) as usize;
+			if buf.len() < pos + 5 + len {
+				break;
+			}
+			message.extend_from_slice(&buf[pos + 5..pos + 5 + len]);
+			pos += 5 + len;
+
+			if message.len() >= 4 {
+				if message[0] != HANDSHAKE_CLIENT_HELLO {
+					return Ok((None, buf));
+				}
+				let msg_len = u32::from_be_bytes([0, message[1], message[2], message[3]]) as usize;
+				if message.len() >= 4 + msg_len {
+					message.truncate(4 + msg_len);
+					return Ok((Some(message), buf));
+				}
+			}
+		}
+
+		if buf.len() > MAX_CLIENT_HELLO_LEN {
+			return Ok((None, buf));
+		}
+		if stream.read_buf(&mut buf).await? == 0 {
+			return Err(io::ErrorKind::UnexpectedEof.into());
+		}
+	}
+}
+
+/// The parts of a ClientHello that make up the fingerprints, without GREASE values.
+#[derive(Default)]
+struct ClientHello {
+	version: u16,
+	ciphers: Vec<u16>,
+	extensions: Vec<u16>,
+	groups: Vec<u16>,
+	point_formats: Vec<u8>,
+	signature_algorithms: Vec<u16>,
+	supported_versions: Vec<u16>,
+	alpn: Option<Vec<u8>>,
+	has_sni: bool,
+}
+
+impl Fingerprint {
+	/// Computes the fingerprints of a ClientHello handshake message,
+	/// or `None` if it is malformed.
+	pub fn new(message: &[u8]) -> Option<Self> {
+		let hello = parse_client_hello(message.get(4..)?)?;
+
+		let ja3 = ja3(&hello);
+		let ja3_hash = md5_hex(&ja3);
+
+		Some(Fingerprint {
+			ja3,
+			ja3_hash,
+			ja4: ja4(&hello),
+		})
+	}
+}
+
+/// `SSLVersion,Ciphers,Extensions,EllipticCurves,EllipticCurvePointFormats`
+/// with every list in the order sent by the client.
+fn ja3(hello: &ClientHello) -> String {
+	fn join<T: ToString>(values: &[T]) -> String {
+		values
+			.iter()
+			.map(ToString::to_string)
+			.collect::<Vec<_>>()
+			.join("-")
+	}
+
+	format!(
+		"{},{},{},{},{}",
+		hello.version,
+		join(&hello.ciphers),
+		join(&hello.extensions),
+		join(&hello.groups),
+		join(&hello.point_formats),
+	)
+}
+
+fn ja4(hello: &ClientHello) -> String {
+	let version = hello
+		.supported_versions
+		.iter()
+		.copied()
+		.max()
+		.unwrap_or(hello.version);
+	let version = match version {
+		0x0304 => "13",
+		0x0303 => "12",
+		0x0302 => "11",
+		0x0301 => "10",
+		0x0300 => "s3",
+		_ => "00",
+	};
+
+	// The first and last character of the first ALPN protocol, or of its hex
+	// representation if they are not alphanumeric
+	let alpn = match hello.alpn.as_deref() {
+		Some([first, .., last]) | Some([first @ last]) => {
+			if first.is_ascii_alphanumeric() && last.is_ascii_alphanumeric() {
+				format!("{}{}", *first as char, *last as char)
+			} else {
+				format!("{:x}{:x}", first >> 4, last & 0x0f)
+			}
+		}
+		_ => "00".to_string(),
+	};
+
+	let mut ciphers = hello.ciphers.clone();
+	ciphers.sort_unstable();
+
+	let mut extensions: Vec<u16> = hello
+		.extensions
+		.iter()
+		.copied()
+		.filter(|&ext| ext != EXT_SERVER_NAME && ext != EXT_ALPN)
+		.collect();
+	extensions.sort_unstable();
+
+	let mut extensions_and_algorithms = hex_list(&extensions);
+	if !hello.signature_algorithms.is_empty() {
+		extensions_and_algorithms.push('_');
+		extensions_and_algorithms.push_str(&hex_list(&hello.signature_algorithms));
+	}
+
+	format!(
+		"t{}{}{:02}{:02}{}_{}_{}",
+		version,
+		if hello.has_sni { 'd' } else { 'i' },
+		hello.ciphers.len().min(99),
+		hello.extensions.len().min(99),
+		alpn,
+		truncated_hash(&hex_list(&ciphers), ciphers.is_empty()),
+		truncated_hash(&extensions_and_algorithms, extensions.is_empty()),
+	)
+}
+
+fn hex_list(values: &[u16]) -> String {
+	values
+		.iter()
+		.map(|v| format!("{:04x}", v))
+		.collect::<Vec<_>>()
+		.join(",")
+}
+
+/// The first 12 hex digits of the SHA-256 hash, or zeros if there was nothing to hash.
+fn truncated_hash(s: &str, empty: bool) -> String {
+	if empty {
+		return "0".repeat(12);
+	}
+	hex(&Sha256::digest(s.as_bytes())[..6])
+}
+
+pub(crate) fn md5_hex(s: &str) -> String {
+	hex(&Md5::digest(s.as_bytes()))
+}
+
+fn hex(bytes: &[u8]) -> String {
+	let mut s = String::with_capacity(bytes.len() * 2);
+	for b in bytes {
+		let _ = write!(s, "{:02x}", b);
+	}
+	s
+}
+
+/// GREASE values (RFC 8701) are random and would make fingerprints unstable.
+fn is_grease(value: u16) -> bool {
+	value & 0x0f0f == 0x0a0a && value >> 8 == value & 0xff
+}
+
+/// Parses the body of a ClientHello message.
+fn parse_client_hello(body: &[u8]) -> Option<ClientHello> {
+	let mut r = Reader(body);
+	let mut hello = ClientHello {
+		version: r.u16()?,
+		..Default::default()
+	};
+
+	r.bytes(32)?;
+	let session_id_len = r.u8()?;
+	r.bytes(session_id_len as usize)?;
+
+	let mut ciphers = Reader(r.vec16()?);
+	while !ciphers.is_empty() {
+		hello.ciphers.push(ciphers.u16()?);
+	}
+
+	let compression_len = r.u8()?;
+	r.bytes(compression_len as usize)?;
+
+	// Extensions are optional in very old clients
+	if !r.is_empty() {
+		let mut extensions = Reader(r.vec16()?);
+		while !extensions.is_empty() {
+			let ext = extensions.u16()?;
+			let mut data = Reader(extensions.vec16()?);
+			hello.extensions.push(ext);
+
+			match ext {
+				EXT_SERVER_NAME => hello.has_sni = true,
+				EXT_SUPPORTED_GROUPS => {
+					let mut list = Reader(data.vec16()?);
+					while !list.is_empty() {
+						hello.groups.push(list.u16()?);
+					}
+				}
+				EXT_EC_POINT_FORMATS => {
+					let len = data.u8()?;
+					hello.point_formats = data.bytes(len as usize)?.to_vec();
+				}
+				EXT_SIGNATURE_ALGORITHMS => {
+					let mut list = Reader(data.vec16()?);
+					while !list.is_empty() {
+						hello.signature_algorithms.push(list.u16()?);
+					}
+				}
+				EXT_ALPN => {
+					let mut list = Reader(data.vec16()?);
+					if !list.is_empty() {
+						let len = list.u8()?;
+						hello.alpn = Some(list.bytes(len as usize)?.to_vec());
+					}
+				}
+				EXT_SUPPORTED_VERSIONS => {
+					let len = data.u8()?;
+					let mut list = Reader(data.bytes(len as usize)?);
+					while !list.is_empty() {
+						hello.supported_versions.push(list.u16()?);
+					}
+				}
+				_ => {}
+			}
+		}
+	}
+
+	hello.ciphers.retain(|&v| !is_grease(v));
+	hello.extensions.retain(|&v| !is_grease(v));
+	hello.groups.retain(|&v| !is_grease(v));
+	hello.signature_algorithms.retain(|&v| !is_grease(v));
+	hello.supported_versions.retain(|&v| !is_grease(v));
+
+	Some(hello)
+}
+
+/// Reads big-endian values from the front of a byte slice.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+	fn is_empty(&self) -> bool {
+		self.0.is_empty()
+	}
+
+	fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+		if self.0.len() < len {
+			return None;
+		}
+		let (bytes, rest) = self.0.split_at(len);
+		self.0 = rest;
+		Some(bytes)
+	}
+
+	fn u8(&mut self) -> Option<u8> {
+		self.bytes(1).map(|b| b[0])
+	}
+
+	fn u16(&mut self) -> Option<u16> {
+		self.bytes(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+	}
+
+	/// A vector with a 16 bit length prefix
+	fn vec16(&mut self) -> Option<&'a [u8]> {
+		let len = self.u16()?;
+		self.bytes(len as usize)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// The ClientHello of curl 7.88.1 with OpenSSL 3 to a host name, without the record header
+	const CURL_CLIENT_HELLO: &str = concat!(
+		"010001fc030328b4d39e93389bc97816c269d78b78d9746755202d716fbcc386b5d9d0e1e13c20c7a757c817",
+		"2f82ae7cc835604d77d6815efee09b074b2c407b1391717a80af68003e130213031301c02cc030009fcca9cc",
+		"a8ccaac02bc02f009ec024c028006bc023c0270067c00ac0140039c009c0130033009d009c003d003c003500",
+		"2f00ff0100017500000014001200000f7775742e6578616d706c652e636f6d000b000403000102000a001600",
+		"14001d0017001e00190018010001010102010301040010000e000c02683208687474702f312e310016000000",
+		"17000000310000000d002a0028040305030603080708080809080a080b080408050806040105010601030303",
+		"010302040205020602002b0009080304030303020301002d00020101003300260024001d002034981112c2bf",
+		"aa702d6812d2699229add1310c3f34bba0e0978d87bdd182570e001500ae0000000000000000000000000000",
+		"0000000000000000000000000000000000000000000000000000000000000000000000000000000000000000",
+		"0000000000000000000000000000000000000000000000000000000000000000000000000000000000000000",
+		"0000000000000000000000000000000000000000000000000000000000000000000000000000000000000000",
+		"00000000000000000000000000000000000000000000000000000000",
+	);
+
+	/// The fingerprints shown in the README for curl
+	const CURL_JA3: &str = "771,4866-4867-4865-49196-49200-159-52393-52392-52394-49195-49199-158-\
+		49188-49192-107-49187-49191-103-49162-49172-57-49161-49171-51-157-156-61-60-53-47-255,\
+		0-11-10-16-22-23-49-13-43-45-51-21,29-23-30-25-24-256-257-258-259-260,0-1-2";
+	const CURL_JA3_HASH: &str = "0149f47eabf9a20d0893e2a44e5a6323";
+	const CURL_JA4: &str = "t13d3112h2_e8f1e7e78f70_b26ce05bbdd6";
+
+	fn unhex(s: &str) -> Vec<u8> {
+		(0..s.len())
+			.step_by(2)
+			.map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+			.collect()
+	}
+
+	fn curl_client_hello() -> Vec<u8> {
+		unhex(CURL_CLIENT_HELLO)
+	}
+
+	/// Wraps a handshake message in records of at most `fragment` bytes.
+	fn records(message: &[u8], fragment: usize) -> Vec<u8> {
+		let mut records = Vec::new();
+		for chunk in message.chunks(fragment) {
+			records.extend_from_slice(&[RECORD_HANDSHAKE, 0x03, 0x01]);
+			records.extend_from_slice(&(chunk.len() as u16).to_be_bytes());
+			records.extend_from_slice(chunk);
+		}
+		records
+	}
+
+	/// Adds a GREASE cipher suite and extension to the front of their lists.
+	fn add_grease(message: &[u8]) -> Vec<u8> {
+		fn add_u16(bytes: &mut [u8], value: u16) {
+			let old = u16::from_be_bytes([bytes[0], bytes[1]]);
+			bytes[..2].copy_from_slice(&(old + value).to_be_bytes());
+		}
+
+		let mut message = message.to_vec();
+		let ciphers = 4 + 2 + 32 + 1 + message[38] as usize;
+		message.splice(ciphers + 2..ciphers + 2, [0x0a, 0x0a]);
+		add_u16(&mut message[ciphers..], 2);
+
+		let cipher_len = u16::from_be_bytes([message[ciphers], message[ciphers + 1]]) as usize;
+		let compression = ciphers + 2 + cipher_len;
+		let extensions = compression + 1 + message[compression] as usize;
+		message.splice(extensions + 2..extensions + 2, [0x3a, 0x3a, 0, 0]);
+		add_u16(&mut message[extensions..], 4);
+
+		add_u16(&mut message[2..], 6);
+		message
+	}
+
+	#[test]
+	fn curl() {
+		let fingerprint = Fingerprint::new(&curl_client_hello()).unwrap();
+		assert_eq!(fingerprint.ja3, CURL_JA3);
+		assert_eq!(fingerprint.ja3_hash, CURL_JA3_HASH);
+		assert_eq!(fingerprint.ja4, CURL_JA4);
+	}
+
+	#[test]
+	fn grease() {
+		let message = add_grease(&curl_client_hello());
+		assert_ne!(message, curl_client_hello());
+
+		let fingerprint = Fingerprint::new(&message).unwrap();
+		assert_eq!(fingerprint.ja3, CURL_JA3);
+		assert_eq!(fingerprint.ja4, CURL_JA4);
+
+		assert!(is_grease(0x0a0a) && is_grease(0xfafa));
+		assert!(!is_grease(0x0a1a) && !is_grease(0x1301));
+	}
+
+	#[test]
+	fn truncated() {
+		let message = curl_client_hello();
+		// Extensions are optional, so the message can end after the compression methods
+		let extensions = 4 + 2 + 32 + 1 + 32 + 2 + 62 + 2;
+		let extensions_len = u16::from_be_bytes([message[extensions], message[extensions + 1]]);
+		assert_eq!(extensions + 2 + extensions_len as usize, message.len());
+
+		for len in 0..message.len() {
+			let fingerprint = Fingerprint::new(&message[..len]);
+			assert_eq!(fingerprint.is_some(), len == extensions, "{}", len);
+		}
+	}
+
+	#[tokio::test]
+	async fn read_fragmented() {
+		let message = curl_client_hello();
+		for fragment in [message.len(), 100, 1] {
+			let mut input = records(&message, fragment);
+			input.extend_from_slice(b"\x14\x03\x03\x00\x01\x01");
+
+			let mut stream = &input[..];
+			let (hello, read) = read_client_hello(&mut stream).await.unwrap();
+			assert_eq!(hello.as_deref(), Some(&message[..]));
+			// Everything read is replayed
+			let mut replayed = read;
+			replayed.extend_from_slice(stream);
+			assert_eq!(replayed, input);
+		}
+	}
+
+	#[tokio::test]
+	async fn read_other_protocols() {
+		let mut stream = &b"GET / HTTP/1.1\r\n\r\n"[..];
+		let (hello, read) = read_client_hello(&mut stream).await.unwrap();
+		assert_eq!(hello, None);
+		assert_eq!(read, b"GET / HTTP/1.1\r\n\r\n");
+
+		// A handshake message other than a ClientHello
+		let mut message = curl_client_hello();
+		message[0] = 2;
+		let input = records(&message, message.len());
+		let (hello, _) = read_client_hello(&mut &input[..]).await.unwrap();
+		assert_eq!(hello, None);
+
+		let input = records(&curl_client_hello(), 100);
+		let res = read_client_hello(&mut &input[..input.len() - 1]).await;
+		assert!(res.is_err_and(|e| e.kind() == io::ErrorKind::UnexpectedEof));
+	}
+}
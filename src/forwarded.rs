@@ -0,0 +1,440 @@
+//! Client address resolution from the `Forwarded` (RFC 7239), `X-Forwarded-For`
+//! and `X-Real-IP` headers set by trusted reverse proxies.
+
+use hyper::header::{HeaderMap, HeaderName, FORWARDED};
+use ipnet::IpNet;
+use std::net::{IpAddr, SocketAddr};
+
+static X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+static X_REAL_IP: HeaderName = HeaderName::from_static("x-real-ip");
+
+/// The address of the client that sent a request.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ClientAddr {
+	pub ip: IpAddr,
+	/// Not every proxy header includes the port
+	pub port: Option<u16>,
+}
+
+impl From<SocketAddr> for ClientAddr {
+	fn from(addr: SocketAddr) -> Self {
+		ClientAddr {
+			ip: addr.ip(),
+			port: Some(addr.port()),
+		}
+	}
+}
+
+/// Returns the client address for a request from `peer_addr`.
+///
+/// The headers are only considered if the peer is a trusted proxy. The chain of
+/// addresses is walked from right to left, skipping trusted proxies, and the first
+/// untrusted address is the client. Reaching a malformed entry falls back to the
+/// peer address, so that attacker-controlled text is never echoed.
+pub fn client_addr(headers: &HeaderMap, peer_addr: SocketAddr, trusted: &[IpNet]) -> ClientAddr {
+	if !is_trusted(peer_addr.ip(), trusted) {
+		return peer_addr.into();
+	}
+
+	forwarded_client_addr(headers, peer_addr, trusted)
+}
+
+/// Like [`client_addr`], for a peer that is trusted regardless of its address.
+pub fn forwarded_client_addr(
+	headers: &HeaderMap,
+	peer_addr: SocketAddr,
+	trusted: &[IpNet],
+) -> ClientAddr {
+	let chain = if headers.contains_key(FORWARDED) {
+		parse_forwarded(headers)
+	} else if headers.contains_key(&X_FORWARDED_FOR) {
+		parse_x_forwarded_for(headers)
+	} else if let Some(value) = headers.get(&X_REAL_IP) {
+		value.to_str().ok().map(|v| vec![parse_node(v.trim())])
+	} else {
+		return peer_addr.into();
+	};
+
+	let mut client = None;
+
+	for entry in chain.unwrap_or_default().into_iter().rev() {
+		match entry {
+			None => return peer_addr.into(),
+			Some(addr) if !is_trusted(addr.ip, trusted) => return addr,
+			// If every hop is trusted, the leftmost one is the best guess for the client
+			Some(addr) => client = Some(addr),
+		}
+	}
+
+	client.unwrap_or_else(|| peer_addr.into())
+}
+
+fn is_trusted(ip: IpAddr, trusted: &[IpNet]) -> bool {
+	let ip = ip.to_canonical();
+	trusted.iter().any(|net| net.contains(&ip))
+}
+
+/// Collects the `for=` addresses of all `Forwarded` headers in order,
+/// with `None` for elements that are malformed.
+fn parse_forwarded(headers: &HeaderMap) -> Option<Vec<Option<ClientAddr>>> {
+	let mut chain = Vec::new();
+
+	for value in headers.get_all(FORWARDED) {
+		for element in split_unquoted(value.to_str().ok()?, ',') {
+			chain.push(parse_forwarded_element(element));
+		}
+	}
+
+	Some(chain)
+}
+
+/// Parses the `for=` address of an element like `for=192.0.2.60;proto=http`.
+fn parse_forwarded_element(element: &str) -> Option<ClientAddr> {
+	let mut element_for = None;
+
+	for pair in split_unquoted(element, ';') {
+		let (name, value) = pair.trim().split_once('=')?;
+		if !name.eq_ignore_ascii_case("for") {
+			continue;
+		}
+		if element_for.is_some() {
+			return None;
+		}
+
+		let value = match value.strip_prefix('"') {
+			Some(quoted) => quoted.strip_suffix('"')?,
+			None => value,
+		};
+		// Obfuscated and unknown identifiers cannot be echoed
+		element_for = Some(parse_forwarded_node(value)?);
+	}
+
+	element_for
+}
+
+/// Parses a `for=` node, e.g. `192.0.2.43`, `192.0.2.43:47011` or `[2001:db8::1]:4711`.
+fn parse_forwarded_node(node: &str) -> Option<ClientAddr> {
+	if let Some(rest) = node.strip_prefix('[') {
+		let (ip, port) = rest.split_once(']')?;
+		let ip = ip.parse::<std::net::Ipv6Addr>().ok()?;
+		let port = match port {
+			"" => None,
+			port => parse_port(port.strip_prefix(':')?)?,
+		};
+		return Some(ClientAddr {
+			ip: ip.into(),
+			port,
+		});
+	}
+
+	match node.split_once(':') {
+		None => Some(ClientAddr {
+			ip: IpAddr::V4(node.parse().ok()?),
+			port: None,
+		}),
+		Some((ip, port)) => Some(ClientAddr {
+			ip: IpAddr::V4(ip.parse().ok()?),
+			port: parse_port(port)?,
+		}),
+	}
+}
+
+/// Parses a port, where obfuscated ports like `_abc` are valid but unknown.
+fn parse_port(port: &str) -> Option<Option<u16>> {
+	if port.starts_with('_') {
+		return Some(None);
+	}
+	port.parse().ok().map(Some)
+}
+
+fn parse_x_forwarded_for(headers: &HeaderMap) -> Option<Vec<Option<ClientAddr>>> {
+	let mut chain = Vec::new();
+
+	for value in headers.get_all(&X_FORWARDED_FOR) {
+		for node in value.to_str().ok()?.split(',') {
+			chain.push(parse_node(node.trim()));
+		}
+	}
+
+	Some(chain)
+}
+
+/// Parses a plain address as used by `X-Forwarded-For` and `X-Real-IP`.
+/// Some proxies include the port, so that is accepted as well.
+fn parse_node(node: &str) -> Option<ClientAddr> {
+	if let Ok(ip) = node.parse::<IpAddr>() {
+		return Some(ClientAddr { ip, port: None });
+	}
+
+	node.parse::<SocketAddr>().ok().map(ClientAddr::from)
+}
+
+/// Splits on a separator that is not inside a quoted string.
+fn split_unquoted(s: &str, separator: char) -> Vec<&str> {
+	let mut parts = Vec::new();
+	let mut in_quotes = false;
+	let mut escaped = false;
+	let mut start = 0;
+
+	for (i, c) in s.char_indices() {
+		match c {
+			_ if escaped => escaped = false,
+			'\\' if in_quotes => escaped = true,
+			'"' => in_quotes = !in_quotes,
+			c if c == separator && !in_quotes => {
+				parts.push(&s[start..i]);
+				start = i + 1;
+			}
+			_ => {}
+		}
+	}
+	parts.push(&s[start..]);
+
+	parts
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn trusted() -> Vec<IpNet> {
+		vec!["10.0.0.0/8".parse().unwrap(), "fd00::/8".parse().unwrap()]
+	}
+
+	fn peer() -> SocketAddr {
+		"10.0.0.1:4000".parse().unwrap()
+	}
+
+	fn headers(headers: &[(&'static str, &'static str)]) -> HeaderMap {
+		let mut map = HeaderMap::new();
+		for (name, value) in headers {
+			map.append(*name, value.parse().unwrap());
+		}
+		map
+	}
+
+	fn resolve(list: &[(&'static str, &'static str)]) -> ClientAddr {
+		client_addr(&headers(list), peer(), &trusted())
+	}
+
+	fn addr(ip: &str, port: Option<u16>) -> ClientAddr {
+		ClientAddr {
+			ip: ip.parse().unwrap(),
+			port,
+		}
+	}
+
+	#[test]
+	fn untrusted_peer() {
+		let peer = "192.0.2.1:4000".parse().unwrap();
+		let headers = headers(&[("forwarded", "for=198.51.100.7")]);
+		assert_eq!(client_addr(&headers, peer, &trusted()), peer.into());
+
+		// Unless it is trusted regardless of its address, like a PROXY protocol listener
+		assert_eq!(
+			forwarded_client_addr(&headers, peer, &trusted()),
+			addr("198.51.100.7", None)
+		);
+	}
+
+	#[test]
+	fn no_headers() {
+		assert_eq!(resolve(&[]), peer().into());
+	}
+
+	#[test]
+	fn ipv4_mapped_peer() {
+		let peer = "[::ffff:10.0.0.1]:4000".parse().unwrap();
+		let headers = headers(&[("x-forwarded-for", "198.51.100.7")]);
+		assert_eq!(
+			client_addr(&headers, peer, &trusted()),
+			addr("198.51.100.7", None)
+		);
+	}
+
+	#[test]
+	fn forwarded() {
+		assert_eq!(
+			resolve(&[("forwarded", "for=192.0.2.60;proto=http;by=203.0.113.43")]),
+			addr("192.0.2.60", None)
+		);
+		assert_eq!(
+			resolve(&[("forwarded", "For=192.0.2.60")]),
+			addr("192.0.2.60", None)
+		);
+		// Elements without a for= parameter do not name a client
+		assert_eq!(resolve(&[("forwarded", "proto=https")]), peer().into());
+	}
+
+	#[test]
+	fn forwarded_quoting() {
+		assert_eq!(
+			resolve(&[("forwarded", "for=\"192.0.2.43:47011\"")]),
+			addr("192.0.2.43", Some(47011))
+		);
+		// Separators inside quoted strings do not split elements or pairs
+		assert_eq!(
+			resolve(&[("forwarded", "for=192.0.2.43;host=\"a,b;c\";proto=https")]),
+			addr("192.0.2.43", None)
+		);
+		assert_eq!(
+			resolve(&[("forwarded", "for=192.0.2.43;ext=\"a\\\",for=198.51.100.7\"")]),
+			addr("192.0.2.43", None)
+		);
+		// An unterminated quote
+		assert_eq!(resolve(&[("forwarded", "for=\"192.0.2.43")]), peer().into());
+	}
+
+	#[test]
+	fn forwarded_ipv6() {
+		assert_eq!(
+			resolve(&[("forwarded", "for=\"[2001:db8:cafe::17]:4711\"")]),
+			addr("2001:db8:cafe::17", Some(4711))
+		);
+		assert_eq!(
+			resolve(&[("forwarded", "for=\"[2001:db8:cafe::17]\"")]),
+			addr("2001:db8:cafe::17", None)
+		);
+		// Obfuscated ports are valid, but not known
+		assert_eq!(
+			resolve(&[("forwarded", "for=\"[2001:db8:cafe::17]:_abc\"")]),
+			addr("2001:db8:cafe::17", None)
+		);
+		// IPv6 addresses must be bracketed
+		assert_eq!(
+			resolve(&[("forwarded", "for=\"2001:db8:cafe::17\"")]),
+			peer().into()
+		);
+		assert_eq!(
+			resolve(&[("forwarded", "for=\"[192.0.2.43]\"")]),
+			peer().into()
+		);
+	}
+
+	#[test]
+	fn forwarded_chain() {
+		// Walked from the right, skipping the trusted proxies
+		assert_eq!(
+			resolve(&[(
+				"forwarded",
+				"for=192.0.2.1, for=198.51.100.7, for=10.1.1.1, for=\"[fd00::1]\""
+			)]),
+			addr("198.51.100.7", None)
+		);
+		// Multiple headers form one chain in order
+		assert_eq!(
+			resolve(&[
+				("forwarded", "for=192.0.2.1"),
+				("forwarded", "for=198.51.100.7"),
+				("forwarded", "for=10.1.1.1"),
+			]),
+			addr("198.51.100.7", None)
+		);
+	}
+
+	#[test]
+	fn all_trusted() {
+		assert_eq!(
+			resolve(&[("x-forwarded-for", "10.3.3.3, 10.2.2.2")]),
+			addr("10.3.3.3", None)
+		);
+	}
+
+	#[test]
+	fn spoofed_leftmost_entry() {
+		// The client prepended its own entry, which is left of the address the trusted
+		// proxy saw
+		assert_eq!(
+			resolve(&[("x-forwarded-for", "127.0.0.1, 198.51.100.7")]),
+			addr("198.51.100.7", None)
+		);
+		assert_eq!(
+			resolve(&[("forwarded", "for=10.9.9.9, for=198.51.100.7")]),
+			addr("198.51.100.7", None)
+		);
+		assert_eq!(
+			resolve(&[
+				("x-forwarded-for", "10.9.9.9"),
+				("x-forwarded-for", "198.51.100.7, 10.2.2.2"),
+			]),
+			addr("198.51.100.7", None)
+		);
+	}
+
+	#[test]
+	fn malformed_entry() {
+		// Reaching a malformed entry falls back to the peer
+		for value in [
+			"<script>",
+			"192.0.2.1, <script>, 10.2.2.2",
+			"",
+			"192.0.2.999",
+		] {
+			assert_eq!(
+				resolve(&[("x-forwarded-for", value)]),
+				peer().into(),
+				"{}",
+				value
+			);
+		}
+		for value in [
+			"for=unknown",
+			"for=_hidden",
+			"for=192.0.2.1;for=192.0.2.2",
+			"for",
+			"for=192.0.2.1:99999",
+			"for=192.0.2.1, garbage",
+		] {
+			assert_eq!(resolve(&[("forwarded", value)]), peer().into(), "{}", value);
+		}
+
+		// Malformed entries left of the client are never reached
+		assert_eq!(
+			resolve(&[("x-forwarded-for", "<script>, 198.51.100.7")]),
+			addr("198.51.100.7", None)
+		);
+	}
+
+	#[test]
+	fn x_forwarded_for() {
+		assert_eq!(
+			resolve(&[("x-forwarded-for", "198.51.100.7")]),
+			addr("198.51.100.7", None)
+		);
+		assert_eq!(
+			resolve(&[("x-forwarded-for", "198.51.100.7:5000")]),
+			addr("198.51.100.7", Some(5000))
+		);
+		assert_eq!(
+			resolve(&[("x-forwarded-for", "2001:db8::1,[2001:db8::2]:443")]),
+			addr("2001:db8::2", Some(443))
+		);
+
+		// Forwarded takes precedence
+		assert_eq!(
+			resolve(&[
+				("x-forwarded-for", "203.0.113.1"),
+				("forwarded", "for=198.51.100.7"),
+			]),
+			addr("198.51.100.7", None)
+		);
+	}
+
+	#[test]
+	fn x_real_ip() {
+		assert_eq!(
+			resolve(&[("x-real-ip", " 198.51.100.7 ")]),
+			addr("198.51.100.7", None)
+		);
+		assert_eq!(resolve(&[("x-real-ip", "nope")]), peer().into());
+
+		// X-Forwarded-For takes precedence
+		assert_eq!(
+			resolve(&[
+				("x-real-ip", "203.0.113.1"),
+				("x-forwarded-for", "198.51.100.7"),
+			]),
+			addr("198.51.100.7", None)
+		);
+	}
+}
@@ -0,0 +1,478 @@
+//! Akamai-style fingerprints of the frames an HTTP/2 client sends at the start
+//! of a connection, in the format `SETTINGS|WINDOW_UPDATE|PRIORITY|Pseudo-Header-Order`.
+//!
+//! See <https://www.blackhat.com/docs/eu-17/materials/eu-17-Shuster-Passive-Fingerprinting-Of-HTTP2-Clients-wp.pdf>.
+
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, OnceLock};
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+/// Stop recording if the first HEADERS frame has not arrived by then
+const MAX_RECORDED_LEN: usize = 64 * 1024;
+
+const FRAME_HEADERS: u8 = 0x1;
+const FRAME_PRIORITY: u8 = 0x2;
+const FRAME_SETTINGS: u8 = 0x4;
+const FRAME_WINDOW_UPDATE: u8 = 0x8;
+
+const FLAG_ACK: u8 = 0x1;
+const FLAG_PADDED: u8 = 0x8;
+const FLAG_PRIORITY: u8 = 0x20;
+
+/// The fingerprint of a connection, set once its first HEADERS frame has been read.
+pub type H2Fingerprint = Arc<OnceLock<String>>;
+
+/// A stream that records what the client sends until its first HEADERS frame.
+pub struct H2Recorder<S> {
+	inner: S,
+	recorded: Option<Vec<u8>>,
+	fingerprint: Option<H2Fingerprint>,
+}
+
+impl<S> H2Recorder<S> {
+	/// Records the connection preface if a fingerprint should be taken, otherwise
+	/// the stream is passed through.
+	pub fn new(inner: S, fingerprint: Option<H2Fingerprint>) -> Self {
+		H2Recorder {
+			inner,
+			recorded: fingerprint.as_ref().map(|_| Vec::new()),
+			fingerprint,
+		}
+	}
+
+	fn record(&mut self, bytes: &[u8]) {
+		let recorded = match &mut self.recorded {
+			Some(recorded) => recorded,
+			None => return,
+		};
+		recorded.extend_from_slice(bytes);
+
+		match parse(recorded) {
+			Parse::Incomplete if recorded.len() < MAX_RECORDED_LEN => {}
+			Parse::Done(fingerprint) => {
+				if let Some(cell) = &self.fingerprint {
+					let _ = cell.set(fingerprint);
+				}
+				self.recorded = None;
+			}
+			_ => self.recorded = None,
+		}
+	}
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for H2Recorder<S> {
+	fn poll_read(
+		mut self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &mut ReadBuf<'_>,
+	) -> Poll<io::Result<()>> {
+		let filled = buf.filled().len();
+		let res = Pin::new(&mut self.inner).poll_read(cx, buf);
+
+		if let Poll::Ready(Ok(())) = res {
+			self.record(&buf.filled()[filled..]);
+		}
+		res
+	}
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for H2Recorder<S> {
+	fn poll_write(
+		mut self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &[u8],
+	) -> Poll<io::Result<usize>> {
+		Pin::new(&mut self.inner).poll_write(cx, buf)
+	}
+
+	fn poll_write_vectored(
+		mut self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		bufs: &[io::IoSlice<'_>],
+	) -> Poll<io::Result<usize>> {
+		Pin::new(&mut self.inner).poll_write_vectored(cx, bufs)
+	}
+
+	fn is_write_vectored(&self) -> bool {
+		self.inner.is_write_vectored()
+	}
+
+	fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		Pin::new(&mut self.inner).poll_flush(cx)
+	}
+
+	fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		Pin::new(&mut self.inner).poll_shutdown(cx)
+	}
+}
+
+enum Parse {
+	Incomplete,
+	/// Not an HTTP/2 connection, or a malformed one
+	Invalid,
+	Done(String),
+}
+
+/// Parses the frames up to and including the first HEADERS frame.
+fn parse(buf: &[u8]) -> Parse {
+	if buf.len() < PREFACE.len() {
+		return match PREFACE.starts_with(buf) {
+			true => Parse::Incomplete,
+			false => Parse::Invalid,
+		};
+	}
+	if !buf.starts_with(PREFACE) {
+		return Parse::Invalid;
+	}
+
+	let mut settings = Vec::new();
+	let mut window_update = None;
+	let mut priorities = Vec::new();
+	let mut pos = PREFACE.len();
+
+	loop {
+		let header = match buf.get(pos..pos + 9) {
+			Some(header) => header,
+			None => return Parse::Incomplete,
+		};
+		let len = u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize;
+		let (kind, flags) = (header[3], header[4]);
+		let stream_id =
+			u32::from_be_bytes([header[5], header[6], header[7], header[8]]) & 0x7fff_ffff;
+
+		let payload = match buf.get(pos + 9..pos + 9 + len) {
+			Some(payload) => payload,
+			None => return Parse::Incomplete,
+		};
+		pos += 9 + len;
+
+		match kind {
+			FRAME_SETTINGS if flags & FLAG_ACK == 0 => {
+				for setting in payload.chunks_exact(6) {
+					let id = u16::from_be_bytes([setting[0], setting[1]]);
+					let value =
+						u32::from_be_bytes([setting[2], setting[3], setting[4], setting[5]]);
+					settings.push(format!("{}:{}", id, value));
+				}
+			}
+			FRAME_WINDOW_UPDATE if stream_id == 0 && payload.len() == 4 => {
+				let increment =
+					u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]]);
+				window_update = Some(increment & 0x7fff_ffff);
+			}
+			FRAME_PRIORITY if payload.len() == 5 => {
+				let dependency =
+					u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]]);
+				priorities.push(format!(
+					"{}:{}:{}:{}",
+					stream_id,
+					dependency >> 31,
+					dependency & 0x7fff_ffff,
+					// The weight is sent as one less than its value
+					payload[4] as u16 + 1,
+				));
+			}
+			FRAME_HEADERS => {
+				let pseudo_headers = match header_block(payload, flags) {
+					Some(block) => pseudo_header_order(block),
+					None => return Parse::Invalid,
+				};
+
+				return Parse::Done(format!(
+					"{}|{}|{}|{}",
+					settings.join(";"),
+					window_update.map_or("00".to_string(), |i| i.to_string()),
+					if priorities.is_empty() {
+						"0".to_string()
+					} else {
+						priorities.join(",")
+					},
+					pseudo_headers.join(","),
+				));
+			}
+			_ => {}
+		}
+	}
+}
+
+/// Strips the padding and priority fields from a HEADERS frame payload.
+fn header_block(payload: &[u8], flags: u8) -> Option<&[u8]> {
+	let mut block = payload;
+	let mut padding = 0;
+
+	if flags & FLAG_PADDED != 0 {
+		padding = *block.first()? as usize;
+		block = &block[1..];
+	}
+	if flags & FLAG_PRIORITY != 0 {
+		block = block.get(5..)?;
+	}
+
+	block.get(..block.len().checked_sub(padding)?)
+}
+
+/// The first letters of the pseudo-header names at the start of an HPACK header block,
+/// e.g. `m`, `a`, `s`, `p` for `:method`, `:authority`, `:scheme`, `:path`.
+fn pseudo_header_order(mut block: &[u8]) -> Vec<String> {
+	let mut order = Vec::new();
+
+	while let Some(&first) = block.first() {
+		let name = if first & 0x80 != 0 {
+			// Indexed header field
+			match hpack_int(&mut block, 7) {
+				Some(index) => static_pseudo_name(index),
+				None => None,
+			}
+		} else if first & 0xe0 == 0x20 {
+			// Dynamic table size update
+			if hpack_int(&mut block, 5).is_none() {
+				break;
+			}
+			continue;
+		} else {
+			// Literal header field, with or without indexing
+			let prefix = if first & 0x40 != 0 { 6 } else { 4 };
+			let name = match hpack_int(&mut block, prefix) {
+				Some(0) => hpack_plain_string(&mut block),
+				Some(index) => static_pseudo_name(index),
+				None => None,
+			};
+			if hpack_skip_string(&mut block).is_none() {
+				break;
+			}
+			name
+		};
+
+		match name.as_deref().and_then(|name| name.strip_prefix(':')) {
+			Some(pseudo) => order.push(pseudo.chars().next().unwrap_or('?').to_string()),
+			// Pseudo-headers come before all other fields
+			None => break,
+		}
+	}
+
+	order
+}
+
+/// Pseudo-header names in the HPACK static table (RFC 7541, Appendix A).
+fn static_pseudo_name(index: usize) -> Option<String> {
+	let name = match index {
+		1 => ":authority",
+		2 | 3 => ":method",
+		4 | 5 => ":path",
+		6 | 7 => ":scheme",
+		8..=14 => ":status",
+		_ => return None,
+	};
+	Some(name.to_string())
+}
+
+/// Decodes an HPACK integer with an N-bit prefix (RFC 7541, Section 5.1).
+fn hpack_int(block: &mut &[u8], prefix_bits: u32) -> Option<usize> {
+	let mask = (1usize << prefix_bits) - 1;
+	let (&first, rest) = block.split_first()?;
+	*block = rest;
+
+	let mut value = first as usize & mask;
+	if value < mask {
+		return Some(value);
+	}
+
+	let mut shift = 0;
+	loop {
+		let (&b, rest) = block.split_first()?;
+		*block = rest;
+		value = value.checked_add(((b & 0x7f) as usize).checked_shl(shift)?)?;
+		shift += 7;
+		if b & 0x80 == 0 {
+			return Some(value);
+		}
+	}
+}
+
+/// Reads a string literal, unless it is Huffman encoded, which pseudo-header
+/// names practically never are since they are in the static table.
+fn hpack_plain_string(block: &mut &[u8]) -> Option<String> {
+	let huffman = block.first()? & 0x80 != 0;
+	let len = hpack_int(block, 7)?;
+	let bytes = block.get(..len)?;
+	*block = &block[len..];
+
+	match huffman {
+		true => None,
+		false => Some(String::from_utf8_lossy(bytes).into_owned()),
+	}
+}
+
+fn hpack_skip_string(block: &mut &[u8]) -> Option<()> {
+	let len = hpack_int(block, 7)?;
+	*block = block.get(len..)?;
+	Some(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use tokio::io::AsyncReadExt;
+
+	/// The frames curl 7.88.1 with nghttp2 sends after the preface, up to its first HEADERS
+	/// frame, as captured with `--http2-prior-knowledge`
+	const CURL_FRAMES: &str = concat!(
+		"00001204000000000000030000006400040200000000020000000000000408000000000001ff000100001f01",
+		"0500000001828486418b089d5c0b8170dc0be0781f7a8825b650c3abbcf2e153032a2f2a",
+	);
+
+	/// The fingerprint shown in the README for curl
+	const CURL_AKAMAI: &str = "3:100;4:33554432;2:0|33488897|0|m,p,s,a";
+
+	fn unhex(s: &str) -> Vec<u8> {
+		(0..s.len())
+			.step_by(2)
+			.map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+			.collect()
+	}
+
+	fn frame(kind: u8, flags: u8, stream_id: u32, payload: &[u8]) -> Vec<u8> {
+		let mut frame = (payload.len() as u32).to_be_bytes()[1..].to_vec();
+		frame.push(kind);
+		frame.push(flags);
+		frame.extend_from_slice(&stream_id.to_be_bytes());
+		frame.extend_from_slice(payload);
+		frame
+	}
+
+	fn connection(frames: &[Vec<u8>]) -> Vec<u8> {
+		let mut buf = PREFACE.to_vec();
+		for frame in frames {
+			buf.extend_from_slice(frame);
+		}
+		buf
+	}
+
+	fn fingerprint(buf: &[u8]) -> Option<String> {
+		match parse(buf) {
+			Parse::Done(fingerprint) => Some(fingerprint),
+			_ => None,
+		}
+	}
+
+	/// A HEADERS frame with the END_HEADERS flag.
+	fn headers(flags: u8, block: &[u8]) -> Vec<u8> {
+		frame(FRAME_HEADERS, 0x04 | flags, 1, block)
+	}
+
+	fn settings(settings: &[(u16, u32)]) -> Vec<u8> {
+		let mut payload = Vec::new();
+		for (id, value) in settings {
+			payload.extend_from_slice(&id.to_be_bytes());
+			payload.extend_from_slice(&value.to_be_bytes());
+		}
+		frame(FRAME_SETTINGS, 0, 0, &payload)
+	}
+
+	#[test]
+	fn curl() {
+		let mut buf = PREFACE.to_vec();
+		buf.extend_from_slice(&unhex(CURL_FRAMES));
+		assert_eq!(fingerprint(&buf).as_deref(), Some(CURL_AKAMAI));
+
+		// Nothing is known before the HEADERS frame is complete
+		for len in 0..buf.len() {
+			assert!(matches!(parse(&buf[..len]), Parse::Incomplete), "{}", len);
+		}
+	}
+
+	#[tokio::test]
+	async fn recorder() {
+		let mut input = PREFACE.to_vec();
+		input.extend_from_slice(&unhex(CURL_FRAMES));
+		input.extend_from_slice(&frame(FRAME_SETTINGS, FLAG_ACK, 0, &[]));
+
+		let cell = H2Fingerprint::default();
+		let mut stream = H2Recorder::new(&input[..], Some(cell.clone()));
+		let mut read = Vec::new();
+		stream.read_to_end(&mut read).await.unwrap();
+
+		assert_eq!(read, input);
+		assert_eq!(cell.get().map(String::as_str), Some(CURL_AKAMAI));
+	}
+
+	#[test]
+	fn priorities_and_padding() {
+		let mut priority = 0x8000_0000u32.to_be_bytes().to_vec();
+		priority.push(200);
+
+		// Priority fields, :method GET, a literal :authority, :scheme https, :path /
+		let mut block = vec![4];
+		block.extend_from_slice(&priority);
+		block.extend_from_slice(&[0x82, 0x41, 3, b'a', b'.', b'b', 0x87, 0x84]);
+		block.extend_from_slice(&[0; 4]);
+
+		let buf = connection(&[
+			settings(&[(1, 65536), (2, 0), (4, 6291456), (6, 262144)]),
+			frame(FRAME_SETTINGS, FLAG_ACK, 0, &[]),
+			frame(FRAME_WINDOW_UPDATE, 0, 0, &15663105u32.to_be_bytes()),
+			frame(FRAME_PRIORITY, 0, 3, &[0, 0, 0, 0, 200]),
+			frame(FRAME_PRIORITY, 0, 5, &[0x80, 0, 0, 3, 100]),
+			headers(FLAG_PADDED | FLAG_PRIORITY, &block),
+		]);
+		assert_eq!(
+			fingerprint(&buf).as_deref(),
+			Some("1:65536;2:0;4:6291456;6:262144|15663105|3:0:0:201,5:1:3:101|m,a,s,p")
+		);
+	}
+
+	#[test]
+	fn missing_frames() {
+		let buf = connection(&[settings(&[]), headers(0, &[0x82, 0x84, 0x87, 0x41, 0])]);
+		assert_eq!(fingerprint(&buf).as_deref(), Some("|00|0|m,p,s,a"));
+	}
+
+	#[test]
+	fn pseudo_headers() {
+		// Literal names, a dynamic table size update, and regular fields ending the list
+		let mut block = vec![0x20, 0x00, 7];
+		block.extend_from_slice(b":method");
+		block.extend_from_slice(&[3, b'G', b'E', b'T', 0x10, 5]);
+		block.extend_from_slice(b":path");
+		block.extend_from_slice(&[1, b'/', 0x7a, 0x01, b'x', 0x84]);
+		assert_eq!(pseudo_header_order(&block), ["m", "p"]);
+
+		// Huffman encoded names are not decoded
+		assert!(pseudo_header_order(&[0x00, 0x81, 0xff, 0x00]).is_empty());
+
+		// Cut off in the middle
+		assert_eq!(pseudo_header_order(&[0x82, 0x41, 10, b'a']), ["m"]);
+	}
+
+	#[test]
+	fn hpack_integers() {
+		// The examples of RFC 7541, Appendix C.1
+		assert_eq!(hpack_int(&mut &[0x0a][..], 5), Some(10));
+		assert_eq!(hpack_int(&mut &[0x1f, 0x9a, 0x0a][..], 5), Some(1337));
+		assert_eq!(hpack_int(&mut &[0x2a][..], 8), Some(42));
+
+		assert_eq!(hpack_int(&mut &[0x1f, 0x9a][..], 5), None);
+		assert_eq!(
+			hpack_int(
+				&mut &[0x1f, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x7f][..],
+				5
+			),
+			None
+		);
+	}
+
+	#[test]
+	fn invalid() {
+		assert!(matches!(parse(b"GET / HTTP/1.1\r\n"), Parse::Invalid));
+		assert!(matches!(parse(b"PRI * HTTP/1.1"), Parse::Invalid));
+
+		// More padding than payload
+		let buf = connection(&[headers(FLAG_PADDED, &[10, 0x82])]);
+		assert!(matches!(parse(&buf), Parse::Invalid));
+		let buf = connection(&[headers(FLAG_PRIORITY, &[0, 0, 0])]);
+		assert!(matches!(parse(&buf), Parse::Invalid));
+	}
+}
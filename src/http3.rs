@@ -0,0 +1,166 @@
+//! HTTP/3 over QUIC, serving the same endpoints as the TLS listeners.
+
+use crate::listener::{ListenerSpec, ServerState, HANDSHAKE_TIMEOUT};
+use crate::metrics::{Labels, OpenConnection};
+use crate::service::{self, ConnInfo};
+use crate::tls::TlsInfo;
+use h3::server::RequestStream;
+use hyper::body::Bytes;
+use hyper::{Body, Method, Request};
+use quinn::crypto::rustls::HandshakeData;
+use std::sync::Arc;
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+use tokio::{select, time};
+
+pub const ALPN: &[u8] = b"h3";
+const H3_NO_ERROR: quinn::VarInt = quinn::VarInt::from_u32(0x100);
+
+/// Accepts QUIC connections until the sender of `spec_rx` is dropped, then waits for
+/// open connections to finish.
+pub async fn serve(
+	endpoint: quinn::Endpoint,
+	mut spec_rx: watch::Receiver<Arc<ListenerSpec>>,
+	state: Arc<ServerState>,
+) {
+	let (drain_tx, drain_rx) = watch::channel(false);
+
+	loop {
+		let connecting = select! {
+			res = endpoint.accept() => match res {
+				Some(connecting) => connecting,
+				None => break,
+			},
+			res = spec_rx.changed() => match res {
+				Ok(()) => continue,
+				Err(_) => break,
+			},
+		};
+
+		tokio::spawn(handle_connection(
+			connecting,
+			spec_rx.borrow().clone(),
+			state.clone(),
+			drain_rx.clone(),
+		));
+	}
+
+	drop(drain_rx);
+
+	let _ = drain_tx.send(true);
+	drain_tx.closed().await;
+	endpoint.wait_idle().await;
+}
+
+async fn handle_connection(
+	connecting: quinn::Connecting,
+	spec: Arc<ListenerSpec>,
+	state: Arc<ServerState>,
+	mut drain_rx: watch::Receiver<bool>,
+) {
+	// The connections are told apart from those on the TCP port by the version
+	let labels = Labels {
+		version: "h3",
+		..Labels::new(&spec.addr.to_string().into(), connecting.remote_address())
+	};
+
+	let conn = match time::timeout(HANDSHAKE_TIMEOUT, connecting).await {
+		Ok(Ok(conn)) => conn,
+		res => {
+			if let Ok(Err(e)) = res {
+				debug!("QUIC handshake failed: {}", e);
+			}
+			state.metrics.tls_handshake_failures.inc(&labels);
+			return;
+		}
+	};
+
+	let _open = OpenConnection::new(&state.metrics, labels.clone());
+
+	let settings = state.settings();
+	let tls = conn
+		.handshake_data()
+		.and_then(|data| data.downcast::<HandshakeData>().ok())
+		.map(|data| TlsInfo::quic(&data));
+
+	let mut h3_conn =
+		match h3::server::Connection::new(h3_quinn::Connection::new(conn.clone())).await {
+			Ok(h3_conn) => h3_conn,
+			Err(e) => {
+				debug!(
+					"Error setting up HTTP/3 connection from {}: {}",
+					conn.remote_address(),
+					e
+				);
+				return;
+			}
+		};
+
+	let mut requests = JoinSet::new();
+
+	loop {
+		let accepted = select! {
+			res = h3_conn.accept() => res,
+			Some(_) = requests.join_next() => continue,
+			_ = drain_rx.changed() => {
+				// Refuse new requests and finish the ones that were already started
+				if let Err(e) = h3_conn.shutdown(0).await {
+					debug!("Error shutting down HTTP/3 connection: {}", e);
+				}
+				break;
+			}
+		};
+
+		let (req, stream) = match accepted {
+			Ok(Some(accepted)) => accepted,
+			Ok(None) => break,
+			Err(e) => {
+				debug!("Error serving HTTP/3 connection: {}", e);
+				break;
+			}
+		};
+
+		// The address is looked up for every request, so it follows connection migration
+		let conn_info = ConnInfo {
+			remote_addr: conn.remote_address(),
+			listener: spec.clone(),
+			tls: tls.clone(),
+			fingerprint: None,
+			h2_fingerprint: None,
+			settings: settings.clone(),
+			labels: labels.clone(),
+		};
+
+		let state = state.clone();
+		requests.spawn(async move {
+			if let Err(e) = handle_request(&state, &conn_info, req, stream).await {
+				debug!("Error serving HTTP/3 request: {}", e);
+			}
+		});
+	}
+
+	while requests.join_next().await.is_some() {}
+	conn.close(H3_NO_ERROR, b"");
+}
+
+async fn handle_request(
+	state: &ServerState,
+	conn_info: &ConnInfo,
+	req: Request<()>,
+	mut stream: RequestStream<h3_quinn::BidiStream<Bytes>, Bytes>,
+) -> Result<(), h3::Error> {
+	let head = req.method() == Method::HEAD;
+
+	let (parts, body) = service::handle(state, conn_info, req.map(|()| Body::empty())).into_parts();
+	stream
+		.send_response(hyper::Response::from_parts(parts, ()))
+		.await?;
+
+	// The bodies are generated in memory, so this never fails
+	let body = hyper::body::to_bytes(body).await.unwrap_or_default();
+	if !head && !body.is_empty() {
+		stream.send_data(body).await?;
+	}
+
+	stream.finish().await
+}
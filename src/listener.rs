@@ -0,0 +1,660 @@
+use crate::access_log::AccessLog;
+use crate::acme::Http01Tokens;
+use crate::config::ListenerConfig;
+use crate::fingerprint::{self, Fingerprint};
+use crate::h2_fingerprint::H2Recorder;
+use crate::metrics::{Labels, Metrics, OpenConnection};
+use crate::proxy_protocol::{self, ProxyHeader};
+use crate::rate_limit::{self, Limit, RateLimiter};
+use crate::router::Router;
+use crate::service::{self, ConnInfo, ResponseFormat};
+use crate::tls::TlsInfo;
+use anyhow::Result;
+use hyper::header::{HeaderValue, ALT_SVC};
+use hyper::server::conn::Http;
+use hyper::service::service_fn;
+use ipnet::IpNet;
+use nix::unistd::{Group, User};
+use std::convert::Infallible;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, RwLock};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use std::{fmt, fs, io};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+use tokio::sync::watch;
+use tokio::{select, time};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+
+/// Time a client gets to send the PROXY protocol header and complete the TLS handshake.
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ListenerKind {
+	/// HTTPS, the main listener type
+	Tls,
+	/// Cleartext HTTP/1.1 and h2c
+	Http,
+	/// Cleartext HTTP that only answers ACME HTTP-01 challenges
+	AcmeHttp01,
+	/// Cleartext HTTP serving `/metrics` and the other admin endpoints
+	Admin,
+}
+
+/// Peers on Unix sockets are local processes, so they are shown as the loopback address.
+pub const UNIX_PEER_ADDR: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0));
+
+/// Address of a listener, either TCP, a Unix socket path, or the name of
+/// sockets passed by systemd.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ListenAddr {
+	Tcp(SocketAddr),
+	Unix(PathBuf),
+	Systemd(String),
+}
+
+impl ListenAddr {
+	pub fn tcp(&self) -> Option<SocketAddr> {
+		match self {
+			ListenAddr::Tcp(addr) => Some(*addr),
+			_ => None,
+		}
+	}
+
+	pub fn is_unix(&self) -> bool {
+		matches!(self, ListenAddr::Unix(_))
+	}
+}
+
+impl fmt::Display for ListenAddr {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			ListenAddr::Tcp(addr) => write!(f, "{}", addr),
+			ListenAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+			ListenAddr::Systemd(name) => write!(f, "systemd:{}", name),
+		}
+	}
+}
+
+/// Permissions and ownership of a Unix socket file, left as created if not set.
+#[derive(Clone, Debug, Default)]
+pub struct UnixSocketOptions {
+	pub mode: Option<u32>,
+	/// User name or ID
+	pub owner: Option<String>,
+	/// Group name or ID
+	pub group: Option<String>,
+}
+
+/// A listener as configured on the command line.
+#[derive(Clone, Debug)]
+pub struct ListenerSpec {
+	pub addr: ListenAddr,
+	pub kind: ListenerKind,
+	/// Expect a PROXY protocol header from trusted peers
+	pub proxy_protocol: bool,
+	/// `Alt-Svc` header advertising HTTP/3 on the same address, if enabled
+	pub alt_svc: Option<HeaderValue>,
+	pub unix_socket: UnixSocketOptions,
+	/// Serve HTTP/2 only, in addition to the global setting
+	pub http2_only: bool,
+	/// Format of responses to requests that do not ask for one
+	pub format: ResponseFormat,
+	/// Requests per second of each client, instead of the global limit
+	pub rate_limit: Option<f64>,
+	pub rate_limit_burst: Option<u32>,
+}
+
+impl ListenerSpec {
+	fn new(addr: ListenAddr, kind: ListenerKind) -> Self {
+		ListenerSpec {
+			addr,
+			kind,
+			proxy_protocol: false,
+			alt_svc: None,
+			unix_socket: UnixSocketOptions::default(),
+			http2_only: false,
+			format: ResponseFormat::Text,
+			rate_limit: None,
+			rate_limit_burst: None,
+		}
+	}
+
+	/// Parses an address with optional port or a `unix:` path, followed by
+	/// comma-separated options, e.g. `[::]:443,proxy-protocol,format=json` or
+	/// `unix:/run/wut.sock,mode=660,group=www-data`.
+	pub fn parse(bind: &str, default_port: u16, kind: ListenerKind) -> Result<Self> {
+		let mut parts = bind.split(',');
+		let addr = crate::parse_addr(parts.next().unwrap_or_default(), default_port)?;
+		let mut spec = ListenerSpec::new(addr, kind);
+
+		for option in parts {
+			match option.split_once('=') {
+				Some((name, value)) => spec.set_option(name, Some(value), bind)?,
+				None => spec.set_option(option, None, bind)?,
+			}
+		}
+
+		spec.check_rate_limit(bind)?;
+		Ok(spec)
+	}
+
+	/// Creates a listener from a `[[listener]]` table of the config file.
+	pub fn from_config(config: &ListenerConfig) -> Result<Self> {
+		let (kind, default_port) = match config.tls {
+			true => (ListenerKind::Tls, crate::DEFAULT_PORT),
+			false => (ListenerKind::Http, crate::DEFAULT_HTTP_PORT),
+		};
+
+		let mut spec = ListenerSpec::new(crate::parse_addr(&config.bind, default_port)?, kind);
+		spec.proxy_protocol = config.proxy_protocol;
+		spec.http2_only = config.http2_only;
+		spec.format = config.format.unwrap_or(ResponseFormat::Text);
+		spec.rate_limit = config.rate_limit;
+		spec.rate_limit_burst = config.rate_limit_burst;
+
+		for (name, value) in [
+			("mode", &config.mode),
+			("owner", &config.owner),
+			("group", &config.group),
+		] {
+			if let Some(value) = value {
+				spec.set_option(name, Some(value), &config.bind)?;
+			}
+		}
+
+		spec.check_rate_limit(&config.bind)?;
+		Ok(spec)
+	}
+
+	fn check_rate_limit(&self, bind: &str) -> Result<()> {
+		if let Some(rate) = self.rate_limit {
+			rate_limit::check_rate(rate)?;
+		}
+		rate_limit::check_burst(self.rate_limit_burst)?;
+
+		if self.rate_limit_burst.is_some() && self.rate_limit.is_none() {
+			anyhow::bail!(
+				"option rate-limit-burst requires rate-limit for listener {}",
+				bind
+			);
+		}
+		Ok(())
+	}
+
+	fn set_option(&mut self, name: &str, value: Option<&str>, bind: &str) -> Result<()> {
+		match (name, value) {
+			("proxy-protocol", None) => self.proxy_protocol = true,
+			("http2-only", None) => self.http2_only = true,
+			("format", Some("text")) => self.format = ResponseFormat::Text,
+			("format", Some("json")) => self.format = ResponseFormat::Json,
+			("mode" | "owner" | "group", Some(_)) if !self.addr.is_unix() => {
+				anyhow::bail!(
+					"option {} is only supported for unix: listeners, got {}",
+					name,
+					bind
+				)
+			}
+			("mode", Some(mode)) => match u32::from_str_radix(mode, 8) {
+				Ok(mode) if mode <= 0o7777 => self.unix_socket.mode = Some(mode),
+				_ => anyhow::bail!("invalid octal mode {} for listener {}", mode, bind),
+			},
+			("rate-limit", Some(rate)) => self.rate_limit = Some(rate_limit::parse_rate(rate)?),
+			("rate-limit-burst", Some(burst)) => match burst.parse() {
+				Ok(burst) => self.rate_limit_burst = Some(burst),
+				Err(_) => anyhow::bail!("invalid rate-limit-burst {} for listener {}", burst, bind),
+			},
+			("owner", Some(owner)) => self.unix_socket.owner = Some(owner.to_string()),
+			("group", Some(group)) => self.unix_socket.group = Some(group.to_string()),
+			(name, Some(value)) => {
+				anyhow::bail!("unknown option {}={} for listener {}", name, value, bind)
+			}
+			(name, None) => anyhow::bail!("unknown option {} for listener {}", name, bind),
+		}
+
+		Ok(())
+	}
+
+	/// Binds the listener, replacing a stale Unix socket file from a previous run.
+	pub async fn bind(&self) -> Result<Listener> {
+		let path = match &self.addr {
+			ListenAddr::Tcp(addr) => return Ok(Listener::Tcp(TcpListener::bind(addr).await?)),
+			ListenAddr::Unix(path) => path,
+			ListenAddr::Systemd(name) => anyhow::bail!("socket {} must be passed by systemd", name),
+		};
+
+		match fs::symlink_metadata(path) {
+			Ok(metadata) if metadata.file_type().is_socket() => fs::remove_file(path)?,
+			Ok(_) => anyhow::bail!("{} exists and is not a socket", path.display()),
+			Err(_) => {}
+		}
+
+		let listener = UnixListener::bind(path)?;
+		let options = &self.unix_socket;
+
+		if let Some(mode) = options.mode {
+			fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+		}
+
+		if options.owner.is_some() || options.group.is_some() {
+			let uid = options.owner.as_deref().map(lookup_user).transpose()?;
+			let gid = options.group.as_deref().map(lookup_group).transpose()?;
+			std::os::unix::fs::chown(path, uid, gid).map_err(|e| {
+				anyhow::Error::msg(format!(
+					"failed to change owner of {}: {}",
+					path.display(),
+					e
+				))
+			})?;
+		}
+
+		Ok(Listener::Unix(listener))
+	}
+
+	pub fn scheme(&self) -> &'static str {
+		match self.kind {
+			ListenerKind::Tls => "https",
+			ListenerKind::Http | ListenerKind::AcmeHttp01 | ListenerKind::Admin => "http",
+		}
+	}
+}
+
+fn lookup_user(user: &str) -> Result<u32> {
+	if let Ok(uid) = user.parse() {
+		return Ok(uid);
+	}
+	match User::from_name(user)? {
+		Some(user) => Ok(user.uid.as_raw()),
+		None => anyhow::bail!("unknown user {}", user),
+	}
+}
+
+fn lookup_group(group: &str) -> Result<u32> {
+	if let Ok(gid) = group.parse() {
+		return Ok(gid);
+	}
+	match Group::from_name(group)? {
+		Some(group) => Ok(group.gid.as_raw()),
+		None => anyhow::bail!("unknown group {}", group),
+	}
+}
+
+/// A bound TCP or Unix socket listener.
+pub enum Listener {
+	Tcp(TcpListener),
+	Unix(UnixListener),
+}
+
+enum Accepted {
+	Tcp(TcpStream, SocketAddr),
+	Unix(UnixStream),
+}
+
+impl Listener {
+	async fn accept(&self) -> io::Result<Accepted> {
+		match self {
+			Listener::Tcp(listener) => {
+				let (stream, peer_addr) = listener.accept().await?;
+				Ok(Accepted::Tcp(stream, peer_addr))
+			}
+			Listener::Unix(listener) => {
+				let (stream, _) = listener.accept().await?;
+				Ok(Accepted::Unix(stream))
+			}
+		}
+	}
+}
+
+/// Settings that can change on a configuration reload. Connections keep the
+/// ones that were current when they were accepted.
+#[derive(Default)]
+pub struct Settings {
+	pub http2_only: bool,
+	/// Record the HTTP/2 connection preface of each connection for `/h2-fingerprint`
+	pub h2_fingerprint: bool,
+	/// Peers that are allowed to send a PROXY protocol header
+	pub proxy_trusted: Vec<IpNet>,
+	/// Reverse proxies whose forwarding headers are used for the client address
+	pub trusted_proxies: Vec<IpNet>,
+	/// Port of the HTTPS listener that plain HTTP requests are redirected to, if enabled
+	pub http_redirect_port: Option<u16>,
+	/// Requests per second of each client on the listeners without a limit of their own
+	pub rate_limit: Limit,
+	/// The options in effect as JSON, served on `/config` of the admin listener
+	pub effective_config: serde_json::Value,
+}
+
+/// State shared by all listeners.
+pub struct ServerState {
+	pub tls_acceptor: TlsAcceptor,
+	pub settings: RwLock<Arc<Settings>>,
+	pub metrics: Metrics,
+	/// Cleared when the server starts to shut down, for `/readyz` of the admin listener
+	pub ready: AtomicBool,
+	pub access_log: Option<AccessLog>,
+	pub rate_limiter: RateLimiter,
+	pub http01_tokens: Arc<Http01Tokens>,
+	pub router: Router,
+}
+
+impl ServerState {
+	pub fn settings(&self) -> Arc<Settings> {
+		self.settings.read().unwrap().clone()
+	}
+}
+
+/// Accepts connections until the sender of `spec_rx` is dropped, then waits for open
+/// connections to finish. Updated specs apply to connections accepted afterwards.
+pub async fn serve(
+	listener: Listener,
+	mut spec_rx: watch::Receiver<Arc<ListenerSpec>>,
+	state: Arc<ServerState>,
+) {
+	let (drain_tx, drain_rx) = watch::channel(false);
+
+	loop {
+		let spec = spec_rx.borrow().clone();
+
+		let accepted = select! {
+			res = listener.accept() => match res {
+				Ok(conn) => conn,
+				Err(e) => {
+					if !is_connection_error(&e) {
+						// Most likely out of file descriptors, back off for a bit
+						error!(
+							listener:% = spec.addr;
+							"Failed to accept connection on {}: {}",
+							spec.addr,
+							e
+						);
+						time::sleep(Duration::from_secs(1)).await;
+					}
+					continue;
+				}
+			},
+			res = spec_rx.changed() => match res {
+				Ok(()) => continue,
+				Err(_) => break,
+			},
+		};
+
+		match accepted {
+			Accepted::Tcp(stream, peer_addr) => tokio::spawn(handle_connection(
+				stream,
+				peer_addr,
+				spec,
+				state.clone(),
+				drain_rx.clone(),
+			)),
+			Accepted::Unix(stream) => tokio::spawn(handle_connection(
+				stream,
+				UNIX_PEER_ADDR,
+				spec,
+				state.clone(),
+				drain_rx.clone(),
+			)),
+		};
+	}
+
+	drop(listener);
+	drop(drain_rx);
+
+	let _ = drain_tx.send(true);
+	drain_tx.closed().await;
+}
+
+fn is_connection_error(e: &io::Error) -> bool {
+	matches!(
+		e.kind(),
+		io::ErrorKind::ConnectionRefused
+			| io::ErrorKind::ConnectionAborted
+			| io::ErrorKind::ConnectionReset
+	)
+}
+
+async fn handle_connection<S>(
+	stream: S,
+	peer_addr: SocketAddr,
+	spec: Arc<ListenerSpec>,
+	state: Arc<ServerState>,
+	drain_rx: watch::Receiver<bool>,
+) where
+	S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+	let settings = state.settings();
+	let setup = time::timeout(
+		HANDSHAKE_TIMEOUT,
+		read_proxy_header(stream, peer_addr, &spec, &settings),
+	);
+
+	let (stream, remote_addr) = match setup.await {
+		Ok(Ok(res)) => res,
+		Ok(Err(e)) => {
+			debug!("Closing connection from {}: {}", peer_addr, e);
+			return;
+		}
+		Err(_) => return,
+	};
+
+	let mut labels = Labels::new(&spec.addr.to_string().into(), remote_addr);
+	if spec.addr.is_unix() && remote_addr == UNIX_PEER_ADDR {
+		labels.family = "unix";
+	}
+	// Connections to the admin listeners are not counted
+	let _open = (spec.kind != ListenerKind::Admin)
+		.then(|| OpenConnection::new(&state.metrics, labels.clone()));
+
+	let mut conn_info = ConnInfo {
+		remote_addr,
+		listener: spec.clone(),
+		tls: None,
+		fingerprint: None,
+		h2_fingerprint: settings.h2_fingerprint.then(Default::default),
+		settings,
+		labels,
+	};
+
+	match spec.kind {
+		ListenerKind::Tls => {
+			let accept = time::timeout(HANDSHAKE_TIMEOUT, accept_tls(stream, &state));
+			let (stream, fingerprint) = match accept.await {
+				Ok(Ok(res)) => res,
+				res => {
+					if let Ok(Err(e)) = res {
+						debug!("TLS handshake with {} failed: {}", peer_addr, e);
+					}
+					state.metrics.tls_handshake_failures.inc(&conn_info.labels);
+					return;
+				}
+			};
+
+			conn_info.tls = Some(TlsInfo::new(stream.get_ref().1));
+			conn_info.fingerprint = fingerprint;
+
+			serve_http(stream, Arc::new(conn_info), state.clone(), drain_rx).await
+		}
+		ListenerKind::Http | ListenerKind::AcmeHttp01 | ListenerKind::Admin => {
+			serve_http(stream, Arc::new(conn_info), state.clone(), drain_rx).await
+		}
+	}
+}
+
+/// Completes the TLS handshake after fingerprinting the ClientHello, which is
+/// replayed to rustls afterwards.
+async fn accept_tls<S: AsyncRead + AsyncWrite + Unpin>(
+	mut stream: Rewind<S>,
+	state: &ServerState,
+) -> io::Result<(TlsStream<Rewind<S>>, Option<Fingerprint>)> {
+	let (client_hello, read) = fingerprint::read_client_hello(&mut stream).await?;
+	stream.unread(read);
+
+	let fingerprint = client_hello.and_then(|hello| Fingerprint::new(&hello));
+	let stream = state.tls_acceptor.accept(stream).await?;
+
+	Ok((stream, fingerprint))
+}
+
+/// Reads the PROXY protocol header if the listener expects one, and returns the stream
+/// together with the client address that should be echoed.
+async fn read_proxy_header<S: AsyncRead + Unpin>(
+	mut stream: S,
+	peer_addr: SocketAddr,
+	spec: &ListenerSpec,
+	settings: &Settings,
+) -> io::Result<(Rewind<S>, SocketAddr)> {
+	if !spec.proxy_protocol {
+		return Ok((Rewind::new(stream, Vec::new()), peer_addr));
+	}
+
+	// Only local processes can connect to Unix sockets
+	let trusted = spec.addr.is_unix()
+		|| settings
+			.proxy_trusted
+			.iter()
+			.any(|net| net.contains(&peer_addr.ip()));
+
+	// Untrusted peers may not send a header, so there is no need to buffer one
+	if !trusted {
+		let (found, prefix) = proxy_protocol::read_signature(&mut stream).await?;
+		if found {
+			return Err(crate::error(
+				"PROXY protocol header from untrusted peer".into(),
+			));
+		}
+		return Ok((Rewind::new(stream, prefix), peer_addr));
+	}
+
+	let (header, prefix) = proxy_protocol::read_header(&mut stream).await?;
+	let stream = Rewind::new(stream, prefix);
+
+	let header = match header {
+		None => return Ok((stream, peer_addr)),
+		Some(header) => header,
+	};
+
+	match header {
+		ProxyHeader::Proxied(addr) => Ok((stream, addr)),
+		ProxyHeader::Local => Ok((stream, peer_addr)),
+	}
+}
+
+async fn serve_http<S>(
+	stream: S,
+	conn_info: Arc<ConnInfo>,
+	state: Arc<ServerState>,
+	mut drain_rx: watch::Receiver<bool>,
+) where
+	S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+	let stream = H2Recorder::new(stream, conn_info.h2_fingerprint.clone());
+
+	let http2_only = conn_info.settings.http2_only || conn_info.listener.http2_only;
+	let service_state = state.clone();
+	let service = service_fn(move |req| {
+		let mut response = service::handle(&service_state, &conn_info, req);
+		if let Some(alt_svc) = &conn_info.listener.alt_svc {
+			response.headers_mut().insert(ALT_SVC, alt_svc.clone());
+		}
+		async { Ok::<_, Infallible>(response) }
+	});
+
+	let conn = Http::new()
+		.http2_only(http2_only)
+		.serve_connection(stream, service);
+	tokio::pin!(conn);
+
+	let res = select! {
+		res = conn.as_mut() => res,
+		_ = drain_rx.changed() => {
+			conn.as_mut().graceful_shutdown();
+			conn.await
+		}
+	};
+
+	if let Err(e) = res {
+		debug!("Error serving connection: {}", e);
+	}
+}
+
+/// A stream that first yields the bytes that were already read from it.
+pub struct Rewind<S> {
+	prefix: Vec<u8>,
+	pos: usize,
+	inner: S,
+}
+
+impl<S> Rewind<S> {
+	pub fn new(inner: S, prefix: Vec<u8>) -> Self {
+		Rewind {
+			prefix,
+			pos: 0,
+			inner,
+		}
+	}
+}
+
+impl<S> Rewind<S> {
+	/// Puts bytes that were read back in front of the stream.
+	pub fn unread(&mut self, mut bytes: Vec<u8>) {
+		bytes.extend_from_slice(&self.prefix[self.pos..]);
+		self.prefix = bytes;
+		self.pos = 0;
+	}
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Rewind<S> {
+	fn poll_read(
+		mut self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &mut ReadBuf<'_>,
+	) -> Poll<io::Result<()>> {
+		if self.pos < self.prefix.len() {
+			let len = buf.remaining().min(self.prefix.len() - self.pos);
+			buf.put_slice(&self.prefix[self.pos..self.pos + len]);
+			self.pos += len;
+			if self.pos == self.prefix.len() {
+				self.prefix = Vec::new();
+				self.pos = 0;
+			}
+			return Poll::Ready(Ok(()));
+		}
+
+		Pin::new(&mut self.inner).poll_read(cx, buf)
+	}
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Rewind<S> {
+	fn poll_write(
+		mut self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &[u8],
+	) -> Poll<io::Result<usize>> {
+		Pin::new(&mut self.inner).poll_write(cx, buf)
+	}
+
+	fn poll_write_vectored(
+		mut self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		bufs: &[io::IoSlice<'_>],
+	) -> Poll<io::Result<usize>> {
+		Pin::new(&mut self.inner).poll_write_vectored(cx, bufs)
+	}
+
+	fn is_write_vectored(&self) -> bool {
+		self.inner.is_write_vectored()
+	}
+
+	fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		Pin::new(&mut self.inner).poll_flush(cx)
+	}
+
+	fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		Pin::new(&mut self.inner).poll_shutdown(cx)
+	}
+}
@@ -0,0 +1,62 @@
+//! The application log, in the human readable format of env_logger or as JSON lines.
+
+use log::kv::{self, Key, Value, VisitSource};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Number};
+use std::io::Write;
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LogFormat {
+	/// Free text, as printed by env_logger
+	Human,
+	/// One object per line with the timestamp, level, target, message and the fields of the message
+	Json,
+}
+
+/// Sets up the logger, with the level filter taken from `RUST_LOG`.
+pub fn init(format: LogFormat) {
+	let mut builder = env_logger::Builder::from_default_env();
+
+	if format == LogFormat::Json {
+		builder.format(|buf, record| {
+			let mut object = Map::new();
+			object.insert(
+				"timestamp".into(),
+				buf.timestamp_millis().to_string().into(),
+			);
+			object.insert("level".into(), record.level().as_str().into());
+			object.insert("target".into(), record.target().into());
+			object.insert("message".into(), record.args().to_string().trim().into());
+
+			let _ = record.key_values().visit(&mut Fields(&mut object));
+
+			serde_json::to_writer(&mut *buf, &object)?;
+			writeln!(buf)
+		});
+	}
+
+	builder.init();
+}
+
+/// Adds the key-value pairs of a record to its JSON object.
+struct Fields<'a>(&'a mut Map<String, serde_json::Value>);
+
+impl<'kvs> VisitSource<'kvs> for Fields<'_> {
+	fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+		let value = if let Some(value) = value.to_bool() {
+			value.into()
+		} else if let Some(value) = value.to_u64() {
+			value.into()
+		} else if let Some(value) = value.to_i64() {
+			value.into()
+		} else if let Some(number) = value.to_f64().and_then(Number::from_f64) {
+			number.into()
+		} else {
+			value.to_string().into()
+		};
+
+		self.0.insert(key.to_string(), value);
+		Ok(())
+	}
+}
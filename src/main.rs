@@ -1,144 +1,565 @@
 #[macro_use]
 extern crate log;
 
+mod access_log;
+mod acme;
+mod admin;
+mod config;
+mod date;
+mod dns;
+mod fingerprint;
+mod forwarded;
+mod h2_fingerprint;
+mod http3;
+mod listener;
+mod logging;
+mod metrics;
+mod proxy_protocol;
+mod rate_limit;
+mod raw;
+mod reload;
+mod router;
+mod service;
+mod stun;
+mod systemd;
+mod tls;
+mod upgrade;
+
+use access_log::{AccessLog, AccessLogFormat};
+use acme::{AcmeChallenge, AcmeConfig, Http01Tokens};
 use anyhow::Result;
-use clap::{arg, Parser};
-use hyper::server::conn::AddrIncoming;
-use hyper::server::Builder;
-use hyper::service::{make_service_fn, service_fn};
-use hyper::{Body, Request, Response, Server};
-use hyper_rustls::acceptor::TlsStream;
-use hyper_rustls::TlsAcceptor;
-use std::convert::Infallible;
-use std::io::{Seek, SeekFrom};
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser};
+use config::{FileConfig, ListenerConfig};
+use ipnet::IpNet;
+use listener::{ListenAddr, ListenerKind, ListenerSpec, ServerState, Settings};
+use logging::LogFormat;
+use metrics::Metrics;
+use rate_limit::{Limit, RateLimiter};
+use reload::Running;
+use router::Router;
+use serde::Serialize;
+use std::collections::HashMap;
 use std::net::SocketAddr;
-use std::sync::atomic::{AtomicU64, Ordering};
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
 use std::time::Duration;
 use std::vec::Vec;
-use std::{env, fs, io};
-use tokio::signal::unix::{signal, SignalKind};
-use tokio::task::JoinHandle;
+use std::{env, io};
+use tls::{CertPair, CertResolver, CertSources};
+use tokio::signal::unix::{signal, Signal, SignalKind};
 use tokio::time::Instant;
 use tokio::{select, time};
+use upgrade::Sockets;
 
 const DEFAULT_PORT: u16 = 11313;
+const DEFAULT_HTTP_PORT: u16 = 80;
+const DEFAULT_STUN_PORT: u16 = 3478;
+const DEFAULT_DNS_PORT: u16 = 53;
+const DEFAULT_ADMIN_PORT: u16 = 11314;
 
 /// A HTTPS server that echoes the client's IP-address
-#[derive(Parser, Debug)]
+#[derive(Parser, Debug, Serialize)]
 #[command(author, version, about, long_about = None)]
+#[serde(rename_all = "kebab-case")]
 struct Args {
-	/// Address to bind to, with optional port (can be provided multiple times)
+	/// TOML file with the same options as the command line, which takes precedence, and [[listener]] tables
+	#[arg(long = "config")]
+	config: Option<String>,
+
+	/// Validate the configuration and certificates, and exit
+	#[arg(long = "check-config", default_value_t = false)]
+	#[serde(skip)]
+	check_config: bool,
+
+	/// Address to bind to, with optional port, as unix:/path or as systemd:name, and options like proxy-protocol after a comma (can be provided multiple times)
 	#[arg(short, long, default_values = vec!["127.0.0.1:11313", "[::1]:11313"])]
 	bind: Vec<String>,
 
-	/// Certificate file path
-	#[arg(short, long, required = true)]
-	cert_path: String,
+	/// Address to bind the plain HTTP listener to, with optional port, as unix:/path or as systemd:name (can be provided multiple times)
+	#[arg(long = "bind-http")]
+	bind_http: Vec<String>,
+
+	/// Address to bind the admin listener serving /metrics, /healthz, /readyz, /stats and /config to, with optional port, as unix:/path or as systemd:name (can be provided multiple times)
+	#[arg(long = "admin-bind", default_values = vec!["127.0.0.1:11314"])]
+	admin_bind: Vec<String>,
+
+	/// Address to bind the STUN server to, with optional port (can be provided multiple times)
+	#[arg(long = "bind-stun")]
+	bind_stun: Vec<String>,
+
+	/// Address to bind the DNS responder to over UDP and TCP, with optional port (can be provided multiple times)
+	#[arg(long = "bind-dns")]
+	bind_dns: Vec<String>,
+
+	/// Name the DNS responder answers with the address of the querying resolver, e.g. whoami.example.com
+	#[arg(long = "dns-zone")]
+	dns_zone: Option<String>,
+
+	/// Address to bind a raw TCP listener to, which writes the client address and closes the connection (can be provided multiple times)
+	#[arg(long = "bind-raw-tcp")]
+	bind_raw_tcp: Vec<String>,
+
+	/// Address to bind a raw UDP socket to, which replies to every datagram with the client address (can be provided multiple times)
+	#[arg(long = "bind-raw-udp")]
+	bind_raw_udp: Vec<String>,
+
+	/// Redirect requests on the plain HTTP listeners to the first HTTPS listener instead of answering them
+	#[arg(long = "http-redirect", default_value_t = false)]
+	http_redirect: bool,
+
+	/// Address range allowed to send PROXY protocol headers to listeners with the proxy-protocol option (can be provided multiple times)
+	#[arg(long = "proxy-protocol-trusted")]
+	proxy_protocol_trusted: Vec<String>,
+
+	/// Address range of reverse proxies whose Forwarded, X-Forwarded-For and X-Real-IP headers are trusted (can be provided multiple times)
+	#[arg(long = "trusted-proxy")]
+	trusted_proxy: Vec<String>,
+
+	/// Certificate file path (can be provided multiple times, paired with --key-path in order)
+	#[arg(short, long)]
+	cert_path: Vec<String>,
+
+	/// Key file path (can be provided multiple times)
+	#[arg(short, long)]
+	key_path: Vec<String>,
+
+	/// Directory with <name>.crt and <name>.key pairs or certbot-style subdirectories (can be provided multiple times)
+	#[arg(short = 'd', long = "cert-dir")]
+	cert_dir: Vec<String>,
+
+	/// Host name of the certificate to use for clients that send no or an unknown SNI [default: first certificate]
+	#[arg(long = "default-cert")]
+	default_cert: Option<String>,
+
+	/// Domain to obtain a certificate for with ACME (can be provided multiple times)
+	#[arg(long = "acme-domain")]
+	acme_domain: Vec<String>,
+
+	/// Contact email address for the ACME account (can be provided multiple times)
+	#[arg(long = "acme-email")]
+	acme_email: Vec<String>,
 
-	/// Key file path
-	#[arg(short, long, required = true)]
-	key_path: String,
+	/// ACME directory URL
+	#[arg(
+		long = "acme-directory",
+		default_value = "https://acme-v02.api.letsencrypt.org/directory"
+	)]
+	acme_directory: String,
+
+	/// Additional root certificate to trust for the ACME directory, e.g. of a local test CA
+	#[arg(long = "acme-root-cert")]
+	acme_root_cert: Option<String>,
+
+	/// Directory where the ACME account and certificates are stored
+	#[arg(long = "acme-state-dir", default_value = "acme")]
+	acme_state_dir: String,
+
+	/// ACME challenge type
+	#[arg(long = "acme-challenge", value_enum, default_value_t = AcmeChallenge::TlsAlpn01)]
+	acme_challenge: AcmeChallenge,
+
+	/// Address for the plain HTTP listener answering HTTP-01 challenges (can be provided multiple times)
+	#[arg(long = "acme-http-bind")]
+	acme_http_bind: Vec<String>,
+
+	/// Write an access log of the HTTP requests to stdout, to syslog, or to a file, which is reopened on SIGUSR1
+	#[arg(long = "access-log", value_name = "stdout|syslog|PATH")]
+	access_log: Option<String>,
+
+	/// Format of the access log
+	#[arg(long = "access-log-format", value_enum, default_value_t = AccessLogFormat::Combined)]
+	access_log_format: AccessLogFormat,
+
+	/// Add the JA3 and JA4 fingerprints of TLS clients to the JSON access log
+	#[arg(long = "access-log-fingerprints", default_value_t = false)]
+	access_log_fingerprints: bool,
+
+	/// Format of the application log
+	#[arg(long = "log-format", value_enum, default_value_t = LogFormat::Human)]
+	log_format: LogFormat,
 
 	/// Log interval in seconds
 	#[arg(short = 'i', long = "log-interval", default_value_t = 60)]
 	log_interval: u64,
 
+	/// Interval in seconds for checking the certificate and key files for changes (0 disables)
+	#[arg(short = 'r', long = "reload-interval", default_value_t = 30)]
+	reload_interval: u64,
+
 	/// Use HTTP/2 only
 	#[arg(short = '2', long = "http2-only", default_value_t = false)]
 	http2_only: bool,
+
+	/// Record the frames HTTP/2 clients send at the start of a connection to serve their fingerprint on /h2-fingerprint
+	#[arg(long = "h2-fingerprint", default_value_t = false)]
+	h2_fingerprint: bool,
+
+	/// Also serve HTTP/3 over QUIC on the UDP ports of the HTTPS listeners without proxy-protocol
+	#[arg(long = "http3", default_value_t = false)]
+	http3: bool,
+
+	/// Requests per second each client can make to the HTTP listeners, with IPv6 clients grouped by /64 (0 disables)
+	#[arg(long = "rate-limit", default_value_t = 0.0)]
+	rate_limit: f64,
+
+	/// Requests a client can make at once before the rate limit applies [default: the rate, rounded up]
+	#[arg(long = "rate-limit-burst")]
+	rate_limit_burst: Option<u32>,
+
+	/// Listeners from the [[listener]] tables of the config file
+	#[arg(skip)]
+	listener: Vec<ListenerConfig>,
 }
 
 pub fn main() {
-	match env::var("RUST_LOG") {
-		Err(_) => {
-			env::set_var("RUST_LOG", "info");
-		}
-		Ok(_) => {}
+	if env::var("RUST_LOG").is_err() {
+		env::set_var("RUST_LOG", "info");
 	}
 
-	env_logger::init();
+	let matches = Args::command().get_matches();
+	let args = parse_args(&matches);
 
-	let args = Args::parse();
+	// Errors in the config file are logged in the default format, since it sets the format too
+	logging::init(
+		args.as_ref()
+			.map_or(LogFormat::Human, |args| args.log_format),
+	);
 
-	if let Err(e) = run_server(args) {
+	if let Err(e) = args.and_then(|args| start(args, &matches)) {
 		error!("Fatal: {}", e);
 		std::process::exit(1);
 	}
 }
 
+fn start(args: Args, matches: &ArgMatches) -> Result<()> {
+	// Taken before the runtime starts its threads, since this modifies the environment
+	let sockets = Sockets::inherit()?;
+
+	run_server(args, matches, sockets)
+}
+
+/// Reads the config file, if there is one, for the options not given on the command line.
+fn parse_args(matches: &ArgMatches) -> Result<Args> {
+	let mut args = Args::from_arg_matches(matches)?;
+
+	if let Some(path) = &args.config {
+		FileConfig::load(path)?.apply(&mut args, matches);
+	}
+
+	Ok(args)
+}
+
 #[tokio::main]
-async fn run_server(args: Args) -> Result<()> {
-	let certs = load_certs(args.cert_path.as_str())?;
-	let key = load_private_key(args.key_path.as_str())?;
+async fn run_server(mut args: Args, matches: &ArgMatches, sockets: Sockets) -> Result<()> {
+	if args.cert_path.is_empty() && args.cert_dir.is_empty() && args.acme_domain.is_empty() {
+		return Err(anyhow::Error::msg(
+			"no certificates configured, set cert-path and key-path, cert-dir or acme-domain",
+		));
+	}
 
-	let mut servers: Vec<Builder<TlsAcceptor>> = Vec::new();
+	if args.cert_path.len() != args.key_path.len() {
+		return Err(anyhow::Error::msg(format!(
+			"got {} certificate paths but {} key paths",
+			args.cert_path.len(),
+			args.key_path.len()
+		)));
+	}
 
-	for bind in &args.bind {
-		let addr = parse_addr(bind)?;
+	let pairs = args
+		.cert_path
+		.iter()
+		.zip(&args.key_path)
+		.map(|(cert_path, key_path)| CertPair {
+			cert_path: cert_path.clone(),
+			key_path: key_path.clone(),
+		})
+		.collect();
+
+	let acme_config = match args.acme_domain.is_empty() {
+		true => None,
+		false => Some(AcmeConfig {
+			domains: args.acme_domain.clone(),
+			contact: args.acme_email.clone(),
+			directory_url: args.acme_directory.clone(),
+			root_cert: args.acme_root_cert.clone(),
+			state_dir: args.acme_state_dir.clone(),
+			challenge: args.acme_challenge,
+		}),
+	};
 
-		let incoming = AddrIncoming::bind(&addr)?;
-		let acceptor = TlsAcceptor::builder()
-			.with_single_cert(certs.clone(), key.clone())
-			.map_err(|e| error(format!("{}", e)))?
-			.with_all_versions_alpn()
-			.with_incoming(incoming);
+	let resolver = Arc::new(CertResolver::new(CertSources {
+		pairs,
+		dirs: args.cert_dir.clone(),
+		default_name: args.default_cert.clone(),
+		acme_pair: acme_config.as_ref().map(AcmeConfig::cert_pair),
+	})?);
+	let mut tls_config = rustls::ServerConfig::builder()
+		.with_safe_defaults()
+		.with_no_client_auth()
+		.with_cert_resolver(resolver.clone());
+
+	tls_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec(), b"http/1.0".to_vec()];
+	if acme_config
+		.as_ref()
+		.is_some_and(|c| c.challenge == AcmeChallenge::TlsAlpn01)
+	{
+		tls_config
+			.alpn_protocols
+			.push(tls::ACME_TLS_ALPN_NAME.to_vec());
+	}
 
-		let server = Server::builder(acceptor).http2_only(args.http2_only);
+	// Also built without --http3, since it can be enabled with a reload
+	let mut quic_tls_config = rustls::ServerConfig::builder()
+		.with_safe_default_cipher_suites()
+		.with_safe_default_kx_groups()
+		.with_protocol_versions(&[&rustls::version::TLS13])?
+		.with_no_client_auth()
+		.with_cert_resolver(resolver.clone());
+	quic_tls_config.alpn_protocols = vec![http3::ALPN.to_vec()];
+	let quic_config = quinn::ServerConfig::with_crypto(Arc::new(quic_tls_config));
+
+	if args.access_log_fingerprints && args.access_log_format != AccessLogFormat::Json {
+		return Err(anyhow::Error::msg(
+			"access-log-fingerprints requires access-log-format json",
+		));
+	}
 
-		servers.push(server);
+	let config = server_config(&args)?;
 
-		if addr.is_ipv4() {
-			info!("Starting to serve IPv4 on https://{addr}",);
-		} else {
-			info!("Starting to serve IPv6 on https://{addr}",);
-		}
+	if args.check_config {
+		info!("Configuration is valid");
+		return Ok(());
 	}
 
-	let req_counter = AtomicU64::new(0);
-	let req_counter_arc = Arc::new(req_counter);
-	let req_counter_arc_service = req_counter_arc.clone();
+	let http01_tokens: Arc<Http01Tokens> = Arc::new(RwLock::new(HashMap::new()));
 
-	let service = make_service_fn(move |socket: &TlsStream| {
-		req_counter_arc_service.fetch_add(1, Ordering::SeqCst);
+	let access_log = match &args.access_log {
+		Some(output) => Some(AccessLog::start(
+			output,
+			args.access_log_format,
+			args.access_log_fingerprints,
+		)?),
+		None => None,
+	};
 
-		let conn = socket.io();
-		let remote_addr: String;
+	let state = Arc::new(ServerState {
+		tls_acceptor: Arc::new(tls_config).into(),
+		settings: Default::default(),
+		metrics: Metrics::new(),
+		ready: AtomicBool::new(false),
+		access_log,
+		rate_limiter: RateLimiter::new(),
+		http01_tokens: http01_tokens.clone(),
+		router: Router::new(),
+	});
 
-		match conn {
-			None => remote_addr = String::from("error"),
-			Some(val) => remote_addr = format!("{}", val.remote_addr().ip()),
-		}
+	let sockets = Arc::new(Mutex::new(sockets));
+	let mut running = Running::new(state.clone(), sockets.clone(), quic_config);
+	running.apply(config).await?;
+
+	if let Some(acme_config) = acme_config {
+		tokio::spawn(acme::run_acme(acme_config, resolver.clone(), http01_tokens));
+	}
+
+	tokio::spawn(tls::watch_certs(resolver, args.reload_interval));
 
-		async move {
-			Ok::<_, Infallible>(service_fn(move |_: Request<Body>| {
-				let response = Response::new(Body::from(remote_addr.clone()));
-				async { Ok::<_, Infallible>(response) }
-			}))
+	// Registered before the server reports that it is ready, so that no signal is missed
+	let mut signals = Signals::new();
+
+	info!("Server started");
+	state.ready.store(true, Ordering::Relaxed);
+	sockets.lock().unwrap().ready();
+	tokio::spawn(systemd::watchdog());
+	tokio::spawn(upgrade::watch(sockets));
+	tokio::spawn(start_counter(args.log_interval, state.clone()));
+	tokio::spawn(evict_rate_limits(state.clone()));
+
+	loop {
+		match shutdown_signal_helper(&mut signals).await {
+			ExitType::Reload => {
+				info!("Received hangup signal. Reloading configuration...");
+				match reload(&args, matches, &mut running).await {
+					Ok(new_args) => {
+						args = new_args;
+						info!("Configuration reloaded");
+					}
+					Err(e) => error!(
+						"Failed to reload configuration, keeping the previous one: {}",
+						e
+					),
+				}
+				continue;
+			}
+			ExitType::ReopenLogs => {
+				if let Some(access_log) = &state.access_log {
+					info!("Received user-defined signal 1. Reopening access log...");
+					access_log.reopen();
+				}
+				continue;
+			}
+			ExitType::Interrupt => {
+				info!("Received interrupt signal. Exiting...");
+			}
+			ExitType::Termination => {
+				info!("Received termination signal. Exiting...");
+			}
+			ExitType::Upgrade => {
+				info!("Handed over to the new process. Exiting...");
+			}
 		}
-	});
+		break;
+	}
+
+	// After an upgrade, the service belongs to the new process
+	if !upgrade::is_handed_over() {
+		systemd::notify("STOPPING=1");
+	}
+
+	let res = running.shutdown().await;
+
+	// The connections are closed, so the access log has all of their requests
+	if let Some(access_log) = &state.access_log {
+		access_log.close().await;
+	}
+
+	res
+}
+
+/// Reads the configuration again and applies it to the running server. Certificates are
+/// reloaded separately, and the other options that cannot change are kept.
+async fn reload(args: &Args, matches: &ArgMatches, running: &mut Running) -> Result<Args> {
+	let new_args = parse_args(matches)?;
+	let config = server_config(&new_args)?;
+
+	running.apply(config).await?;
+
+	if restart_options(args) != restart_options(&new_args) {
+		warn!(
+			"Changes to the certificate, ACME, logging and interval options take effect after a restart"
+		);
+	}
+
+	Ok(new_args)
+}
+
+/// The options that are only read at startup.
+fn restart_options(args: &Args) -> impl PartialEq + '_ {
+	(
+		(
+			&args.cert_path,
+			&args.key_path,
+			&args.cert_dir,
+			&args.default_cert,
+		),
+		(&args.acme_domain, &args.acme_email, &args.acme_directory),
+		(
+			&args.acme_root_cert,
+			&args.acme_state_dir,
+			args.acme_challenge,
+		),
+		(args.log_interval, args.reload_interval),
+		(
+			&args.access_log,
+			args.access_log_format,
+			args.access_log_fingerprints,
+		),
+		args.log_format,
+	)
+}
+
+/// Validates the options that can be changed with a reload.
+fn server_config(args: &Args) -> Result<reload::Config> {
+	if !args.bind_dns.is_empty() && args.dns_zone.is_none() {
+		return Err(anyhow::Error::msg("bind-dns requires dns-zone to be set"));
+	}
 
-	let mut server_handles: Vec<JoinHandle<hyper::Result<()>>> = Vec::new();
+	let mut specs = Vec::new();
+	for bind in &args.bind {
+		specs.push(ListenerSpec::parse(bind, DEFAULT_PORT, ListenerKind::Tls)?);
+	}
+	for bind in &args.bind_http {
+		specs.push(ListenerSpec::parse(
+			bind,
+			DEFAULT_HTTP_PORT,
+			ListenerKind::Http,
+		)?);
+	}
+	for bind in &args.acme_http_bind {
+		specs.push(ListenerSpec::parse(
+			bind,
+			DEFAULT_HTTP_PORT,
+			ListenerKind::AcmeHttp01,
+		)?);
+	}
+	for bind in &args.admin_bind {
+		specs.push(ListenerSpec::parse(
+			bind,
+			DEFAULT_ADMIN_PORT,
+			ListenerKind::Admin,
+		)?);
+	}
+	for (i, config) in args.listener.iter().enumerate() {
+		let spec = ListenerSpec::from_config(config)
+			.map_err(|e| anyhow::Error::msg(format!("invalid listener {}: {}", i + 1, e)))?;
+		specs.push(spec);
+	}
 
-	for server in servers {
-		server_handles.push(tokio::spawn(
-			server
-				.serve(service.clone())
-				.with_graceful_shutdown(server_shutdown_signal()),
+	// Unix sockets are only reachable by local processes, which are trusted to send the header.
+	// Sockets passed by systemd count as TCP, since their type is not known before they are taken over.
+	let proxy_protocol_tcp = specs
+		.iter()
+		.any(|spec| spec.proxy_protocol && !spec.addr.is_unix());
+	if proxy_protocol_tcp && args.proxy_protocol_trusted.is_empty() {
+		return Err(anyhow::Error::msg(
+			"TCP listeners with proxy-protocol require at least one --proxy-protocol-trusted range",
 		));
 	}
-	info!("Server started");
 
-	start_counter(args.log_interval, req_counter_arc).await;
+	let mut proxy_trusted = Vec::new();
+	for cidr in &args.proxy_protocol_trusted {
+		proxy_trusted.push(parse_cidr(cidr)?);
+	}
+
+	let mut trusted_proxies = Vec::new();
+	for cidr in &args.trusted_proxy {
+		trusted_proxies.push(parse_cidr(cidr)?);
+	}
+
+	rate_limit::check_rate(args.rate_limit)?;
+	rate_limit::check_burst(args.rate_limit_burst)?;
+
+	let dns_zone = args.dns_zone.as_deref().unwrap_or_default();
 
-	for server_handle in server_handles {
-		server_handle.await?.unwrap();
+	// Key paths and contact addresses are not shown to whoever can reach the admin listener
+	let mut effective_config = serde_json::to_value(args)?;
+	for key in ["key-path", "acme-email"] {
+		if let Some(serde_json::Value::Array(values)) = effective_config.get_mut(key) {
+			values.fill("<redacted>".into());
+		}
 	}
 
-	Ok(())
+	Ok(reload::Config {
+		specs,
+		stun: parse_socket_addrs(&args.bind_stun, DEFAULT_STUN_PORT)?,
+		raw_tcp: parse_socket_addrs(&args.bind_raw_tcp, DEFAULT_PORT)?,
+		raw_udp: parse_socket_addrs(&args.bind_raw_udp, DEFAULT_PORT)?,
+		dns: parse_socket_addrs(&args.bind_dns, DEFAULT_DNS_PORT)?,
+		dns_zone: dns_zone.trim_end_matches('.').to_ascii_lowercase().into(),
+		http3: args.http3,
+		http_redirect: args.http_redirect,
+		settings: Settings {
+			http2_only: args.http2_only,
+			h2_fingerprint: args.h2_fingerprint,
+			proxy_trusted,
+			trusted_proxies,
+			http_redirect_port: None,
+			rate_limit: Limit::new(args.rate_limit, args.rate_limit_burst),
+			effective_config,
+		},
+	})
 }
 
-async fn start_counter(log_interval: u64, req_counter_arc: Arc<AtomicU64>) {
+async fn start_counter(log_interval: u64, state: Arc<ServerState>) {
 	let start_time = Instant::now();
 	let mut prev_elapsed_time = Duration::new(0, 0);
 	let mut prev_total_requests = 0;
@@ -146,97 +567,97 @@ async fn start_counter(log_interval: u64, req_counter_arc: Arc<AtomicU64>) {
 	interval.tick().await;
 
 	loop {
-		select! {
-			_ = interval.tick() => {},
-			exit_type = server_shutdown_counter() => {
-				match exit_type {
-					ExitType::Interrupt => {
-						info!("Received interrupt signal. Exiting...");
-					}
-					ExitType::Termination => {
-						info!("Received termination signal. Exiting...");
-					}
-				}
-				break;
-			},
-		}
-		let total_requests = req_counter_arc.load(Ordering::Relaxed);
+		interval.tick().await;
+		let total_requests = state.metrics.requests.total();
 		let total_requests_diff = total_requests - prev_total_requests;
-		let elapsed_time = start_time.elapsed() - prev_elapsed_time;
-		let rps = total_requests_diff as f64 / elapsed_time.as_secs() as f64;
-		let rps_tot = total_requests as f64 / start_time.elapsed().as_secs() as f64;
+		let now = start_time.elapsed();
+		let elapsed_time = now - prev_elapsed_time;
+		let rps = total_requests_diff as f64 / elapsed_time.as_secs_f64();
+		state.metrics.set_interval_rate(rps);
+
+		let stats = state.metrics.stats();
+
+		let mut per_protocol = String::new();
+		let raw_requests = [
+			("TCP", stats.raw_tcp_requests),
+			("UDP", stats.raw_udp_requests),
+		];
+		for (protocol, requests) in raw_requests {
+			if requests > 0 {
+				per_protocol.push_str(&format!("\nRaw {} requests: {}", protocol, requests));
+			}
+		}
 
 		info!(
-			"\nRequests per second: {:.2}\nTotal requests per second: {:.2}\nTotal requests: {}",
-			rps, rps_tot, total_requests
+			requests_per_second = stats.requests_per_second,
+			total_requests_per_second = stats.total_requests_per_second,
+			total_requests = stats.total_requests,
+			raw_tcp_requests = stats.raw_tcp_requests,
+			raw_udp_requests = stats.raw_udp_requests;
+			"\nRequests per second: {:.2}\nTotal requests per second: {:.2}\nTotal requests: {}{}",
+			stats.requests_per_second,
+			stats.total_requests_per_second,
+			stats.total_requests,
+			per_protocol
 		);
 
-		prev_elapsed_time = elapsed_time;
+		prev_elapsed_time = now;
 		prev_total_requests = total_requests;
 	}
 }
 
-fn error(err: String) -> io::Error {
-	io::Error::new(io::ErrorKind::Other, err)
+async fn evict_rate_limits(state: Arc<ServerState>) {
+	let mut interval = time::interval(rate_limit::EVICT_INTERVAL);
+	loop {
+		interval.tick().await;
+		state.rate_limiter.evict_idle();
+	}
 }
 
-fn load_certs(filename: &str) -> io::Result<Vec<rustls::Certificate>> {
-	let cert_file = fs::File::open(filename)
-		.map_err(|e| error(format!("failed to open {}: {}", filename, e)))?;
-	let mut reader = io::BufReader::new(cert_file);
-
-	let certs = rustls_pemfile::certs(&mut reader)
-		.map_err(|_| error("failed to load certificate".into()))?;
-	Ok(certs.into_iter().map(rustls::Certificate).collect())
+fn error(err: String) -> io::Error {
+	io::Error::other(err)
 }
 
-fn load_private_key(filename: &str) -> io::Result<rustls::PrivateKey> {
-	let keyfile = fs::File::open(filename)
-		.map_err(|e| error(format!("failed to open {}: {}", filename, e)))?;
-	let mut reader = io::BufReader::new(keyfile);
-
-	let ec_keys = {
-		reader.seek(SeekFrom::Start(0))?;
-		rustls_pemfile::ec_private_keys(&mut reader)
-			.map_err(|_| error("failed to read EC private keys".into()))?
-	};
-
-	let pkcs8_keys = {
-		reader.seek(SeekFrom::Start(0))?;
-		rustls_pemfile::pkcs8_private_keys(&mut reader)
-			.map_err(|_| error("failed to read PKCS8 private keys".into()))?
-	};
+fn parse_cidr(cidr: &str) -> Result<IpNet> {
+	// A plain address is a single host range
+	match cidr.parse::<IpNet>() {
+		Ok(net) => Ok(net),
+		_ => match cidr.parse::<std::net::IpAddr>() {
+			Ok(addr) => Ok(addr.into()),
+			_ => Err(anyhow::Error::msg(format!(
+				"failed to parse IP address range: {}",
+				cidr
+			))),
+		},
+	}
+}
 
-	let rsa_keys = {
-		reader.seek(SeekFrom::Start(0))?;
-		rustls_pemfile::rsa_private_keys(&mut reader)
-			.map_err(|_| error("failed to read RSA private keys".into()))?
-	};
+/// Parses a listener address, which is either an IP address with optional port,
+/// a Unix socket path like `unix:/run/wut.sock`, or `systemd:<name>` for the
+/// sockets passed by systemd with that `FileDescriptorName=`.
+fn parse_addr(bind: &str, default_port: u16) -> Result<ListenAddr> {
+	if let Some(name) = bind.strip_prefix("systemd:") {
+		return Ok(ListenAddr::Systemd(name.to_string()));
+	}
 
-	let total_keys = ec_keys.len() + pkcs8_keys.len() + rsa_keys.len();
-
-	match (
-		ec_keys.first(),
-		pkcs8_keys.first(),
-		rsa_keys.first(),
-		total_keys,
-	) {
-		(Some(ec_key), _, _, 1) => Ok(rustls::PrivateKey(ec_key.clone())),
-		(_, Some(pkcs8_key), _, 1) => Ok(rustls::PrivateKey(pkcs8_key.clone())),
-		(_, _, Some(rsa_key), 1) => Ok(rustls::PrivateKey(rsa_key.clone())),
-		(_, _, _, 0) => Err(error(format!("no private keys found in file {}", filename)).into()),
-		_ => Err(error(format!(
-			"expected a single private key in file {}",
-			filename
-		))
-		.into()),
+	match bind.strip_prefix("unix:") {
+		Some("") => Err(anyhow::Error::msg("missing Unix socket path")),
+		Some(path) => Ok(ListenAddr::Unix(path.into())),
+		None => parse_socket_addr(bind, default_port).map(ListenAddr::Tcp),
 	}
 }
 
-fn parse_addr(bind: &String) -> Result<SocketAddr> {
+fn parse_socket_addrs(binds: &[String], default_port: u16) -> Result<Vec<SocketAddr>> {
+	binds
+		.iter()
+		.map(|bind| parse_socket_addr(bind, default_port))
+		.collect()
+}
+
+fn parse_socket_addr(bind: &str, default_port: u16) -> Result<SocketAddr> {
 	// The user tried to enter an IPv4 or IPv6 with
 	// a port and the address should be parsed as is.
-	if bind.matches(":").count() == 1 || bind.as_str().contains("]:") {
+	if bind.matches(":").count() == 1 || bind.contains("]:") {
 		return match bind.parse() {
 			Ok(addr) => Ok(addr),
 			_ => Err(anyhow::Error::msg(format!(
@@ -247,47 +668,56 @@ fn parse_addr(bind: &String) -> Result<SocketAddr> {
 	}
 
 	// Test IPv4 first
-	return match format!("{bind}:{DEFAULT_PORT}").parse() {
+	match format!("{bind}:{default_port}").parse() {
 		Ok(addr) => Ok(addr),
 		_ => {
 			// Then IPv6
-			match format!("[{bind}]:{DEFAULT_PORT}").parse() {
+			match format!("[{bind}]:{default_port}").parse() {
 				Ok(addr) => Ok(addr),
-				_ => {
-					return Err(anyhow::Error::msg(format!(
-						"failed to parse bind IP address: {}",
-						bind
-					)))
-				}
+				_ => Err(anyhow::Error::msg(format!(
+					"failed to parse bind IP address: {}",
+					bind
+				))),
 			}
 		}
-	};
+	}
 }
 
 enum ExitType {
 	Termination,
 	Interrupt,
+	Upgrade,
+	Reload,
+	ReopenLogs,
 }
 
-async fn shutdown_signal_helper() -> ExitType {
-	let mut sigterm
-		= signal(SignalKind::terminate())
-		.expect("failed to initialize SIGTERM handler");
-
-	let mut sigint
-		= signal(SignalKind::interrupt())
-		.expect("failed to initialize SIGTERM handler");
-
-	select! {
-		_ = sigterm.recv() => return ExitType::Termination,
-		_ = sigint.recv() => return ExitType::Interrupt,
-	}
+/// Signal handlers, which are kept for the lifetime of the server so that signals
+/// arriving during a reload are not lost.
+struct Signals {
+	sigterm: Signal,
+	sigint: Signal,
+	sighup: Signal,
+	sigusr1: Signal,
 }
 
-async fn server_shutdown_signal() {
-	let _ = shutdown_signal_helper().await;
+impl Signals {
+	fn new() -> Self {
+		Signals {
+			sigterm: signal(SignalKind::terminate()).expect("failed to initialize SIGTERM handler"),
+			sigint: signal(SignalKind::interrupt()).expect("failed to initialize SIGINT handler"),
+			sighup: signal(SignalKind::hangup()).expect("failed to initialize SIGHUP handler"),
+			sigusr1: signal(SignalKind::user_defined1())
+				.expect("failed to initialize SIGUSR1 handler"),
+		}
+	}
 }
 
-async fn server_shutdown_counter() -> ExitType {
-	return shutdown_signal_helper().await;
+async fn shutdown_signal_helper(signals: &mut Signals) -> ExitType {
+	select! {
+		_ = signals.sigterm.recv() => ExitType::Termination,
+		_ = signals.sigint.recv() => ExitType::Interrupt,
+		_ = signals.sighup.recv() => ExitType::Reload,
+		_ = signals.sigusr1.recv() => ExitType::ReopenLogs,
+		_ = upgrade::handed_over() => ExitType::Upgrade,
+	}
 }
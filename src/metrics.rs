@@ -0,0 +1,344 @@
+//! Counters in the Prometheus text format, served on `/metrics` of the admin listener.
+
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Instant;
+
+/// Content type of the Prometheus text format
+pub const PROMETHEUS_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+/// Content type of the OpenMetrics text format
+pub const OPENMETRICS_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+/// The labels of a sample. Empty labels are left out.
+#[derive(Clone, Default, PartialEq, Eq, Hash)]
+pub struct Labels {
+	/// Address of the listener
+	pub listener: Arc<str>,
+	pub family: &'static str,
+	/// HTTP version, or the protocol of the listeners that do not speak HTTP
+	pub version: &'static str,
+	/// HTTP status code
+	pub code: u16,
+}
+
+impl Labels {
+	pub fn new(listener: &Arc<str>, addr: SocketAddr) -> Self {
+		Labels {
+			listener: listener.clone(),
+			family: family(addr.ip()),
+			..Default::default()
+		}
+	}
+
+	pub fn with_version(&self, version: &'static str) -> Self {
+		Labels {
+			version,
+			..self.clone()
+		}
+	}
+}
+
+/// The listener label of a socket that is not a listener of its own.
+pub fn socket_name(addr: io::Result<SocketAddr>) -> Arc<str> {
+	addr.map_or_else(|_| Arc::from(""), |addr| addr.to_string().into())
+}
+
+pub fn family(ip: IpAddr) -> &'static str {
+	match ip.to_canonical() {
+		IpAddr::V4(_) => "ipv4",
+		IpAddr::V6(_) => "ipv6",
+	}
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum MetricType {
+	Counter,
+	Gauge,
+}
+
+/// A counter or gauge with a value for every label set it was used with.
+pub struct Metric {
+	name: &'static str,
+	help: &'static str,
+	metric_type: MetricType,
+	values: RwLock<HashMap<Labels, AtomicU64>>,
+}
+
+impl Metric {
+	fn new(name: &'static str, help: &'static str, metric_type: MetricType) -> Self {
+		Metric {
+			name,
+			help,
+			metric_type,
+			values: RwLock::new(HashMap::new()),
+		}
+	}
+
+	pub fn add(&self, labels: &Labels, value: u64) {
+		// The label sets are known after the first few requests, so this rarely takes the write lock
+		if let Some(counter) = self.values.read().unwrap().get(labels) {
+			counter.fetch_add(value, Ordering::Relaxed);
+			return;
+		}
+
+		self.values
+			.write()
+			.unwrap()
+			.entry(labels.clone())
+			.or_default()
+			.fetch_add(value, Ordering::Relaxed);
+	}
+
+	pub fn inc(&self, labels: &Labels) {
+		self.add(labels, 1);
+	}
+
+	/// Decrements a gauge, which must have been incremented with the same labels before.
+	pub fn dec(&self, labels: &Labels) {
+		if let Some(gauge) = self.values.read().unwrap().get(labels) {
+			gauge.fetch_sub(1, Ordering::Relaxed);
+		}
+	}
+
+	/// The sum of the values with labels matching `filter`.
+	pub fn sum(&self, filter: impl Fn(&Labels) -> bool) -> u64 {
+		self.values
+			.read()
+			.unwrap()
+			.iter()
+			.filter(|(labels, _)| filter(labels))
+			.map(|(_, value)| value.load(Ordering::Relaxed))
+			.sum()
+	}
+
+	pub fn total(&self) -> u64 {
+		self.sum(|_| true)
+	}
+
+	fn encode(&self, out: &mut String, openmetrics: bool) {
+		let metric_type = match self.metric_type {
+			MetricType::Counter => "counter",
+			MetricType::Gauge => "gauge",
+		};
+
+		// OpenMetrics names the counter without the suffix of its samples
+		let family_name = match openmetrics {
+			true => self.name.trim_end_matches("_total"),
+			false => self.name,
+		};
+		let _ = writeln!(out, "# HELP {} {}", family_name, self.help);
+		let _ = writeln!(out, "# TYPE {} {}", family_name, metric_type);
+
+		let values = self.values.read().unwrap();
+		let mut samples: Vec<_> = values.iter().collect();
+		samples.sort_by(|(a, _), (b, _)| {
+			(&a.listener, a.family, a.version, a.code).cmp(&(
+				&b.listener,
+				b.family,
+				b.version,
+				b.code,
+			))
+		});
+
+		for (labels, value) in samples {
+			out.push_str(self.name);
+			encode_labels(out, labels);
+			let _ = writeln!(out, " {}", value.load(Ordering::Relaxed));
+		}
+	}
+}
+
+fn encode_labels(out: &mut String, labels: &Labels) {
+	let code = match labels.code {
+		0 => String::new(),
+		code => code.to_string(),
+	};
+	let pairs = [
+		("listener", &*labels.listener),
+		("family", labels.family),
+		("version", labels.version),
+		("code", &code),
+	];
+
+	let mut first = true;
+	for (name, value) in pairs.iter().filter(|(_, value)| !value.is_empty()) {
+		out.push(if first { '{' } else { ',' });
+		first = false;
+
+		out.push_str(name);
+		out.push_str("=\"");
+		for c in value.chars() {
+			match c {
+				'\\' => out.push_str("\\\\"),
+				'"' => out.push_str("\\\""),
+				'\n' => out.push_str("\\n"),
+				c => out.push(c),
+			}
+		}
+		out.push('"');
+	}
+
+	if !first {
+		out.push('}');
+	}
+}
+
+/// The request counts that are logged every `--log-interval` seconds and served on `/stats`.
+#[derive(Serialize)]
+pub struct Stats {
+	/// Over the last log interval
+	pub requests_per_second: f64,
+	pub total_requests_per_second: f64,
+	pub total_requests: u64,
+	pub raw_tcp_requests: u64,
+	pub raw_udp_requests: u64,
+	pub uptime_seconds: u64,
+}
+
+/// All metrics of the server. The admin listeners are not counted.
+pub struct Metrics {
+	started: Instant,
+	/// Requests per second over the last log interval
+	interval_rate: Mutex<f64>,
+	pub connections: Metric,
+	pub open_connections: Metric,
+	pub tls_handshake_failures: Metric,
+	/// Requests on all listeners, including STUN, DNS and the raw listeners
+	pub requests: Metric,
+	pub responses: Metric,
+	pub sent_bytes: Metric,
+	/// Requests rejected by the rate limit, which are not counted as requests
+	pub rate_limited: Metric,
+	pub access_log_dropped: Metric,
+}
+
+impl Metrics {
+	pub fn new() -> Self {
+		Metrics {
+			started: Instant::now(),
+			interval_rate: Mutex::new(0.0),
+			connections: Metric::new(
+				"wut_connections_total",
+				"Accepted connections.",
+				MetricType::Counter,
+			),
+			open_connections: Metric::new(
+				"wut_open_connections",
+				"Connections that are currently open.",
+				MetricType::Gauge,
+			),
+			tls_handshake_failures: Metric::new(
+				"wut_tls_handshake_failures_total",
+				"Connections closed because the TLS handshake failed.",
+				MetricType::Counter,
+			),
+			requests: Metric::new(
+				"wut_requests_total",
+				"Received requests.",
+				MetricType::Counter,
+			),
+			responses: Metric::new(
+				"wut_responses_total",
+				"HTTP responses by status code.",
+				MetricType::Counter,
+			),
+			sent_bytes: Metric::new(
+				"wut_sent_bytes_total",
+				"Bytes sent in response bodies and datagrams.",
+				MetricType::Counter,
+			),
+			rate_limited: Metric::new(
+				"wut_rate_limited_total",
+				"Requests rejected by the rate limit.",
+				MetricType::Counter,
+			),
+			access_log_dropped: Metric::new(
+				"wut_access_log_dropped_total",
+				"Access log entries dropped because the writer could not keep up.",
+				MetricType::Counter,
+			),
+		}
+	}
+
+	/// Counts a request with its response.
+	pub fn response(&self, labels: &Labels, status: u16, bytes: u64) {
+		self.requests.inc(labels);
+		self.responses.inc(&Labels {
+			code: status,
+			..labels.clone()
+		});
+		self.sent_bytes.add(labels, bytes);
+	}
+
+	/// Counts a request to one of the listeners that do not speak HTTP.
+	pub fn request(&self, labels: &Labels, bytes: u64) {
+		self.requests.inc(labels);
+		self.sent_bytes.add(labels, bytes);
+	}
+
+	pub fn set_interval_rate(&self, rate: f64) {
+		*self.interval_rate.lock().unwrap() = rate;
+	}
+
+	pub fn stats(&self) -> Stats {
+		let total_requests = self.requests.total();
+		let uptime = self.started.elapsed();
+
+		Stats {
+			requests_per_second: *self.interval_rate.lock().unwrap(),
+			total_requests_per_second: total_requests as f64 / uptime.as_secs_f64(),
+			total_requests,
+			raw_tcp_requests: self.requests.sum(|labels| labels.version == "raw-tcp"),
+			raw_udp_requests: self.requests.sum(|labels| labels.version == "raw-udp"),
+			uptime_seconds: uptime.as_secs(),
+		}
+	}
+
+	/// Renders all metrics in the Prometheus or OpenMetrics text format.
+	pub fn encode(&self, openmetrics: bool) -> String {
+		let mut out = String::new();
+
+		for metric in [
+			&self.connections,
+			&self.open_connections,
+			&self.tls_handshake_failures,
+			&self.requests,
+			&self.responses,
+			&self.sent_bytes,
+			&self.rate_limited,
+			&self.access_log_dropped,
+		] {
+			metric.encode(&mut out, openmetrics);
+		}
+
+		if openmetrics {
+			out.push_str("# EOF\n");
+		}
+		out
+	}
+}
+
+/// Decrements the open connections when a connection is closed.
+pub struct OpenConnection<'a> {
+	metrics: &'a Metrics,
+	labels: Labels,
+}
+
+impl<'a> OpenConnection<'a> {
+	pub fn new(metrics: &'a Metrics, labels: Labels) -> Self {
+		metrics.connections.inc(&labels);
+		metrics.open_connections.inc(&labels);
+		OpenConnection { metrics, labels }
+	}
+}
+
+impl Drop for OpenConnection<'_> {
+	fn drop(&mut self) {
+		self.metrics.open_connections.dec(&self.labels);
+	}
+}
@@ -0,0 +1,420 @@
+//! Parsing of HAProxy PROXY protocol v1 and v2 headers.
+//!
+//! See <https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt>.
+
+use crate::error;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+const V1_MAX_LEN: usize = 107;
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+const V2_HEADER_LEN: usize = 16;
+
+pub enum ProxyHeader {
+	/// The connection was proxied on behalf of this source address.
+	Proxied(SocketAddr),
+	/// The header does not carry a usable address, e.g. a health check
+	/// of the proxy itself, so the socket address should be used.
+	Local,
+}
+
+/// Reads a PROXY protocol header from the start of the stream if there is one.
+///
+/// Returns the parsed header and the bytes that were read past it,
+/// which must be replayed before the rest of the stream.
+pub async fn read_header<S: AsyncRead + Unpin>(
+	stream: &mut S,
+) -> io::Result<(Option<ProxyHeader>, Vec<u8>)> {
+	let mut buf = Vec::with_capacity(V1_MAX_LEN + 1);
+
+	loop {
+		if starts_with_partial(&buf, V1_PREFIX) {
+			if let Some(end) = buf.windows(2).position(|w| w == b"\r\n") {
+				let header = parse_v1(&buf[..end])?;
+				return Ok((Some(header), buf.split_off(end + 2)));
+			}
+			if buf.len() >= V1_MAX_LEN {
+				return Err(error("PROXY protocol v1 header too long".into()));
+			}
+		} else if starts_with_partial(&buf, V2_SIGNATURE) {
+			if buf.len() >= V2_HEADER_LEN {
+				let len = u16::from_be_bytes([buf[14], buf[15]]) as usize;
+				if buf.len() >= V2_HEADER_LEN + len {
+					let header = parse_v2(&buf[..V2_HEADER_LEN + len])?;
+					return Ok((Some(header), buf.split_off(V2_HEADER_LEN + len)));
+				}
+			}
+		} else {
+			return Ok((None, buf));
+		}
+
+		if stream.read_buf(&mut buf).await? == 0 {
+			return Err(io::ErrorKind::UnexpectedEof.into());
+		}
+	}
+}
+
+/// Reads only as far as needed to tell whether the stream starts with a PROXY protocol
+/// signature, for peers that are not allowed to send a header.
+///
+/// Returns whether a signature was found and the bytes that were read,
+/// which must be replayed before the rest of the stream.
+pub async fn read_signature<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<(bool, Vec<u8>)> {
+	let mut buf = Vec::with_capacity(V2_SIGNATURE.len());
+
+	loop {
+		if buf.starts_with(V1_PREFIX) || buf.starts_with(V2_SIGNATURE) {
+			return Ok((true, buf));
+		}
+		if !starts_with_partial(&buf, V1_PREFIX) && !starts_with_partial(&buf, V2_SIGNATURE) {
+			return Ok((false, buf));
+		}
+
+		if stream.read_buf(&mut buf).await? == 0 {
+			return Err(io::ErrorKind::UnexpectedEof.into());
+		}
+	}
+}
+
+/// Whether `buf` and `prefix` agree on their common length.
+fn starts_with_partial(buf: &[u8], prefix: &[u8]) -> bool {
+	let len = buf.len().min(prefix.len());
+	buf[..len] == prefix[..len]
+}
+
+/// Parses a line like `PROXY TCP4 192.0.2.1 198.51.100.1 56324 443`.
+fn parse_v1(line: &[u8]) -> io::Result<ProxyHeader> {
+	let invalid = || error("invalid PROXY protocol v1 header".into());
+
+	let line = std::str::from_utf8(line).map_err(|_| invalid())?;
+	let mut parts = line.split(' ').skip(1);
+
+	match parts.next() {
+		Some("TCP4") | Some("TCP6") => {}
+		Some("UNKNOWN") => return Ok(ProxyHeader::Local),
+		_ => return Err(invalid()),
+	}
+
+	let src_ip: IpAddr = parts
+		.next()
+		.and_then(|p| p.parse().ok())
+		.ok_or_else(invalid)?;
+	let _dst_ip: IpAddr = parts
+		.next()
+		.and_then(|p| p.parse().ok())
+		.ok_or_else(invalid)?;
+	let src_port: u16 = parts
+		.next()
+		.and_then(|p| p.parse().ok())
+		.ok_or_else(invalid)?;
+	let _dst_port: u16 = parts
+		.next()
+		.and_then(|p| p.parse().ok())
+		.ok_or_else(invalid)?;
+
+	if parts.next().is_some() {
+		return Err(invalid());
+	}
+
+	Ok(ProxyHeader::Proxied(SocketAddr::new(src_ip, src_port)))
+}
+
+/// Parses a binary v2 header, including the 16 byte fixed part.
+fn parse_v2(header: &[u8]) -> io::Result<ProxyHeader> {
+	let invalid = || error("invalid PROXY protocol v2 header".into());
+
+	let version_command = header[12];
+	if version_command >> 4 != 2 {
+		return Err(invalid());
+	}
+
+	match version_command & 0x0f {
+		// LOCAL
+		0 => return Ok(ProxyHeader::Local),
+		// PROXY
+		1 => {}
+		_ => return Err(invalid()),
+	}
+
+	let addresses = &header[V2_HEADER_LEN..];
+
+	// The high nibble is the address family, the low nibble the transport protocol
+	match header[13] >> 4 {
+		// AF_INET
+		1 => {
+			let a = addresses.get(..12).ok_or_else(invalid)?;
+			let ip = Ipv4Addr::new(a[0], a[1], a[2], a[3]);
+			let port = u16::from_be_bytes([a[8], a[9]]);
+			Ok(ProxyHeader::Proxied(SocketAddr::new(ip.into(), port)))
+		}
+		// AF_INET6
+		2 => {
+			let a = addresses.get(..36).ok_or_else(invalid)?;
+			let ip: [u8; 16] = a[..16].try_into().unwrap();
+			let port = u16::from_be_bytes([a[32], a[33]]);
+			Ok(ProxyHeader::Proxied(SocketAddr::new(
+				Ipv6Addr::from(ip).into(),
+				port,
+			)))
+		}
+		// AF_UNSPEC or AF_UNIX
+		_ => Ok(ProxyHeader::Local),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Reads a header from `input`, returning the source address, or `None` for a LOCAL
+	/// header, and the bytes after the header.
+	async fn read(input: &[u8]) -> io::Result<(Option<Option<SocketAddr>>, Vec<u8>)> {
+		let mut stream = input;
+		let (header, rest) = read_header(&mut stream).await?;
+		let header = header.map(|header| match header {
+			ProxyHeader::Proxied(addr) => Some(addr),
+			ProxyHeader::Local => None,
+		});
+
+		let mut rest = rest;
+		rest.extend_from_slice(stream);
+		Ok((header, rest))
+	}
+
+	fn v2(command: u8, family: u8, payload: &[u8]) -> Vec<u8> {
+		let mut header = V2_SIGNATURE.to_vec();
+		header.push(0x20 | command);
+		header.push(family);
+		header.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+		header.extend_from_slice(payload);
+		header
+	}
+
+	fn v4_addresses() -> Vec<u8> {
+		let mut addresses = vec![192, 0, 2, 1, 198, 51, 100, 1];
+		addresses.extend_from_slice(&56324u16.to_be_bytes());
+		addresses.extend_from_slice(&443u16.to_be_bytes());
+		addresses
+	}
+
+	fn v6_addresses() -> Vec<u8> {
+		let mut addresses = Vec::new();
+		addresses.extend_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+		addresses.extend_from_slice(&"2001:db8::2".parse::<Ipv6Addr>().unwrap().octets());
+		addresses.extend_from_slice(&56324u16.to_be_bytes());
+		addresses.extend_from_slice(&443u16.to_be_bytes());
+		addresses
+	}
+
+	fn is_invalid(res: io::Result<(Option<Option<SocketAddr>>, Vec<u8>)>) -> bool {
+		res.is_err_and(|e| e.kind() == io::ErrorKind::Other)
+	}
+
+	fn is_eof(res: io::Result<(Option<Option<SocketAddr>>, Vec<u8>)>) -> bool {
+		res.is_err_and(|e| e.kind() == io::ErrorKind::UnexpectedEof)
+	}
+
+	#[tokio::test]
+	async fn v1_tcp4() {
+		let (header, rest) =
+			read(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\nGET / HTTP/1.1\r\n")
+				.await
+				.unwrap();
+		assert_eq!(header, Some(Some("192.0.2.1:56324".parse().unwrap())));
+		assert_eq!(rest, b"GET / HTTP/1.1\r\n");
+	}
+
+	#[tokio::test]
+	async fn v1_tcp6() {
+		let (header, rest) = read(b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 443\r\n")
+			.await
+			.unwrap();
+		assert_eq!(header, Some(Some("[2001:db8::1]:56324".parse().unwrap())));
+		assert!(rest.is_empty());
+	}
+
+	#[tokio::test]
+	async fn v1_unknown() {
+		let (header, _) = read(b"PROXY UNKNOWN\r\n").await.unwrap();
+		assert_eq!(header, Some(None));
+
+		// The addresses after UNKNOWN are to be ignored
+		let (header, _) = read(b"PROXY UNKNOWN ffff:: ffff:: 1 2\r\n").await.unwrap();
+		assert_eq!(header, Some(None));
+	}
+
+	#[tokio::test]
+	async fn v1_split_reads() {
+		let mut stream = b"PROXY TCP4 192.0.2.1 ".chain(&b"198.51.100.1 56324 443\r\nrest"[..]);
+		let (header, rest) = read_header(&mut stream).await.unwrap();
+		assert!(matches!(header, Some(ProxyHeader::Proxied(addr)) if addr.port() == 56324));
+
+		let mut rest = rest;
+		stream.read_to_end(&mut rest).await.unwrap();
+		assert_eq!(rest, b"rest");
+	}
+
+	#[tokio::test]
+	async fn v1_invalid() {
+		for header in [
+			&b"PROXY TCP5 192.0.2.1 198.51.100.1 56324 443\r\n"[..],
+			b"PROXY TCP4 192.0.2.1 198.51.100.1 56324\r\n",
+			b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443 1\r\n",
+			b"PROXY TCP4 192.0.2.256 198.51.100.1 56324 443\r\n",
+			b"PROXY TCP4 192.0.2.1 198.51.100.1 65536 443\r\n",
+			b"PROXY TCP4  192.0.2.1 198.51.100.1 56324 443\r\n",
+			b"PROXY TCP4 192.0.2.1 198.51.100.1 \xff 443\r\n",
+		] {
+			assert!(is_invalid(read(header).await), "{:?}", header);
+		}
+	}
+
+	#[tokio::test]
+	async fn v1_too_long() {
+		let mut header = b"PROXY UNKNOWN ".to_vec();
+		header.resize(V1_MAX_LEN + 10, b'a');
+		header.extend_from_slice(b"\r\n");
+		assert!(is_invalid(read(&header).await));
+	}
+
+	#[tokio::test]
+	async fn v1_truncated() {
+		assert!(is_eof(
+			read(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443").await
+		));
+		assert!(is_eof(read(b"PROX").await));
+	}
+
+	#[tokio::test]
+	async fn v2_proxy_tcp4() {
+		let mut input = v2(1, 0x11, &v4_addresses());
+		input.extend_from_slice(b"\x16\x03\x01");
+		let (header, rest) = read(&input).await.unwrap();
+		assert_eq!(header, Some(Some("192.0.2.1:56324".parse().unwrap())));
+		assert_eq!(rest, b"\x16\x03\x01");
+	}
+
+	#[tokio::test]
+	async fn v2_proxy_tcp6() {
+		let (header, rest) = read(&v2(1, 0x21, &v6_addresses())).await.unwrap();
+		assert_eq!(header, Some(Some("[2001:db8::1]:56324".parse().unwrap())));
+		assert!(rest.is_empty());
+	}
+
+	#[tokio::test]
+	async fn v2_tlvs() {
+		let mut payload = v4_addresses();
+		// PP2_TYPE_ALPN
+		payload.extend_from_slice(b"\x01\x00\x02h2");
+		// PP2_TYPE_AUTHORITY
+		payload.extend_from_slice(b"\x02\x00\x0bexample.com");
+		// PP2_TYPE_NOOP as padding
+		payload.extend_from_slice(b"\x04\x00\x03\0\0\0");
+
+		let mut input = v2(1, 0x11, &payload);
+		input.extend_from_slice(b"after");
+		let (header, rest) = read(&input).await.unwrap();
+		assert_eq!(header, Some(Some("192.0.2.1:56324".parse().unwrap())));
+		assert_eq!(rest, b"after");
+	}
+
+	#[tokio::test]
+	async fn v2_local() {
+		// Health checks of the proxy usually have no addresses
+		let (header, rest) = read(&v2(0, 0x00, &[])).await.unwrap();
+		assert_eq!(header, Some(None));
+		assert!(rest.is_empty());
+
+		// Addresses and TLVs of a LOCAL header are ignored
+		let mut payload = v4_addresses();
+		payload.extend_from_slice(b"\x01\x00\x02h2");
+		let (header, _) = read(&v2(0, 0x11, &payload)).await.unwrap();
+		assert_eq!(header, Some(None));
+	}
+
+	#[tokio::test]
+	async fn v2_unspec_and_unix() {
+		let (header, _) = read(&v2(1, 0x00, &[])).await.unwrap();
+		assert_eq!(header, Some(None));
+		let (header, _) = read(&v2(1, 0x31, &[0; 216])).await.unwrap();
+		assert_eq!(header, Some(None));
+	}
+
+	#[tokio::test]
+	async fn v2_invalid() {
+		// Unknown command
+		assert!(is_invalid(read(&v2(2, 0x11, &v4_addresses())).await));
+
+		// Version 1 in the binary format
+		let mut input = v2(1, 0x11, &v4_addresses());
+		input[12] = 0x11;
+		assert!(is_invalid(read(&input).await));
+
+		// Lengths too short for the address family
+		assert!(is_invalid(read(&v2(1, 0x11, &v4_addresses()[..11])).await));
+		assert!(is_invalid(read(&v2(1, 0x21, &v4_addresses())).await));
+	}
+
+	#[tokio::test]
+	async fn v2_truncated() {
+		let input = v2(1, 0x21, &v6_addresses());
+		for len in [1, 12, V2_HEADER_LEN - 1, V2_HEADER_LEN, input.len() - 1] {
+			assert!(is_eof(read(&input[..len]).await), "{}", len);
+		}
+
+		// A length past the end of the stream waits for more data instead of reading past it
+		let mut input = v2(1, 0x11, &v4_addresses());
+		input[14..16].copy_from_slice(&u16::MAX.to_be_bytes());
+		assert!(is_eof(read(&input).await));
+	}
+
+	#[tokio::test]
+	async fn bad_signature() {
+		// Not a header, so everything read is replayed
+		let mut input = v2(1, 0x11, &v4_addresses());
+		input[8] = b'X';
+		let (header, rest) = read(&input).await.unwrap();
+		assert_eq!(header, None);
+		assert_eq!(rest, input);
+
+		let (header, rest) = read(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+		assert_eq!(header, None);
+		assert_eq!(rest, b"GET / HTTP/1.1\r\n\r\n");
+
+		let (header, rest) = read(b"PROXY\tTCP4").await.unwrap();
+		assert_eq!(header, None);
+		assert_eq!(rest, b"PROXY\tTCP4");
+	}
+
+	#[tokio::test]
+	async fn signature() {
+		async fn check(input: &[u8]) -> (bool, Vec<u8>) {
+			let mut stream = input;
+			read_signature(&mut stream).await.unwrap()
+		}
+
+		// A header is detected without reading its remainder
+		let (found, read) = check(&v2(1, 0x11, &v4_addresses())).await;
+		assert!(found);
+		assert!(read.starts_with(V2_SIGNATURE));
+		let (found, read) = check(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n").await;
+		assert!(found);
+		assert!(read.starts_with(V1_PREFIX));
+
+		// Anything else is replayed
+		let (found, read) = check(b"GET / HTTP/1.1\r\n\r\n").await;
+		assert!(!found);
+		assert!(b"GET / HTTP/1.1\r\n\r\n".starts_with(&read));
+		assert_eq!(
+			check(b"PROXY\tTCP4").await,
+			(false, b"PROXY\tTCP4".to_vec())
+		);
+
+		let mut stream: &[u8] = b"\r\n\r\n";
+		assert!(read_signature(&mut stream)
+			.await
+			.is_err_and(|e| e.kind() == io::ErrorKind::UnexpectedEof));
+	}
+}
@@ -0,0 +1,390 @@
+//! Per-client token buckets for the HTTP listeners. Clients are told to come back
+//! with `429 Too Many Requests` once their bucket is empty.
+
+use anyhow::Result;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, RandomState};
+use std::net::{IpAddr, Ipv6Addr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Shards of the client table, each behind its own lock
+const SHARDS: usize = 16;
+/// Clients tracked at once, after which the least recently seen ones are forgotten
+const MAX_CLIENTS: usize = 65536;
+/// Share of a full shard that is made free at once, so that eviction does not run for
+/// every new client
+const EVICT_FRACTION: usize = 8;
+/// Lowest rate other than 0, so that the time until a bucket is full stays representable
+const MIN_RATE: f64 = 0.001;
+/// Interval of the sweep for clients whose bucket has filled up again
+pub const EVICT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Requests per second with the number of requests that can be made at once.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Limit {
+	/// 0 for no limit
+	pub rate: f64,
+	pub burst: f64,
+}
+
+impl Limit {
+	/// The burst defaults to the requests of one second.
+	pub fn new(rate: f64, burst: Option<u32>) -> Self {
+		Limit {
+			rate,
+			burst: burst.map_or(rate.ceil().max(1.0), f64::from),
+		}
+	}
+}
+
+pub fn parse_rate(rate: &str) -> Result<f64> {
+	match rate.parse::<f64>() {
+		Ok(rate) => check_rate(rate).map(|_| rate),
+		Err(_) => anyhow::bail!("invalid rate limit {}", rate),
+	}
+}
+
+pub fn check_rate(rate: f64) -> Result<()> {
+	if !rate.is_finite() || (rate != 0.0 && rate < MIN_RATE) || rate < 0.0 {
+		anyhow::bail!(
+			"invalid rate limit {}, expected 0 or at least {} requests per second",
+			rate,
+			MIN_RATE
+		);
+	}
+	Ok(())
+}
+
+pub fn check_burst(burst: Option<u32>) -> Result<()> {
+	if burst == Some(0) {
+		anyhow::bail!("the rate limit burst must be at least 1");
+	}
+	Ok(())
+}
+
+struct Bucket {
+	tokens: f64,
+	updated: Instant,
+	/// When the bucket is full again, after which it is no different from a new one
+	full_at: Instant,
+}
+
+/// Buckets by scope and client. The scope is empty for the global limit, or the address of
+/// a listener with a limit of its own. IPv6 clients are grouped by /64, which is usually a
+/// single host.
+type Shard = HashMap<(Arc<str>, IpAddr), Bucket>;
+
+pub struct RateLimiter {
+	shards: Vec<Mutex<Shard>>,
+	hasher: RandomState,
+	/// Scope of the global limit
+	global: Arc<str>,
+}
+
+impl RateLimiter {
+	pub fn new() -> Self {
+		RateLimiter {
+			shards: (0..SHARDS).map(|_| Mutex::new(HashMap::new())).collect(),
+			hasher: RandomState::new(),
+			global: Arc::from(""),
+		}
+	}
+
+	/// Takes a token from the bucket of a client, or returns the time until there is one.
+	/// `listener` is the address of a listener with a limit of its own.
+	pub fn check(
+		&self,
+		listener: Option<&Arc<str>>,
+		ip: IpAddr,
+		limit: Limit,
+	) -> Result<(), Duration> {
+		self.check_at(listener, ip, limit, Instant::now())
+	}
+
+	fn check_at(
+		&self,
+		listener: Option<&Arc<str>>,
+		ip: IpAddr,
+		limit: Limit,
+		now: Instant,
+	) -> Result<(), Duration> {
+		let scope = listener.unwrap_or(&self.global);
+		let key = (scope.clone(), client_key(ip));
+		let mut shard = self.shards[self.hasher.hash_one(&key) as usize % SHARDS]
+			.lock()
+			.unwrap();
+
+		if shard.len() >= MAX_CLIENTS / SHARDS && !shard.contains_key(&key) {
+			evict(&mut shard, now);
+		}
+
+		let bucket = shard.entry(key).or_insert(Bucket {
+			tokens: limit.burst,
+			updated: now,
+			full_at: now,
+		});
+
+		let refilled = now.duration_since(bucket.updated).as_secs_f64() * limit.rate;
+		let tokens = (bucket.tokens + refilled).min(limit.burst);
+		let allowed = tokens >= 1.0;
+
+		bucket.tokens = if allowed { tokens - 1.0 } else { tokens };
+		bucket.updated = now;
+		bucket.full_at = now + Duration::from_secs_f64((limit.burst - bucket.tokens) / limit.rate);
+
+		match allowed {
+			true => Ok(()),
+			false => Err(Duration::from_secs_f64((1.0 - tokens) / limit.rate)),
+		}
+	}
+
+	/// Forgets the clients whose bucket has filled up again.
+	pub fn evict_idle(&self) {
+		let now = Instant::now();
+		for shard in &self.shards {
+			shard
+				.lock()
+				.unwrap()
+				.retain(|_, bucket| bucket.full_at > now);
+		}
+	}
+}
+
+/// Makes room in a full shard, by forgetting the idle clients, and then the least recently
+/// seen ones until at least a fraction of the shard is free.
+fn evict(shard: &mut Shard, now: Instant) {
+	shard.retain(|_, bucket| bucket.full_at > now);
+
+	let capacity = MAX_CLIENTS / SHARDS;
+	let keep = capacity - capacity / EVICT_FRACTION;
+	if shard.len() > keep {
+		let mut updated: Vec<Instant> = shard.values().map(|bucket| bucket.updated).collect();
+		let (_, cutoff, _) = updated.select_nth_unstable(shard.len() - keep - 1);
+		let cutoff = *cutoff;
+		shard.retain(|_, bucket| bucket.updated > cutoff);
+	}
+}
+
+fn client_key(ip: IpAddr) -> IpAddr {
+	match ip.to_canonical() {
+		IpAddr::V6(ip) => IpAddr::V6(Ipv6Addr::from(u128::from(ip) & !u128::from(u64::MAX))),
+		ip => ip,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn ip(ip: &str) -> IpAddr {
+		ip.parse().unwrap()
+	}
+
+	#[test]
+	fn burst() {
+		let limiter = RateLimiter::new();
+		let limit = Limit::new(1.0, Some(3));
+		let now = Instant::now();
+
+		for _ in 0..3 {
+			assert_eq!(limiter.check_at(None, ip("192.0.2.1"), limit, now), Ok(()));
+		}
+		assert!(limiter.check_at(None, ip("192.0.2.1"), limit, now).is_err());
+
+		// Other clients have buckets of their own
+		assert_eq!(limiter.check_at(None, ip("192.0.2.2"), limit, now), Ok(()));
+	}
+
+	#[test]
+	fn default_burst() {
+		assert_eq!(Limit::new(0.5, None).burst, 1.0);
+		assert_eq!(Limit::new(2.5, None).burst, 3.0);
+		assert_eq!(Limit::new(2.5, Some(10)).burst, 10.0);
+	}
+
+	#[test]
+	fn retry_after() {
+		let limiter = RateLimiter::new();
+		let limit = Limit::new(2.0, Some(1));
+		let now = Instant::now();
+
+		assert_eq!(limiter.check_at(None, ip("192.0.2.1"), limit, now), Ok(()));
+		assert_eq!(
+			limiter.check_at(None, ip("192.0.2.1"), limit, now),
+			Err(Duration::from_millis(500))
+		);
+
+		// Half of the token is back after a quarter second
+		let retry = limiter
+			.check_at(
+				None,
+				ip("192.0.2.1"),
+				limit,
+				now + Duration::from_millis(250),
+			)
+			.unwrap_err();
+		assert!(retry.abs_diff(Duration::from_millis(250)) < Duration::from_millis(1));
+	}
+
+	#[test]
+	fn refill() {
+		let limiter = RateLimiter::new();
+		let limit = Limit::new(10.0, Some(5));
+		let now = Instant::now();
+
+		for _ in 0..5 {
+			assert_eq!(limiter.check_at(None, ip("192.0.2.1"), limit, now), Ok(()));
+		}
+		assert!(limiter.check_at(None, ip("192.0.2.1"), limit, now).is_err());
+
+		// Two tokens after 200 ms
+		let later = now + Duration::from_millis(200);
+		for _ in 0..2 {
+			assert_eq!(
+				limiter.check_at(None, ip("192.0.2.1"), limit, later),
+				Ok(())
+			);
+		}
+		assert!(limiter
+			.check_at(None, ip("192.0.2.1"), limit, later)
+			.is_err());
+
+		// Never more than the burst, however long the client was away
+		let much_later = now + Duration::from_secs(3600);
+		for _ in 0..5 {
+			assert_eq!(
+				limiter.check_at(None, ip("192.0.2.1"), limit, much_later),
+				Ok(())
+			);
+		}
+		assert!(limiter
+			.check_at(None, ip("192.0.2.1"), limit, much_later)
+			.is_err());
+	}
+
+	#[test]
+	fn ipv6_grouped_by_64() {
+		assert_eq!(client_key(ip("2001:db8::1")), ip("2001:db8::"));
+		assert_eq!(client_key(ip("2001:db8::ffff:1:2:3")), ip("2001:db8::"));
+		assert_eq!(client_key(ip("2001:db8:0:1::1")), ip("2001:db8:0:1::"));
+		assert_eq!(client_key(ip("::ffff:192.0.2.1")), ip("192.0.2.1"));
+		assert_eq!(client_key(ip("192.0.2.1")), ip("192.0.2.1"));
+
+		let limiter = RateLimiter::new();
+		let limit = Limit::new(1.0, Some(1));
+		let now = Instant::now();
+
+		assert_eq!(
+			limiter.check_at(None, ip("2001:db8::1"), limit, now),
+			Ok(())
+		);
+		assert!(limiter
+			.check_at(None, ip("2001:db8::2"), limit, now)
+			.is_err());
+		assert_eq!(
+			limiter.check_at(None, ip("2001:db8:0:1::1"), limit, now),
+			Ok(())
+		);
+	}
+
+	#[test]
+	fn listener_scope() {
+		let limiter = RateLimiter::new();
+		let limit = Limit::new(1.0, Some(1));
+		let listener: Arc<str> = Arc::from("[::]:443");
+		let now = Instant::now();
+
+		assert_eq!(limiter.check_at(None, ip("192.0.2.1"), limit, now), Ok(()));
+		assert_eq!(
+			limiter.check_at(Some(&listener), ip("192.0.2.1"), limit, now),
+			Ok(())
+		);
+		assert!(limiter
+			.check_at(Some(&listener), ip("192.0.2.1"), limit, now)
+			.is_err());
+	}
+
+	fn full_shard(now: Instant, idle: usize) -> Shard {
+		let mut shard = Shard::new();
+		let capacity = MAX_CLIENTS / SHARDS;
+		for i in 0..capacity {
+			// Seen longer ago the higher the index
+			let updated = now + Duration::from_secs((capacity - i) as u64);
+			let full_at = match i < idle {
+				true => now,
+				false => now + Duration::from_secs(60),
+			};
+			shard.insert(
+				(
+					Arc::from(""),
+					IpAddr::from([10, 0, (i >> 8) as u8, i as u8]),
+				),
+				Bucket {
+					tokens: 0.0,
+					updated,
+					full_at,
+				},
+			);
+		}
+		shard
+	}
+
+	#[test]
+	fn evict_least_recently_seen() {
+		let now = Instant::now();
+		let capacity = MAX_CLIENTS / SHARDS;
+		let mut shard = full_shard(now, 0);
+
+		evict(&mut shard, now);
+
+		// The most recently seen clients are kept, with a batch of free room
+		let keep = capacity - capacity / EVICT_FRACTION;
+		assert_eq!(shard.len(), keep);
+		assert!(shard.contains_key(&(Arc::from(""), IpAddr::from([10, 0, 0, 0]))));
+		let oldest = capacity - 1;
+		let oldest = IpAddr::from([10, 0, (oldest >> 8) as u8, oldest as u8]);
+		assert!(!shard.contains_key(&(Arc::from(""), oldest)));
+	}
+
+	#[test]
+	fn evict_idle_first() {
+		let now = Instant::now();
+		let capacity = MAX_CLIENTS / SHARDS;
+		let mut shard = full_shard(now, capacity / 2);
+
+		evict(&mut shard, now);
+
+		// Forgetting the idle clients made enough room, which spares the others
+		assert_eq!(shard.len(), capacity - capacity / 2);
+		assert!(shard.values().all(|bucket| bucket.full_at > now));
+	}
+
+	#[test]
+	fn evict_when_full() {
+		let limiter = RateLimiter::new();
+		let limit = Limit::new(1.0, Some(1));
+		let now = Instant::now();
+
+		for i in 0..MAX_CLIENTS as u32 * 2 {
+			let _ = limiter.check_at(None, IpAddr::from(i.to_be_bytes()), limit, now);
+		}
+
+		for shard in &limiter.shards {
+			assert!(shard.lock().unwrap().len() <= MAX_CLIENTS / SHARDS);
+		}
+	}
+
+	#[test]
+	fn invalid_rates() {
+		assert!(parse_rate("0").is_ok());
+		assert!(parse_rate("0.5").is_ok());
+		assert!(parse_rate("-1").is_err());
+		assert!(parse_rate("0.0001").is_err());
+		assert!(parse_rate("inf").is_err());
+		assert!(parse_rate("NaN").is_err());
+		assert!(parse_rate("fast").is_err());
+		assert!(check_burst(Some(0)).is_err());
+		assert!(check_burst(None).is_ok());
+	}
+}
@@ -0,0 +1,92 @@
+//! Plain TCP and UDP echo of the client address, for clients that cannot speak TLS or HTTP.
+
+use crate::listener::ServerState;
+use crate::metrics::{self, Labels};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::watch;
+use tokio::{select, time};
+
+/// Time a client gets to receive the address before the connection is dropped
+const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Writes the address of every client that connects, followed by a newline, and closes the
+/// connection, until the sender of `stop` is dropped.
+pub async fn serve_tcp(
+	listener: TcpListener,
+	mut stop: watch::Receiver<()>,
+	state: Arc<ServerState>,
+) {
+	let name = metrics::socket_name(listener.local_addr());
+
+	loop {
+		let (stream, peer_addr) = select! {
+			res = listener.accept() => match res {
+				Ok(conn) => conn,
+				Err(e) => {
+					debug!("Failed to accept raw TCP connection: {}", e);
+					time::sleep(Duration::from_millis(10)).await;
+					continue;
+				}
+			},
+			_ = stop.changed() => break,
+		};
+
+		let labels = Labels {
+			version: "raw-tcp",
+			..Labels::new(&name, peer_addr)
+		};
+		state.metrics.request(&labels, line(peer_addr).len() as u64);
+
+		tokio::spawn(reply_tcp(stream, peer_addr));
+	}
+}
+
+async fn reply_tcp(mut stream: TcpStream, peer_addr: SocketAddr) {
+	let reply = async {
+		stream.write_all(line(peer_addr).as_bytes()).await?;
+		stream.shutdown().await
+	};
+
+	if let Ok(Err(e)) = time::timeout(WRITE_TIMEOUT, reply).await {
+		debug!("Failed to reply to raw TCP client {}: {}", peer_addr, e);
+	}
+}
+
+/// Replies to every datagram with one containing the address of the sender, followed by a newline.
+pub async fn serve_udp(socket: UdpSocket, mut stop: watch::Receiver<()>, state: Arc<ServerState>) {
+	let mut buf = [0; 1500];
+	let listener = metrics::socket_name(socket.local_addr());
+
+	loop {
+		let peer_addr = select! {
+			res = socket.recv_from(&mut buf) => match res {
+				Ok((_, peer_addr)) => peer_addr,
+				Err(e) => {
+					debug!("Failed to receive raw UDP datagram: {}", e);
+					time::sleep(Duration::from_millis(10)).await;
+					continue;
+				}
+			},
+			_ = stop.changed() => break,
+		};
+
+		let line = line(peer_addr);
+		let labels = Labels {
+			version: "raw-udp",
+			..Labels::new(&listener, peer_addr)
+		};
+		state.metrics.request(&labels, line.len() as u64);
+
+		if let Err(e) = socket.send_to(line.as_bytes(), peer_addr).await {
+			debug!("Failed to reply to raw UDP client {}: {}", peer_addr, e);
+		}
+	}
+}
+
+fn line(peer_addr: SocketAddr) -> String {
+	format!("{}\n", peer_addr.ip().to_canonical())
+}
@@ -0,0 +1,558 @@
+//! Live configuration changes. On `SIGHUP` the configuration is read again, new
+//! listeners are opened, removed ones stop accepting and drain their connections,
+//! and the others pick up the changed settings for the connections they accept next.
+
+use crate::dns::{self, Zone};
+use crate::listener::{
+	self, ListenAddr, Listener, ListenerKind, ListenerSpec, ServerState, Settings,
+};
+use crate::upgrade::{self, Sockets};
+use crate::{http3, raw, stun};
+use anyhow::Result;
+use hyper::header::HeaderValue;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::mem;
+use std::net::SocketAddr;
+use std::os::fd::AsFd;
+use std::path::PathBuf;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use tokio::net::{TcpListener, UdpSocket};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+/// The configuration of everything that can be changed without a restart.
+pub struct Config {
+	pub specs: Vec<ListenerSpec>,
+	pub stun: Vec<SocketAddr>,
+	pub raw_tcp: Vec<SocketAddr>,
+	pub raw_udp: Vec<SocketAddr>,
+	pub dns: Vec<SocketAddr>,
+	pub dns_zone: Zone,
+	pub http3: bool,
+	/// Redirect plain HTTP requests to the first HTTPS listener
+	pub http_redirect: bool,
+	/// The redirect port is filled in once the listeners are known
+	pub settings: Settings,
+}
+
+/// A task serving a listener, which stops once `spec_tx` is dropped.
+struct ListenerTask {
+	addr: ListenAddr,
+	/// Passed by systemd, which owns the socket file
+	activated: bool,
+	/// Started as an admin listener, which is stopped last on shutdown
+	admin: bool,
+	spec_tx: watch::Sender<Arc<ListenerSpec>>,
+}
+
+impl ListenerTask {
+	/// The socket file to remove once the listener is closed, unless systemd owns it.
+	fn socket_file(&self) -> Option<&PathBuf> {
+		match &self.addr {
+			ListenAddr::Unix(path) if !self.activated => Some(path),
+			_ => None,
+		}
+	}
+}
+
+/// The tasks serving the current configuration, by the key of their socket.
+pub struct Running {
+	state: Arc<ServerState>,
+	sockets: Arc<Mutex<Sockets>>,
+	quic_config: quinn::ServerConfig,
+	listeners: HashMap<String, Vec<ListenerTask>>,
+	quic: HashMap<String, watch::Sender<Arc<ListenerSpec>>>,
+	services: HashMap<String, watch::Sender<()>>,
+	dns: HashMap<String, watch::Sender<Zone>>,
+	handles: Vec<JoinHandle<()>>,
+	admin_handles: Vec<JoinHandle<()>>,
+}
+
+/// The sockets for a configuration, bound before anything is changed so that a
+/// failure leaves the running configuration alone.
+#[derive(Default)]
+struct Plan {
+	keys: HashSet<String>,
+	/// Socket files of the Unix listeners that were bound
+	socket_files: Vec<PathBuf>,
+	listeners: Vec<PlannedListener>,
+	services: Vec<(String, SocketAddr, Option<Service>)>,
+}
+
+struct PlannedListener {
+	key: String,
+	/// With the address the socket is bound to
+	spec: ListenerSpec,
+	activated: bool,
+	/// `None` if the listener is already running
+	listener: Option<Listener>,
+	quic: Option<(String, SocketAddr, Option<quinn::Endpoint>)>,
+}
+
+enum Service {
+	Stun(UdpSocket),
+	RawTcp(TcpListener),
+	RawUdp(UdpSocket),
+	DnsUdp(UdpSocket),
+	DnsTcp(TcpListener),
+}
+
+impl Running {
+	pub fn new(
+		state: Arc<ServerState>,
+		sockets: Arc<Mutex<Sockets>>,
+		quic_config: quinn::ServerConfig,
+	) -> Self {
+		Running {
+			state,
+			sockets,
+			quic_config,
+			listeners: HashMap::new(),
+			quic: HashMap::new(),
+			services: HashMap::new(),
+			dns: HashMap::new(),
+			handles: Vec::new(),
+			admin_handles: Vec::new(),
+		}
+	}
+
+	/// Changes the running listeners and settings to the configuration. If any
+	/// socket cannot be bound, nothing is changed.
+	pub async fn apply(&mut self, config: Config) -> Result<()> {
+		let mut plan = Plan::default();
+
+		if let Err(e) = self.plan(&config, &mut plan).await {
+			self.abort(plan);
+			return Err(e);
+		}
+
+		self.commit(config, plan);
+		Ok(())
+	}
+
+	async fn plan(&self, config: &Config, plan: &mut Plan) -> Result<()> {
+		for spec in &config.specs {
+			let key = spec.addr.to_string();
+			if !plan.keys.insert(key.clone()) {
+				anyhow::bail!("{} is bound more than once", key);
+			}
+			let activated = matches!(spec.addr, ListenAddr::Systemd(_));
+
+			if let Some(tasks) = self.listeners.get(&key) {
+				for task in tasks {
+					plan.listeners.push(PlannedListener {
+						key: key.clone(),
+						spec: ListenerSpec {
+							addr: task.addr.clone(),
+							..spec.clone()
+						},
+						activated: task.activated,
+						listener: None,
+						quic: None,
+					});
+				}
+				continue;
+			}
+
+			let fds = self.sockets.lock().unwrap().take(&key);
+			if fds.is_empty() {
+				let listener = spec.bind().await.map_err(|e| bind_error(&key, e))?;
+				if let ListenAddr::Unix(path) = &spec.addr {
+					plan.socket_files.push(path.clone());
+				}
+				self.sockets
+					.lock()
+					.unwrap()
+					.register(&key, listener.as_fd())?;
+				plan.listeners.push(PlannedListener {
+					key,
+					spec: spec.clone(),
+					activated,
+					listener: Some(listener),
+					quic: None,
+				});
+				continue;
+			}
+
+			for fd in fds {
+				let (addr, listener) = fd.into_listener()?;
+				self.sockets
+					.lock()
+					.unwrap()
+					.register(&key, listener.as_fd())?;
+				plan.listeners.push(PlannedListener {
+					key: key.clone(),
+					spec: ListenerSpec {
+						addr,
+						..spec.clone()
+					},
+					activated,
+					listener: Some(listener),
+					quic: None,
+				});
+			}
+		}
+
+		// QUIC cannot carry a PROXY protocol header, so those listeners would echo the proxy.
+		// Sockets passed by systemd get no HTTP/3 either, since the UDP port would have to be
+		// bound without the privileges to do so.
+		for planned in &mut plan.listeners {
+			let addr = match planned.spec.addr.tcp() {
+				Some(addr) if config.http3 => addr,
+				_ => continue,
+			};
+			if planned.spec.kind != ListenerKind::Tls
+				|| planned.spec.proxy_protocol
+				|| planned.activated
+			{
+				continue;
+			}
+
+			let alt_svc = format!("h3=\":{}\"; ma=86400", addr.port());
+			planned.spec.alt_svc = Some(HeaderValue::from_str(&alt_svc)?);
+
+			let key = format!("quic:{}", addr);
+			if !plan.keys.insert(key.clone()) {
+				anyhow::bail!("{} is bound more than once", key);
+			}
+
+			let endpoint = match self.quic.contains_key(&key) {
+				true => None,
+				false => {
+					let socket = self
+						.sockets
+						.lock()
+						.unwrap()
+						.udp(&key, addr)
+						.map_err(|e| bind_error(&key, e))?;
+					Some(quinn::Endpoint::new(
+						quinn::EndpointConfig::default(),
+						Some(self.quic_config.clone()),
+						socket,
+						Arc::new(quinn::TokioRuntime),
+					)?)
+				}
+			};
+			planned.quic = Some((key, addr, endpoint));
+		}
+
+		for &addr in &config.stun {
+			self.plan_service(plan, format!("stun:{}", addr), addr, |sockets, key| {
+				Ok(Service::Stun(UdpSocket::from_std(sockets.udp(key, addr)?)?))
+			})?;
+		}
+		for &addr in &config.raw_tcp {
+			self.plan_service(plan, format!("raw-tcp:{}", addr), addr, |sockets, key| {
+				Ok(Service::RawTcp(sockets.tcp(key, addr)?))
+			})?;
+		}
+		for &addr in &config.raw_udp {
+			self.plan_service(plan, format!("raw-udp:{}", addr), addr, |sockets, key| {
+				Ok(Service::RawUdp(UdpSocket::from_std(
+					sockets.udp(key, addr)?,
+				)?))
+			})?;
+		}
+		for &addr in &config.dns {
+			self.plan_service(plan, format!("dns-udp:{}", addr), addr, |sockets, key| {
+				Ok(Service::DnsUdp(UdpSocket::from_std(
+					sockets.udp(key, addr)?,
+				)?))
+			})?;
+			self.plan_service(plan, format!("dns-tcp:{}", addr), addr, |sockets, key| {
+				Ok(Service::DnsTcp(sockets.tcp(key, addr)?))
+			})?;
+		}
+
+		Ok(())
+	}
+
+	fn plan_service(
+		&self,
+		plan: &mut Plan,
+		key: String,
+		addr: SocketAddr,
+		bind: impl FnOnce(&mut Sockets, &str) -> Result<Service>,
+	) -> Result<()> {
+		if !plan.keys.insert(key.clone()) {
+			anyhow::bail!("{} is bound more than once", key);
+		}
+
+		let service = match self.is_running(&key) {
+			true => None,
+			false => Some(
+				bind(&mut self.sockets.lock().unwrap(), &key).map_err(|e| bind_error(&key, e))?,
+			),
+		};
+		plan.services.push((key, addr, service));
+		Ok(())
+	}
+
+	/// Closes the sockets that were bound for a failed reload.
+	fn abort(&self, plan: Plan) {
+		let mut sockets = self.sockets.lock().unwrap();
+		for key in plan.keys.iter().filter(|key| !self.is_running(key)) {
+			sockets.unregister(key);
+		}
+
+		for path in plan.socket_files {
+			let _ = fs::remove_file(path);
+		}
+	}
+
+	fn is_running(&self, key: &str) -> bool {
+		self.listeners.contains_key(key)
+			|| self.quic.contains_key(key)
+			|| self.services.contains_key(key)
+			|| self.dns.contains_key(key)
+	}
+
+	fn commit(&mut self, config: Config, plan: Plan) {
+		// Port to redirect plain HTTP requests to, omitted from the URL if it is the default
+		let http_redirect_port = match config.http_redirect {
+			false => None,
+			true => Some(
+				plan.listeners
+					.iter()
+					.filter(|planned| planned.spec.kind == ListenerKind::Tls)
+					.find_map(|planned| planned.spec.addr.tcp())
+					.map_or(443, |addr| addr.port()),
+			),
+		};
+
+		// Connections accepted from here on use the new settings
+		*self.state.settings.write().unwrap() = Arc::new(Settings {
+			http_redirect_port,
+			..config.settings
+		});
+
+		let mut old_listeners = mem::take(&mut self.listeners);
+		let mut old_quic = mem::take(&mut self.quic);
+
+		for planned in plan.listeners {
+			let spec = Arc::new(planned.spec);
+
+			if let Some((key, addr, endpoint)) = planned.quic {
+				let quic_tx = match endpoint {
+					Some(endpoint) => {
+						info!(listener:% = addr; "Starting to serve HTTP/3 on https://{}", addr);
+						let (quic_tx, quic_rx) = watch::channel(spec.clone());
+						self.handles.push(tokio::spawn(http3::serve(
+							endpoint,
+							quic_rx,
+							self.state.clone(),
+						)));
+						quic_tx
+					}
+					None => {
+						let quic_tx = old_quic
+							.remove(&key)
+							.expect("planned for a running endpoint");
+						quic_tx.send_replace(spec.clone());
+						quic_tx
+					}
+				};
+				self.quic.insert(key, quic_tx);
+			}
+
+			let task = match planned.listener {
+				Some(listener) => {
+					log_listener(&spec, http_redirect_port.is_some());
+					let (spec_tx, spec_rx) = watch::channel(spec.clone());
+					let admin = spec.kind == ListenerKind::Admin;
+					let handle =
+						tokio::spawn(listener::serve(listener, spec_rx, self.state.clone()));
+					match admin {
+						true => self.admin_handles.push(handle),
+						false => self.handles.push(handle),
+					}
+					ListenerTask {
+						addr: spec.addr.clone(),
+						activated: planned.activated,
+						admin,
+						spec_tx,
+					}
+				}
+				None => {
+					let tasks = old_listeners
+						.get_mut(&planned.key)
+						.expect("planned for a running listener");
+					let task = tasks.remove(0);
+					task.spec_tx.send_replace(spec);
+					task
+				}
+			};
+			self.listeners.entry(planned.key).or_default().push(task);
+		}
+
+		let mut old_services = mem::take(&mut self.services);
+		let mut old_dns = mem::take(&mut self.dns);
+
+		for (key, addr, service) in plan.services {
+			let state = self.state.clone();
+
+			match service {
+				Some(Service::Stun(socket)) => {
+					info!(listener:% = addr; "Starting to serve STUN on {}", addr);
+					let (stop_tx, stop_rx) = watch::channel(());
+					self.handles
+						.push(tokio::spawn(stun::serve(socket, stop_rx, state)));
+					self.services.insert(key, stop_tx);
+				}
+				Some(Service::RawTcp(listener)) => {
+					info!(listener:% = addr; "Starting to serve raw TCP on {}", addr);
+					let (stop_tx, stop_rx) = watch::channel(());
+					self.handles
+						.push(tokio::spawn(raw::serve_tcp(listener, stop_rx, state)));
+					self.services.insert(key, stop_tx);
+				}
+				Some(Service::RawUdp(socket)) => {
+					info!(listener:% = addr; "Starting to serve raw UDP on {}", addr);
+					let (stop_tx, stop_rx) = watch::channel(());
+					self.handles
+						.push(tokio::spawn(raw::serve_udp(socket, stop_rx, state)));
+					self.services.insert(key, stop_tx);
+				}
+				Some(Service::DnsUdp(socket)) => {
+					info!(
+						listener:% = addr, zone:% = config.dns_zone;
+						"Starting to serve DNS for {} on {}",
+						config.dns_zone,
+						addr
+					);
+					let (zone_tx, zone_rx) = watch::channel(config.dns_zone.clone());
+					self.handles
+						.push(tokio::spawn(dns::serve_udp(socket, zone_rx, state)));
+					self.dns.insert(key, zone_tx);
+				}
+				Some(Service::DnsTcp(listener)) => {
+					let (zone_tx, zone_rx) = watch::channel(config.dns_zone.clone());
+					self.handles
+						.push(tokio::spawn(dns::serve_tcp(listener, zone_rx, state)));
+					self.dns.insert(key, zone_tx);
+				}
+				None => match old_dns.remove(&key) {
+					Some(zone_tx) => {
+						zone_tx.send_replace(config.dns_zone.clone());
+						self.dns.insert(key, zone_tx);
+					}
+					None => {
+						let stop_tx = old_services
+							.remove(&key)
+							.expect("planned for a running service");
+						self.services.insert(key, stop_tx);
+					}
+				},
+			}
+		}
+
+		// Dropping the senders stops the tasks, which finish their open connections
+		let mut sockets = self.sockets.lock().unwrap();
+		for (key, tasks) in old_listeners
+			.into_iter()
+			.filter(|(_, tasks)| !tasks.is_empty())
+		{
+			info!(listener = key.as_str(); "Closing listener {}", key);
+			sockets.unregister(&key);
+			for path in tasks.iter().filter_map(ListenerTask::socket_file) {
+				let _ = fs::remove_file(path);
+			}
+		}
+		for key in old_quic
+			.keys()
+			.chain(old_services.keys())
+			.chain(old_dns.keys())
+		{
+			info!(listener = key.as_str(); "Closing {}", key);
+			sockets.unregister(key);
+		}
+
+		self.handles.retain(|handle| !handle.is_finished());
+		self.admin_handles.retain(|handle| !handle.is_finished());
+	}
+
+	/// Stops all tasks and waits for their connections to finish. The admin listeners
+	/// keep serving until then, with `/readyz` reporting that the server is not ready.
+	pub async fn shutdown(mut self) -> Result<()> {
+		self.state.ready.store(false, Ordering::Relaxed);
+
+		let socket_files: Vec<PathBuf> = self
+			.listeners
+			.values()
+			.flatten()
+			.filter_map(ListenerTask::socket_file)
+			.cloned()
+			.collect();
+		let handles = mem::take(&mut self.handles);
+		let admin_handles = mem::take(&mut self.admin_handles);
+
+		// After an upgrade, the new process answers the admin requests
+		let admin_tasks: Vec<ListenerTask> = match upgrade::is_handed_over() {
+			true => Vec::new(),
+			false => self
+				.listeners
+				.drain()
+				.flat_map(|(_, tasks)| tasks)
+				.filter(|task| task.admin)
+				.collect(),
+		};
+
+		// Dropping the senders stops the tasks
+		drop(self);
+		for handle in handles {
+			handle.await?;
+		}
+
+		drop(admin_tasks);
+		for handle in admin_handles {
+			handle.await?;
+		}
+
+		// After an upgrade, the socket files belong to the new process
+		if !upgrade::is_handed_over() {
+			for path in socket_files {
+				let _ = fs::remove_file(path);
+			}
+		}
+
+		Ok(())
+	}
+}
+
+fn bind_error(key: &str, e: anyhow::Error) -> anyhow::Error {
+	anyhow::Error::msg(format!("failed to bind {}: {}", key, e))
+}
+
+fn log_listener(spec: &ListenerSpec, http_redirect: bool) {
+	let family = match &spec.addr {
+		ListenAddr::Tcp(addr) if addr.is_ipv4() => "IPv4",
+		ListenAddr::Tcp(_) => "IPv6",
+		ListenAddr::Unix(_) => "Unix socket",
+		ListenAddr::Systemd(_) => unreachable!("systemd sockets are resolved to their addresses"),
+	};
+
+	match spec.kind {
+		ListenerKind::AcmeHttp01 => info!(
+			listener:% = spec.addr;
+			"Starting to serve ACME HTTP-01 challenges on http://{}",
+			spec.addr
+		),
+		ListenerKind::Http if http_redirect => {
+			info!(listener:% = spec.addr; "Starting to redirect to HTTPS on http://{}", spec.addr)
+		}
+		ListenerKind::Admin => info!(
+			listener:% = spec.addr;
+			"Starting to serve the admin endpoints on http://{}",
+			spec.addr
+		),
+		_ => info!(
+			listener:% = spec.addr;
+			"Starting to serve {} on {}://{}",
+			family,
+			spec.scheme(),
+			spec.addr
+		),
+	}
+}
@@ -0,0 +1,52 @@
+use hyper::Method;
+use std::collections::HashMap;
+
+/// The endpoints served on the echo listeners.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Route {
+	/// The client address, as plain text or JSON depending on the request
+	Echo,
+	Ip,
+	Port,
+	Family,
+	Headers,
+	Tls,
+	Fingerprint,
+	H2Fingerprint,
+	Json,
+	Health,
+}
+
+/// Maps request paths to routes. It is built once at startup and shared by
+/// all connections, so a lookup only borrows the request path.
+pub struct Router {
+	routes: HashMap<&'static str, Route>,
+}
+
+impl Router {
+	pub fn new() -> Self {
+		let routes = HashMap::from([
+			("/", Route::Echo),
+			("/ip", Route::Ip),
+			("/port", Route::Port),
+			("/family", Route::Family),
+			("/headers", Route::Headers),
+			("/tls", Route::Tls),
+			("/fingerprint", Route::Fingerprint),
+			("/h2-fingerprint", Route::H2Fingerprint),
+			("/json", Route::Json),
+			("/health", Route::Health),
+		]);
+
+		Router { routes }
+	}
+
+	pub fn route(&self, path: &str) -> Option<Route> {
+		self.routes.get(path).copied()
+	}
+
+	/// Whether a route can be requested with `method`. Every endpoint is read-only.
+	pub fn allows(method: &Method) -> bool {
+		method == Method::GET || method == Method::HEAD
+	}
+}
@@ -0,0 +1,654 @@
+use crate::access_log::Entry;
+use crate::acme;
+use crate::admin;
+use crate::fingerprint::{self, Fingerprint};
+use crate::forwarded::{self, ClientAddr};
+use crate::h2_fingerprint::H2Fingerprint;
+use crate::listener::{ListenerKind, ListenerSpec, ServerState, Settings};
+use crate::metrics::Labels;
+use crate::rate_limit::Limit;
+use crate::router::{Route, Router};
+use crate::tls::TlsInfo;
+use hyper::body::HttpBody;
+use hyper::header::{HeaderValue, ACCEPT, ALLOW, CONTENT_TYPE, REFERER, RETRY_AFTER, USER_AGENT};
+use hyper::http::uri::Authority;
+use hyper::{Body, Method, Request, Response, StatusCode, Version};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+const TEXT_PLAIN: &str = "text/plain; charset=utf-8";
+
+/// Information about a client connection.
+pub struct ConnInfo {
+	/// Address of the client, taken from the PROXY protocol header if there was one
+	pub remote_addr: SocketAddr,
+	pub listener: Arc<ListenerSpec>,
+	/// Only set for connections on TLS listeners
+	pub tls: Option<TlsInfo>,
+	/// Fingerprints of the TLS ClientHello, if it could be parsed
+	pub fingerprint: Option<Fingerprint>,
+	/// Only set if HTTP/2 fingerprinting is enabled
+	pub h2_fingerprint: Option<H2Fingerprint>,
+	/// Settings at the time the connection was accepted
+	pub settings: Arc<Settings>,
+	/// Metric labels of the listener and the client address family
+	pub labels: Labels,
+}
+
+pub fn handle(state: &ServerState, conn: &ConnInfo, req: Request<Body>) -> Response<Body> {
+	let received = Instant::now();
+	let version = http_version(req.version());
+	let head = req.method() == Method::HEAD;
+
+	// Validation requests are let through, so that certificates are renewed under load too
+	let limited = match conn.listener.kind {
+		ListenerKind::Http if acme::is_http01_request(&req) => Ok(()),
+		ListenerKind::Tls | ListenerKind::Http => check_rate_limit(state, conn, &req),
+		ListenerKind::AcmeHttp01 | ListenerKind::Admin => Ok(()),
+	};
+
+	let response = match limited {
+		Err(retry_after) => too_many_requests_response(retry_after),
+		Ok(()) => match conn.listener.kind {
+			ListenerKind::Tls => route(state, conn, &req),
+			ListenerKind::Http | ListenerKind::AcmeHttp01 if acme::is_http01_request(&req) => {
+				acme::http01_response(&state.http01_tokens, &req)
+			}
+			ListenerKind::AcmeHttp01 => status_response(StatusCode::NOT_FOUND),
+			ListenerKind::Http => match conn.settings.http_redirect_port {
+				Some(port) => redirect_response(&req, port),
+				None => route(state, conn, &req),
+			},
+			ListenerKind::Admin => return admin::handle(state, &req),
+		},
+	};
+
+	// The body is generated in memory, so its size is known
+	let bytes = match head {
+		true => 0,
+		false => response.body().size_hint().exact().unwrap_or_default(),
+	};
+	let labels = conn.labels.with_version(version);
+	match limited {
+		Ok(()) => state
+			.metrics
+			.response(&labels, response.status().as_u16(), bytes),
+		Err(_) => state.metrics.rate_limited.inc(&labels),
+	}
+
+	if let Some(access_log) = &state.access_log {
+		let fingerprint = conn
+			.fingerprint
+			.as_ref()
+			.filter(|_| access_log.fingerprints());
+		let entry = access_log_entry(conn, &req, &response, bytes, received, fingerprint);
+		if !access_log.log(entry) {
+			state.metrics.access_log_dropped.inc(&Labels::default());
+		}
+	}
+
+	response
+}
+
+/// Takes a token from the bucket of the client, with the limit of the listener if it has
+/// its own, or returns the time until the client can try again.
+fn check_rate_limit(
+	state: &ServerState,
+	conn: &ConnInfo,
+	req: &Request<Body>,
+) -> Result<(), Duration> {
+	let (listener, limit) = match conn.listener.rate_limit {
+		Some(rate) => (
+			Some(&conn.labels.listener),
+			Limit::new(rate, conn.listener.rate_limit_burst),
+		),
+		None => (None, conn.settings.rate_limit),
+	};
+
+	if limit.rate == 0.0 {
+		return Ok(());
+	}
+
+	state
+		.rate_limiter
+		.check(listener, client_addr(conn, req).ip, limit)
+}
+
+fn too_many_requests_response(retry_after: Duration) -> Response<Body> {
+	// Whole seconds, rounded up so that the client does not come back too early
+	let retry_after = retry_after.as_secs_f64().ceil().max(1.0);
+
+	let mut response = status_response(StatusCode::TOO_MANY_REQUESTS);
+	response
+		.headers_mut()
+		.insert(RETRY_AFTER, HeaderValue::from(retry_after as u64));
+	response
+}
+
+fn access_log_entry(
+	conn: &ConnInfo,
+	req: &Request<Body>,
+	response: &Response<Body>,
+	bytes: u64,
+	received: Instant,
+	fingerprint: Option<&Fingerprint>,
+) -> Entry {
+	let header = |name| {
+		req.headers()
+			.get(name)
+			.map(|value| String::from_utf8_lossy(value.as_bytes()).into_owned())
+	};
+
+	Entry {
+		time: SystemTime::now(),
+		client: client_addr(conn, req).ip,
+		listener: conn.labels.listener.clone(),
+		method: req.method().clone(),
+		target: req
+			.uri()
+			.path_and_query()
+			.map_or_else(|| req.uri().path().to_string(), |target| target.to_string()),
+		version: req.version(),
+		tls_version: conn.tls.as_ref().map(|tls| tls.version),
+		status: response.status().as_u16(),
+		bytes,
+		latency: received.elapsed(),
+		referer: header(REFERER),
+		user_agent: header(USER_AGENT),
+		ja3: fingerprint.map(|fingerprint| fingerprint.ja3.clone()),
+		ja4: fingerprint.map(|fingerprint| fingerprint.ja4.clone()),
+	}
+}
+
+fn route(state: &ServerState, conn: &ConnInfo, req: &Request<Body>) -> Response<Body> {
+	let route = match state.router.route(req.uri().path()) {
+		Some(route) => route,
+		None => return status_response(StatusCode::NOT_FOUND),
+	};
+
+	if !Router::allows(req.method()) {
+		let mut response = status_response(StatusCode::METHOD_NOT_ALLOWED);
+		response
+			.headers_mut()
+			.insert(ALLOW, HeaderValue::from_static("GET, HEAD"));
+		return response;
+	}
+
+	let format = response_format(req, conn.listener.format);
+
+	match route {
+		Route::Echo => echo_response(conn, req, format),
+		Route::Json => echo_response(conn, req, ResponseFormat::Json),
+		Route::Ip => text_response(client_addr(conn, req).ip.to_string()),
+		Route::Port => match client_addr(conn, req).port {
+			Some(port) => text_response(port.to_string()),
+			None => text_response("unknown"),
+		},
+		Route::Family => text_response(family(client_addr(conn, req).ip.to_canonical())),
+		Route::Headers => headers_response(req, format),
+		Route::Tls => match &conn.tls {
+			Some(tls) => tls_response(tls, format),
+			None => status_response(StatusCode::NOT_FOUND),
+		},
+		Route::Fingerprint => match &conn.fingerprint {
+			Some(fingerprint) => fingerprint_response(fingerprint, format),
+			None => status_response(StatusCode::NOT_FOUND),
+		},
+		Route::H2Fingerprint => match conn.h2_fingerprint.as_ref().and_then(|fp| fp.get()) {
+			Some(fingerprint) => h2_fingerprint_response(fingerprint, format),
+			None => status_response(StatusCode::NOT_FOUND),
+		},
+		Route::Health => text_response("ok"),
+	}
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ResponseFormat {
+	/// Just the IP address, as expected by the `wut` CLI
+	Text,
+	Json,
+}
+
+#[derive(Serialize)]
+struct EchoJson {
+	ip: IpAddr,
+	family: &'static str,
+	port: Option<u16>,
+	proto: &'static str,
+}
+
+fn echo_response(conn: &ConnInfo, req: &Request<Body>, format: ResponseFormat) -> Response<Body> {
+	let client = client_addr(conn, req);
+
+	match format {
+		ResponseFormat::Text => text_response(client.ip.to_string()),
+		ResponseFormat::Json => json_response(&EchoJson {
+			ip: client.ip,
+			family: family(client.ip.to_canonical()),
+			port: client.port,
+			proto: http_version(req.version()),
+		}),
+	}
+}
+
+/// Lists the request headers, one `name: value` per line. Values that are
+/// not valid UTF-8 are shown lossily.
+fn headers_response(req: &Request<Body>, format: ResponseFormat) -> Response<Body> {
+	let headers = req.headers();
+
+	match format {
+		ResponseFormat::Text => {
+			let mut body = String::new();
+			for (name, value) in headers {
+				body.push_str(name.as_str());
+				body.push_str(": ");
+				body.push_str(&String::from_utf8_lossy(value.as_bytes()));
+				body.push('\n');
+			}
+			text_response(body)
+		}
+		ResponseFormat::Json => {
+			let mut map: BTreeMap<&str, Vec<String>> = BTreeMap::new();
+			for (name, value) in headers {
+				map.entry(name.as_str())
+					.or_default()
+					.push(String::from_utf8_lossy(value.as_bytes()).into_owned());
+			}
+			json_response(&map)
+		}
+	}
+}
+
+fn tls_response(tls: &TlsInfo, format: ResponseFormat) -> Response<Body> {
+	match format {
+		ResponseFormat::Text => text_response(format!(
+			"version: {}\ncipher: {}\nalpn: {}\nsni: {}\nresumed: {}\n",
+			tls.version,
+			tls.cipher,
+			tls.alpn.as_deref().unwrap_or("none"),
+			tls.sni.as_deref().unwrap_or("none"),
+			match tls.resumed {
+				Some(true) => "yes",
+				Some(false) => "no",
+				None => "unknown",
+			},
+		)),
+		ResponseFormat::Json => json_response(tls),
+	}
+}
+
+fn fingerprint_response(fingerprint: &Fingerprint, format: ResponseFormat) -> Response<Body> {
+	match format {
+		ResponseFormat::Text => text_response(format!(
+			"ja3: {}\nja3_hash: {}\nja4: {}\n",
+			fingerprint.ja3, fingerprint.ja3_hash, fingerprint.ja4,
+		)),
+		ResponseFormat::Json => json_response(fingerprint),
+	}
+}
+
+#[derive(Serialize)]
+struct H2FingerprintJson<'a> {
+	akamai: &'a str,
+	akamai_hash: String,
+}
+
+fn h2_fingerprint_response(fingerprint: &str, format: ResponseFormat) -> Response<Body> {
+	match format {
+		ResponseFormat::Text => text_response(fingerprint.to_string()),
+		ResponseFormat::Json => json_response(&H2FingerprintJson {
+			akamai: fingerprint,
+			akamai_hash: fingerprint::md5_hex(fingerprint),
+		}),
+	}
+}
+
+pub fn text_response(body: impl Into<Body>) -> Response<Body> {
+	Response::builder()
+		.header(CONTENT_TYPE, TEXT_PLAIN)
+		.body(body.into())
+		.unwrap()
+}
+
+pub fn json_response<T: Serialize>(value: &T) -> Response<Body> {
+	Response::builder()
+		.header(CONTENT_TYPE, "application/json")
+		.body(Body::from(serde_json::to_string(value).unwrap()))
+		.unwrap()
+}
+
+fn family(ip: IpAddr) -> &'static str {
+	match ip {
+		IpAddr::V4(_) => "v4",
+		IpAddr::V6(_) => "v6",
+	}
+}
+
+/// Selects the response format from the `format` query parameter or the `Accept`
+/// header, or else the default of the listener.
+fn response_format(req: &Request<Body>, default: ResponseFormat) -> ResponseFormat {
+	let query_format = req.uri().query().and_then(|query| {
+		query
+			.split('&')
+			.find_map(|param| param.strip_prefix("format="))
+	});
+
+	match query_format {
+		Some("json") => return ResponseFormat::Json,
+		Some("text") => return ResponseFormat::Text,
+		_ => {}
+	}
+
+	req.headers()
+		.get(ACCEPT)
+		.and_then(|v| v.to_str().ok())
+		.and_then(accepted_format)
+		.unwrap_or(default)
+}
+
+/// The format an `Accept` header prefers, JSON or plain text. Both are ranked by
+/// quality first and then by how specific the matching media range is, and there
+/// is no preference on ties.
+fn accepted_format(accept: &str) -> Option<ResponseFormat> {
+	let mut json = (0.0, 0);
+	let mut text = (0.0, 0);
+
+	for range in accept.split(',') {
+		let mut params = range.split(';');
+		let media_type = params.next().unwrap_or_default().trim();
+
+		let quality = params
+			.filter_map(|p| p.trim().strip_prefix("q="))
+			.find_map(|q| q.parse::<f32>().ok())
+			.unwrap_or(1.0);
+
+		let (json_specificity, text_specificity) = match media_type {
+			"application/json" => (2, -1),
+			"application/*" => (1, -1),
+			"text/plain" => (-1, 2),
+			"text/*" => (-1, 1),
+			"*/*" => (0, 0),
+			_ => (-1, -1),
+		};
+
+		if json_specificity > json.1 || json_specificity == json.1 && quality > json.0 {
+			json = (quality, json_specificity);
+		}
+		if text_specificity > text.1 || text_specificity == text.1 && quality > text.0 {
+			text = (quality, text_specificity);
+		}
+	}
+
+	if json.0 > 0.0 && (json.0 > text.0 || json.0 == text.0 && json.1 > text.1) {
+		Some(ResponseFormat::Json)
+	} else if text.0 > 0.0 && (text.0 > json.0 || text.0 == json.0 && text.1 > json.1) {
+		Some(ResponseFormat::Text)
+	} else {
+		None
+	}
+}
+
+fn http_version(version: Version) -> &'static str {
+	match version {
+		Version::HTTP_09 => "http/0.9",
+		Version::HTTP_10 => "http/1.0",
+		Version::HTTP_11 => "http/1.1",
+		Version::HTTP_2 => "h2",
+		Version::HTTP_3 => "h3",
+		_ => "unknown",
+	}
+}
+
+/// The address of the client, taking trusted proxy headers into account.
+fn client_addr(conn: &ConnInfo, req: &Request<Body>) -> ClientAddr {
+	// The reverse proxy in front of a Unix socket is trusted, unless it sends a PROXY protocol header instead
+	if conn.listener.addr.is_unix() && !conn.listener.proxy_protocol {
+		return forwarded::forwarded_client_addr(
+			req.headers(),
+			conn.remote_addr,
+			&conn.settings.trusted_proxies,
+		);
+	}
+
+	if conn.settings.trusted_proxies.is_empty() {
+		return conn.remote_addr.into();
+	}
+
+	forwarded::client_addr(
+		req.headers(),
+		conn.remote_addr,
+		&conn.settings.trusted_proxies,
+	)
+}
+
+pub fn status_response(status: StatusCode) -> Response<Body> {
+	let mut response = Response::new(Body::empty());
+	*response.status_mut() = status;
+	response
+}
+
+/// Redirects a plain HTTP request to the same host and path over HTTPS.
+fn redirect_response(req: &Request<Body>, https_port: u16) -> Response<Body> {
+	let host = req
+		.headers()
+		.get(hyper::header::HOST)
+		.and_then(|host| host.to_str().ok())
+		.or_else(|| req.uri().host())
+		.and_then(|host| host.parse::<Authority>().ok());
+
+	let path = req
+		.uri()
+		.path_and_query()
+		.map(|p| p.as_str())
+		.unwrap_or("/");
+
+	let location = match (host, https_port) {
+		(None, _) => {
+			let mut response = Response::new(Body::from("missing host"));
+			*response.status_mut() = StatusCode::BAD_REQUEST;
+			return response;
+		}
+		(Some(host), 443) => format!("https://{}{}", host.host(), path),
+		(Some(host), port) => format!("https://{}:{}{}", host.host(), port, path),
+	};
+
+	Response::builder()
+		.status(StatusCode::PERMANENT_REDIRECT)
+		.header(hyper::header::LOCATION, location)
+		.body(Body::empty())
+		.unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::listener::ListenerKind;
+	use crate::metrics::Metrics;
+	use crate::rate_limit::RateLimiter;
+	use std::sync::atomic::AtomicBool;
+
+	fn state() -> ServerState {
+		let tls_config = rustls::ServerConfig::builder()
+			.with_safe_defaults()
+			.with_no_client_auth()
+			.with_cert_resolver(Arc::new(rustls::server::ResolvesServerCertUsingSni::new()));
+
+		ServerState {
+			tls_acceptor: Arc::new(tls_config).into(),
+			settings: Default::default(),
+			metrics: Metrics::new(),
+			ready: AtomicBool::new(true),
+			access_log: None,
+			rate_limiter: RateLimiter::new(),
+			http01_tokens: Default::default(),
+			router: Router::new(),
+		}
+	}
+
+	fn conn(bind: &str) -> ConnInfo {
+		ConnInfo {
+			remote_addr: "192.0.2.1:4000".parse().unwrap(),
+			listener: Arc::new(ListenerSpec::parse(bind, 443, ListenerKind::Tls).unwrap()),
+			tls: None,
+			fingerprint: None,
+			h2_fingerprint: None,
+			settings: Default::default(),
+			labels: Labels::default(),
+		}
+	}
+
+	fn request(method: Method, uri: &str, accept: Option<&str>) -> Request<Body> {
+		let mut req = Request::builder().method(method).uri(uri);
+		if let Some(accept) = accept {
+			req = req.header(ACCEPT, accept);
+		}
+		req.body(Body::empty()).unwrap()
+	}
+
+	fn format(uri: &str, accept: Option<&str>, default: ResponseFormat) -> ResponseFormat {
+		response_format(&request(Method::GET, uri, accept), default)
+	}
+
+	async fn body(response: Response<Body>) -> String {
+		let bytes = hyper::body::to_bytes(response.into_body()).await.unwrap();
+		String::from_utf8(bytes.to_vec()).unwrap()
+	}
+
+	#[test]
+	fn accept_quality() {
+		let json = Some(ResponseFormat::Json);
+		let text = Some(ResponseFormat::Text);
+
+		assert_eq!(accepted_format("application/json"), json);
+		assert_eq!(accepted_format("text/plain;q=0.5, application/json"), json);
+		assert_eq!(
+			accepted_format("application/json;q=0.8, text/plain;q=0.9"),
+			text
+		);
+		assert_eq!(
+			accepted_format("application/json; charset=utf-8; q=0.5"),
+			json
+		);
+
+		// Wildcards prefer neither, unless one format is more specific
+		assert_eq!(accepted_format("*/*"), None);
+		assert_eq!(accepted_format("text/*, application/json"), json);
+		assert_eq!(accepted_format("application/*, text/plain"), text);
+		assert_eq!(
+			accepted_format("text/html,application/xhtml+xml,*/*;q=0.8"),
+			None
+		);
+
+		// q=0 rules a format out, even if a wildcard would allow it
+		assert_eq!(accepted_format("application/json;q=0"), None);
+		assert_eq!(accepted_format("application/json;q=0, */*"), text);
+		assert_eq!(accepted_format("text/plain;q=0, */*"), json);
+
+		assert_eq!(accepted_format(""), None);
+		assert_eq!(accepted_format("image/png"), None);
+		assert_eq!(accepted_format("application/json;q=abc"), json);
+	}
+
+	#[test]
+	fn format_query() {
+		let (json, text) = (ResponseFormat::Json, ResponseFormat::Text);
+
+		// The query parameter overrides the Accept header
+		assert_eq!(
+			format("/?format=text", Some("application/json"), json),
+			text
+		);
+		assert_eq!(format("/?format=json", Some("text/plain"), text), json);
+		assert_eq!(format("/?a=1&format=json", None, text), json);
+
+		// Unknown formats are ignored
+		assert_eq!(format("/?format=xml", Some("application/json"), text), json);
+		assert_eq!(format("/?format=xml", None, text), text);
+	}
+
+	#[test]
+	fn format_default() {
+		let (json, text) = (ResponseFormat::Json, ResponseFormat::Text);
+
+		// The default of the listener is used when the request has no preference
+		assert_eq!(format("/", None, json), json);
+		assert_eq!(format("/", Some("*/*"), json), json);
+		assert_eq!(format("/", Some("*/*"), text), text);
+		assert_eq!(format("/", Some("text/plain"), json), text);
+		assert_eq!(format("/", Some("application/json"), text), json);
+	}
+
+	#[tokio::test]
+	async fn echo_formats() {
+		let state = state();
+
+		let text = conn("127.0.0.1:443");
+		let response = handle(&state, &text, request(Method::GET, "/", None));
+		assert_eq!(response.status(), StatusCode::OK);
+		assert_eq!(body(response).await, "192.0.2.1");
+
+		let response = handle(
+			&state,
+			&text,
+			request(Method::GET, "/", Some("application/json")),
+		);
+		assert_eq!(
+			body(response).await,
+			r#"{"ip":"192.0.2.1","family":"v4","port":4000,"proto":"http/1.1"}"#
+		);
+
+		// A listener with format=json, which text/plain still overrides
+		let json = conn("127.0.0.1:443,format=json");
+		let response = handle(&state, &json, request(Method::GET, "/", None));
+		assert_eq!(response.headers()[CONTENT_TYPE], "application/json");
+		let response = handle(&state, &json, request(Method::GET, "/", Some("text/plain")));
+		assert_eq!(body(response).await, "192.0.2.1");
+
+		// /ip is always plain text and /json always JSON
+		let response = handle(&state, &json, request(Method::GET, "/ip", None));
+		assert_eq!(body(response).await, "192.0.2.1");
+		let response = handle(
+			&state,
+			&text,
+			request(Method::GET, "/json", Some("text/plain")),
+		);
+		assert_eq!(response.headers()[CONTENT_TYPE], "application/json");
+	}
+
+	#[test]
+	fn unknown_paths_and_methods() {
+		let state = state();
+		let conn = conn("127.0.0.1:443");
+
+		let response = handle(&state, &conn, request(Method::GET, "/nope", None));
+		assert_eq!(response.status(), StatusCode::NOT_FOUND);
+		let response = handle(&state, &conn, request(Method::GET, "/ip/", None));
+		assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+		for method in [Method::POST, Method::PUT, Method::DELETE, Method::OPTIONS] {
+			let response = handle(&state, &conn, request(method, "/", None));
+			assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+			assert_eq!(response.headers()[ALLOW], "GET, HEAD");
+		}
+
+		// Unknown paths are not found for any method
+		let response = handle(&state, &conn, request(Method::POST, "/nope", None));
+		assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+		let response = handle(&state, &conn, request(Method::HEAD, "/", None));
+		assert_eq!(response.status(), StatusCode::OK);
+	}
+
+	#[test]
+	fn ipv4_mapped_family() {
+		assert_eq!(family("192.0.2.1".parse().unwrap()), "v4");
+		assert_eq!(family("2001:db8::1".parse().unwrap()), "v6");
+
+		let mapped: IpAddr = "::ffff:192.0.2.1".parse().unwrap();
+		assert_eq!(family(mapped.to_canonical()), "v4");
+	}
+}
@@ -0,0 +1,226 @@
+//! A STUN (RFC 8489) server that answers Binding requests with the reflexive
+//! transport address of the client.
+
+use crate::listener::ServerState;
+use crate::metrics::{self, Labels};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::sync::watch;
+use tokio::{select, time};
+
+const HEADER_LEN: usize = 20;
+const MAGIC_COOKIE: u32 = 0x2112_a442;
+
+const BINDING_REQUEST: u16 = 0x0001;
+const BINDING_SUCCESS: u16 = 0x0101;
+
+const ATTR_XOR_MAPPED_ADDRESS: u16 = 0x0020;
+
+const FAMILY_IPV4: u8 = 0x01;
+const FAMILY_IPV6: u8 = 0x02;
+
+/// Answers Binding requests until the sender of `stop` is dropped.
+pub async fn serve(socket: UdpSocket, mut stop: watch::Receiver<()>, state: Arc<ServerState>) {
+	let mut buf = [0; 1500];
+	let listener = metrics::socket_name(socket.local_addr());
+
+	loop {
+		let (len, peer_addr) = select! {
+			res = socket.recv_from(&mut buf) => match res {
+				Ok(res) => res,
+				Err(e) => {
+					// ICMP errors from earlier responses show up here on some platforms
+					debug!("Failed to receive STUN request: {}", e);
+					time::sleep(Duration::from_millis(10)).await;
+					continue;
+				}
+			},
+			_ = stop.changed() => break,
+		};
+
+		let response = match binding_response(&buf[..len], peer_addr) {
+			Some(response) => response,
+			None => continue,
+		};
+
+		let labels = Labels {
+			version: "stun",
+			..Labels::new(&listener, peer_addr)
+		};
+		state.metrics.request(&labels, response.len() as u64);
+
+		if let Err(e) = socket.send_to(&response, peer_addr).await {
+			debug!("Failed to send STUN response to {}: {}", peer_addr, e);
+		}
+	}
+}
+
+/// Builds the success response to a Binding request, or returns `None` for
+/// anything else, which is silently dropped.
+fn binding_response(request: &[u8], peer_addr: SocketAddr) -> Option<Vec<u8>> {
+	let header = request.get(..HEADER_LEN)?;
+
+	let message_type = u16::from_be_bytes([header[0], header[1]]);
+	let len = u16::from_be_bytes([header[2], header[3]]) as usize;
+	if message_type != BINDING_REQUEST
+		|| !len.is_multiple_of(4)
+		|| request.len() != HEADER_LEN + len
+	{
+		return None;
+	}
+
+	// Without the magic cookie this is not a STUN message, or one of an RFC 3489 client
+	if u32::from_be_bytes([header[4], header[5], header[6], header[7]]) != MAGIC_COOKIE {
+		return None;
+	}
+	// The magic cookie followed by the transaction ID, which is what addresses are XORed with
+	let xor_key = &header[4..HEADER_LEN];
+
+	let ip = peer_addr.ip().to_canonical();
+	let port = peer_addr.port();
+
+	let mut value = vec![0, 0];
+	value.extend_from_slice(&(port ^ (MAGIC_COOKIE >> 16) as u16).to_be_bytes());
+
+	let address = match ip {
+		IpAddr::V4(ip) => {
+			value[1] = FAMILY_IPV4;
+			ip.octets().to_vec()
+		}
+		IpAddr::V6(ip) => {
+			value[1] = FAMILY_IPV6;
+			ip.octets().to_vec()
+		}
+	};
+	value.extend(address.iter().zip(xor_key).map(|(a, k)| a ^ k));
+
+	let mut response = Vec::with_capacity(HEADER_LEN + 4 + value.len());
+	response.extend_from_slice(&BINDING_SUCCESS.to_be_bytes());
+	response.extend_from_slice(&(4 + value.len() as u16).to_be_bytes());
+	// Echo the magic cookie and transaction ID
+	response.extend_from_slice(&header[4..HEADER_LEN]);
+	response.extend_from_slice(&ATTR_XOR_MAPPED_ADDRESS.to_be_bytes());
+	response.extend_from_slice(&(value.len() as u16).to_be_bytes());
+	response.extend_from_slice(&value);
+
+	Some(response)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const TRANSACTION_ID: [u8; 12] = [
+		0xb7, 0xe7, 0xa7, 0x01, 0xbc, 0x34, 0xd6, 0x86, 0xfa, 0x87, 0xdf, 0xae,
+	];
+
+	/// The sample request of RFC 5769 section 2.1, with the SOFTWARE, PRIORITY,
+	/// ICE-CONTROLLED, USERNAME, MESSAGE-INTEGRITY and FINGERPRINT attributes.
+	const SAMPLE_REQUEST: [u8; 108] = [
+		0x00, 0x01, 0x00, 0x58, 0x21, 0x12, 0xa4, 0x42, 0xb7, 0xe7, 0xa7, 0x01, 0xbc, 0x34, 0xd6,
+		0x86, 0xfa, 0x87, 0xdf, 0xae, 0x80, 0x22, 0x00, 0x10, 0x53, 0x54, 0x55, 0x4e, 0x20, 0x74,
+		0x65, 0x73, 0x74, 0x20, 0x63, 0x6c, 0x69, 0x65, 0x6e, 0x74, 0x00, 0x24, 0x00, 0x04, 0x6e,
+		0x00, 0x01, 0xff, 0x80, 0x29, 0x00, 0x08, 0x93, 0x2f, 0xf9, 0xb1, 0x51, 0x26, 0x3b, 0x36,
+		0x00, 0x06, 0x00, 0x09, 0x65, 0x76, 0x74, 0x6a, 0x3a, 0x68, 0x36, 0x76, 0x59, 0x20, 0x20,
+		0x20, 0x00, 0x08, 0x00, 0x14, 0x9a, 0xea, 0xa7, 0x0c, 0xbf, 0xd8, 0xcb, 0x56, 0x78, 0x1e,
+		0xf2, 0xb5, 0xb2, 0xd3, 0xf2, 0x49, 0xc1, 0xb5, 0x71, 0xa2, 0x80, 0x28, 0x00, 0x04, 0xe5,
+		0x7a, 0x3b, 0xcf,
+	];
+
+	/// A response with just the XOR-MAPPED-ADDRESS attribute.
+	fn response(attribute: &[u8]) -> Vec<u8> {
+		let mut response = BINDING_SUCCESS.to_be_bytes().to_vec();
+		response.extend_from_slice(&(attribute.len() as u16).to_be_bytes());
+		response.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+		response.extend_from_slice(&TRANSACTION_ID);
+		response.extend_from_slice(attribute);
+		response
+	}
+
+	#[test]
+	fn rfc5769_ipv4() {
+		// The XOR-MAPPED-ADDRESS of the sample IPv4 response in section 2.2
+		let expected = response(&[
+			0x00, 0x20, 0x00, 0x08, 0x00, 0x01, 0xa1, 0x47, 0xe1, 0x12, 0xa6, 0x43,
+		]);
+		let peer = "192.0.2.1:32853".parse().unwrap();
+		assert_eq!(
+			binding_response(&SAMPLE_REQUEST, peer),
+			Some(expected.clone())
+		);
+
+		// Dual-stack sockets see IPv4 clients as IPv4-mapped addresses
+		let peer = "[::ffff:192.0.2.1]:32853".parse().unwrap();
+		assert_eq!(binding_response(&SAMPLE_REQUEST, peer), Some(expected));
+	}
+
+	#[test]
+	fn rfc5769_ipv6() {
+		// The XOR-MAPPED-ADDRESS of the sample IPv6 response in section 2.3
+		let expected = response(&[
+			0x00, 0x20, 0x00, 0x14, 0x00, 0x02, 0xa1, 0x47, 0x01, 0x13, 0xa9, 0xfa, 0xa5, 0xd3,
+			0xf1, 0x79, 0xbc, 0x25, 0xf4, 0xb5, 0xbe, 0xd2, 0xb9, 0xd9,
+		]);
+		let peer = "[2001:db8:1234:5678:11:2233:4455:6677]:32853"
+			.parse()
+			.unwrap();
+		assert_eq!(binding_response(&SAMPLE_REQUEST, peer), Some(expected));
+	}
+
+	#[test]
+	fn request_without_attributes() {
+		let mut request = SAMPLE_REQUEST[..HEADER_LEN].to_vec();
+		request[3] = 0;
+		let expected = response(&[
+			0x00, 0x20, 0x00, 0x08, 0x00, 0x01, 0xa1, 0x47, 0xe1, 0x12, 0xa6, 0x43,
+		]);
+		let peer = "192.0.2.1:32853".parse().unwrap();
+		assert_eq!(binding_response(&request, peer), Some(expected));
+	}
+
+	#[test]
+	fn bad_magic_cookie() {
+		let peer = "192.0.2.1:32853".parse().unwrap();
+		for i in 4..8 {
+			let mut request = SAMPLE_REQUEST;
+			request[i] ^= 0x01;
+			assert_eq!(binding_response(&request, peer), None);
+		}
+	}
+
+	#[test]
+	fn truncated() {
+		let peer = "192.0.2.1:32853".parse().unwrap();
+		for len in 0..SAMPLE_REQUEST.len() {
+			assert_eq!(
+				binding_response(&SAMPLE_REQUEST[..len], peer),
+				None,
+				"{}",
+				len
+			);
+		}
+
+		// Trailing data after the attributes
+		let mut request = SAMPLE_REQUEST.to_vec();
+		request.extend_from_slice(&[0; 4]);
+		assert_eq!(binding_response(&request, peer), None);
+
+		// Lengths must be a multiple of 4
+		let mut request = SAMPLE_REQUEST[..SAMPLE_REQUEST.len() - 2].to_vec();
+		request[3] -= 2;
+		assert_eq!(binding_response(&request, peer), None);
+	}
+
+	#[test]
+	fn other_messages() {
+		let peer = "192.0.2.1:32853".parse().unwrap();
+		// Binding indication, success and error responses
+		for message_type in [0x0011u16, 0x0101, 0x0111, 0x0003] {
+			let mut request = SAMPLE_REQUEST;
+			request[..2].copy_from_slice(&message_type.to_be_bytes());
+			assert_eq!(binding_response(&request, peer), None);
+		}
+	}
+}
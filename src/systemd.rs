@@ -0,0 +1,152 @@
+//! systemd socket activation and service notifications, as described in
+//! sd_listen_fds(3) and sd_notify(3).
+
+use crate::listener::{ListenAddr, Listener};
+use anyhow::Result;
+use nix::fcntl::{fcntl, FcntlArg, FdFlag};
+use nix::sys::socket::{getsockname, getsockopt, sockopt, SockType, SockaddrStorage};
+use std::env;
+use std::ffi::OsStr;
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::net::{SocketAddr as UnixSocketAddr, UnixDatagram};
+use std::process;
+use std::time::Duration;
+use tokio::net::{TcpListener, UnixListener};
+use tokio::time;
+
+/// The first file descriptor passed by systemd, following stdin, stdout and stderr
+pub const LISTEN_FDS_START: RawFd = 3;
+
+/// An inherited socket, along with its `FileDescriptorName=` if it was passed by systemd.
+pub struct ListenFd {
+	pub name: String,
+	pub fd: OwnedFd,
+}
+
+impl ListenFd {
+	/// Converts the socket to a listener, and returns it with the address it is bound to.
+	pub fn into_listener(self) -> Result<(ListenAddr, Listener)> {
+		let listening = getsockopt(&self.fd, sockopt::SockType)? == SockType::Stream
+			&& getsockopt(&self.fd, sockopt::AcceptConn)?;
+		if !listening {
+			anyhow::bail!(
+				"inherited socket {} is not a listening stream socket",
+				self.name
+			);
+		}
+
+		let addr: SockaddrStorage = getsockname(self.fd.as_raw_fd())?;
+
+		if let Some(unix_addr) = addr.as_unix_addr() {
+			let path = match unix_addr.path() {
+				Some(path) => path.to_path_buf(),
+				None => anyhow::bail!("inherited socket {} has no path", self.name),
+			};
+			let listener = std::os::unix::net::UnixListener::from(self.fd);
+			listener.set_nonblocking(true)?;
+			return Ok((
+				ListenAddr::Unix(path),
+				Listener::Unix(UnixListener::from_std(listener)?),
+			));
+		}
+
+		let listener = std::net::TcpListener::from(self.fd);
+		listener.set_nonblocking(true)?;
+		Ok((
+			ListenAddr::Tcp(listener.local_addr()?),
+			Listener::Tcp(TcpListener::from_std(listener)?),
+		))
+	}
+}
+
+/// Takes the sockets passed with `LISTEN_FDS`. This modifies the environment, so it
+/// must be called before any threads are started.
+pub fn listen_fds() -> Result<Vec<ListenFd>> {
+	let pid = env::var("LISTEN_PID").ok();
+	let count = env::var("LISTEN_FDS").ok();
+	let names = env::var("LISTEN_FDNAMES").ok();
+
+	// Child processes must not mistake the sockets for their own
+	for var in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+		env::remove_var(var);
+	}
+
+	// The variables were meant for a parent process if the PID does not match
+	if pid.and_then(|pid| pid.parse().ok()) != Some(process::id()) {
+		return Ok(Vec::new());
+	}
+
+	let count: RawFd = match count.and_then(|count| count.parse().ok()) {
+		Some(count) => count,
+		None => anyhow::bail!("invalid LISTEN_FDS passed by systemd"),
+	};
+	let names: Vec<&str> = names
+		.as_deref()
+		.map_or_else(Vec::new, |names| names.split(':').collect());
+
+	let mut fds = Vec::new();
+	for i in 0..count {
+		let raw_fd = LISTEN_FDS_START + i;
+		fcntl(raw_fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC))?;
+
+		fds.push(ListenFd {
+			// Names are only passed if the socket units set them
+			name: names.get(i as usize).unwrap_or(&"unknown").to_string(),
+			// SAFETY: systemd passes ownership of the descriptors following LISTEN_FDS_START
+			fd: unsafe { OwnedFd::from_raw_fd(raw_fd) },
+		});
+	}
+
+	Ok(fds)
+}
+
+/// Sends a state change like `READY=1` to systemd, if the process was started with `Type=notify`.
+pub fn notify(state: &str) {
+	let path = match env::var_os("NOTIFY_SOCKET") {
+		Some(path) => path,
+		None => return,
+	};
+
+	if let Err(e) = send_notify(&path, state) {
+		warn!("Failed to notify systemd: {}", e);
+	}
+}
+
+fn send_notify(path: &OsStr, state: &str) -> io::Result<()> {
+	let addr = match path.as_bytes().strip_prefix(b"@") {
+		Some(name) => UnixSocketAddr::from_abstract_name(name)?,
+		None => UnixSocketAddr::from_pathname(path)?,
+	};
+
+	UnixDatagram::unbound()?.send_to_addr(state.as_bytes(), &addr)?;
+	Ok(())
+}
+
+/// The interval in which systemd expects keepalives, if `WatchdogSec=` is set for this process.
+fn watchdog_interval() -> Option<Duration> {
+	if let Ok(pid) = env::var("WATCHDOG_PID") {
+		if pid.parse().ok() != Some(process::id()) {
+			return None;
+		}
+	}
+
+	let usec: u64 = env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+	(usec > 0).then(|| Duration::from_micros(usec))
+}
+
+/// Sends `WATCHDOG=1` at half the watchdog interval for as long as the runtime is alive.
+pub async fn watchdog() {
+	let interval = match watchdog_interval() {
+		Some(interval) => interval,
+		None => return,
+	};
+
+	let mut interval = time::interval(interval / 2);
+	loop {
+		interval.tick().await;
+		notify("WATCHDOG=1");
+	}
+}
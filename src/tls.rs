@@ -0,0 +1,605 @@
+use crate::error;
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::{CertifiedKey, SigningKey};
+use rustls::{ProtocolVersion, ServerConnection, SignatureScheme};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::io::{Seek, SeekFrom};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use std::{fs, io};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::{select, time};
+
+/// ALPN protocol used by ACME TLS-ALPN-01 validation (RFC 8737).
+pub const ACME_TLS_ALPN_NAME: &[u8] = b"acme-tls/1";
+
+/// A certificate and private key file pair.
+#[derive(Clone, Debug)]
+pub struct CertPair {
+	pub cert_path: String,
+	pub key_path: String,
+}
+
+/// Where the certificates are loaded from.
+pub struct CertSources {
+	/// Explicitly configured certificate and key pairs.
+	pub pairs: Vec<CertPair>,
+	/// Directories containing certificate and key pairs, see [`scan_cert_dir`].
+	pub dirs: Vec<String>,
+	/// Name of the certificate used for clients that send no or an unknown SNI.
+	/// Defaults to the first loaded certificate.
+	pub default_name: Option<String>,
+	/// Pair managed by the ACME client, which is skipped until it has been issued.
+	pub acme_pair: Option<CertPair>,
+}
+
+impl CertSources {
+	/// Returns all pairs, including the ones currently found in the certificate directories.
+	fn all_pairs(&self) -> io::Result<Vec<CertPair>> {
+		let mut pairs = self.pairs.clone();
+		for dir in &self.dirs {
+			pairs.extend(scan_cert_dir(dir)?);
+		}
+		if let Some(pair) = &self.acme_pair {
+			if fs::metadata(&pair.cert_path).is_ok() {
+				pairs.push(pair.clone());
+			}
+		}
+		Ok(pairs)
+	}
+}
+
+/// The set of certificates that is currently served, indexed by DNS name.
+struct CertStore {
+	by_name: HashMap<String, Arc<CertifiedKey>>,
+	/// Only missing while waiting for the first ACME certificate.
+	default: Option<Arc<CertifiedKey>>,
+}
+
+impl CertStore {
+	fn load(sources: &CertSources) -> io::Result<Self> {
+		let pairs = sources.all_pairs()?;
+		if pairs.is_empty() && sources.acme_pair.is_none() {
+			return Err(error("no certificate and key pairs configured".into()));
+		}
+
+		let mut by_name = HashMap::new();
+		let mut claimed_by: HashMap<String, String> = HashMap::new();
+		let mut first = None;
+
+		for pair in &pairs {
+			let certified_key = Arc::new(load_certified_key(&pair.cert_path, &pair.key_path)?);
+			let names = cert_dns_names(&certified_key.cert[0])
+				.map_err(|e| error(format!("failed to parse {}: {}", pair.cert_path, e)))?;
+
+			if names.is_empty() {
+				warn!(
+					"Certificate {} has no DNS names and can only be used as the default certificate",
+					pair.cert_path
+				);
+			}
+
+			for name in names {
+				if let Some(other) = claimed_by.insert(name.clone(), pair.cert_path.clone()) {
+					return Err(error(format!(
+						"name {} is claimed by both {} and {}",
+						name, other, pair.cert_path
+					)));
+				}
+				by_name.insert(name, certified_key.clone());
+			}
+
+			first.get_or_insert(certified_key);
+		}
+
+		let default = match &sources.default_name {
+			None => first,
+			Some(name) => match lookup(&by_name, name) {
+				Some(key) => Some(key),
+				// The ACME certificate might not have been issued yet
+				None if first.is_none() => None,
+				None => {
+					return Err(error(format!(
+						"no certificate found for default name {}",
+						name
+					)))
+				}
+			},
+		};
+
+		Ok(CertStore { by_name, default })
+	}
+
+	/// The certificate for the SNI sent by a client, or the default certificate.
+	fn get(&self, server_name: Option<&str>) -> Option<Arc<CertifiedKey>> {
+		server_name
+			.and_then(|name| lookup(&self.by_name, name))
+			.or_else(|| self.default.clone())
+	}
+}
+
+/// Finds the certificate for a name, falling back to a wildcard certificate.
+fn lookup(by_name: &HashMap<String, Arc<CertifiedKey>>, name: &str) -> Option<Arc<CertifiedKey>> {
+	let name = name.trim_end_matches('.').to_ascii_lowercase();
+
+	if let Some(key) = by_name.get(&name) {
+		return Some(key.clone());
+	}
+
+	let (_, parent) = name.split_once('.')?;
+	by_name.get(&format!("*.{}", parent)).cloned()
+}
+
+/// Certificate resolver shared by all TLS listeners. It selects the
+/// certificate by the SNI sent by the client.
+///
+/// The certificates can be swapped out at any time with [`CertResolver::reload`].
+/// Connections that already completed their handshake are unaffected.
+pub struct CertResolver {
+	sources: CertSources,
+	current: RwLock<Arc<CertStore>>,
+	/// Certificates for ACME TLS-ALPN-01 challenges, indexed by DNS name.
+	alpn_challenges: RwLock<HashMap<String, Arc<CertifiedKey>>>,
+}
+
+impl CertResolver {
+	pub fn new(sources: CertSources) -> io::Result<Self> {
+		let store = CertStore::load(&sources)?;
+
+		for (name, _) in store.by_name.iter() {
+			info!("Loaded certificate for {}", name);
+		}
+
+		Ok(CertResolver {
+			sources,
+			current: RwLock::new(Arc::new(store)),
+			alpn_challenges: RwLock::new(HashMap::new()),
+		})
+	}
+
+	/// Re-reads all certificates and keys from disk. The previous
+	/// certificates are kept if any pair fails to load or validate.
+	pub fn reload(&self) -> io::Result<()> {
+		let store = CertStore::load(&self.sources)?;
+		*self.current.write().unwrap() = Arc::new(store);
+		Ok(())
+	}
+
+	/// Serves the given certificate to `acme-tls/1` handshakes for a name.
+	pub fn set_alpn_challenge(&self, name: &str, certified_key: CertifiedKey) {
+		self.alpn_challenges
+			.write()
+			.unwrap()
+			.insert(name.to_ascii_lowercase(), Arc::new(certified_key));
+	}
+
+	pub fn clear_alpn_challenge(&self, name: &str) {
+		self.alpn_challenges
+			.write()
+			.unwrap()
+			.remove(&name.to_ascii_lowercase());
+	}
+}
+
+impl ResolvesServerCert for CertResolver {
+	fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+		let is_acme_challenge = client_hello
+			.alpn()
+			.is_some_and(|mut protocols| protocols.any(|p| p == ACME_TLS_ALPN_NAME));
+
+		if is_acme_challenge {
+			let name = client_hello.server_name()?.to_ascii_lowercase();
+			return self.alpn_challenges.read().unwrap().get(&name).cloned();
+		}
+
+		let store = self.current.read().unwrap().clone();
+		store.get(client_hello.server_name())
+	}
+}
+
+/// Reloads the certificates when the files on disk change or when SIGHUP is received.
+pub async fn watch_certs(resolver: Arc<CertResolver>, check_interval: u64) {
+	let mut sighup = signal(SignalKind::hangup()).expect("failed to initialize SIGHUP handler");
+
+	let mut interval = time::interval(Duration::from_secs(check_interval.max(1)));
+	interval.tick().await;
+
+	let mut prev_stamps = file_stamps(&resolver.sources);
+
+	loop {
+		select! {
+			_ = interval.tick(), if check_interval > 0 => {
+				let stamps = file_stamps(&resolver.sources);
+				if stamps == prev_stamps {
+					continue;
+				}
+				// Remember the attempted state even if the reload fails, so that
+				// a half-written pair is retried once the other file changes.
+				prev_stamps = stamps;
+				info!("Certificate files changed on disk. Reloading...");
+			},
+			_ = sighup.recv() => {
+				prev_stamps = file_stamps(&resolver.sources);
+				info!("Received hangup signal. Reloading certificates...");
+			},
+		}
+
+		match resolver.reload() {
+			Ok(()) => info!("Certificates reloaded"),
+			Err(e) => error!(
+				"Failed to reload certificates, keeping the previous ones: {}",
+				e
+			),
+		}
+	}
+}
+
+type FileStamp = Option<(SystemTime, u64)>;
+
+fn file_stamps(sources: &CertSources) -> Vec<(String, FileStamp)> {
+	let stamp = |path: &str| {
+		fs::metadata(path)
+			.and_then(|m| Ok((m.modified()?, m.len())))
+			.ok()
+	};
+
+	let mut stamps = Vec::new();
+	for pair in sources.all_pairs().unwrap_or_default() {
+		stamps.push((pair.cert_path.clone(), stamp(&pair.cert_path)));
+		stamps.push((pair.key_path.clone(), stamp(&pair.key_path)));
+	}
+	stamps
+}
+
+/// Finds the certificate and key pairs in a directory. Two layouts are supported:
+/// `<name>.crt` next to `<name>.key`, and subdirectories containing `fullchain.pem`
+/// and `privkey.pem` like the `live` directory of certbot.
+fn scan_cert_dir(dir: &str) -> io::Result<Vec<CertPair>> {
+	let entries =
+		fs::read_dir(dir).map_err(|e| error(format!("failed to read directory {}: {}", dir, e)))?;
+
+	let mut pairs = Vec::new();
+
+	for entry in entries {
+		let path = entry?.path();
+
+		let (cert_path, key_path) = if path.is_dir() {
+			(path.join("fullchain.pem"), path.join("privkey.pem"))
+		} else if path.extension().is_some_and(|ext| ext == "crt") {
+			(path.clone(), path.with_extension("key"))
+		} else {
+			continue;
+		};
+
+		if !cert_path.is_file() || !key_path.is_file() {
+			continue;
+		}
+
+		pairs.push(CertPair {
+			cert_path: cert_path.to_string_lossy().into_owned(),
+			key_path: key_path.to_string_lossy().into_owned(),
+		});
+	}
+
+	// Directory order is arbitrary, keep the default certificate stable
+	pairs.sort_by(|a, b| a.cert_path.cmp(&b.cert_path));
+
+	Ok(pairs)
+}
+
+/// Returns the lowercased DNS names in the subject alternative names of a certificate.
+pub(crate) fn cert_dns_names(cert: &rustls::Certificate) -> Result<Vec<String>, webpki::Error> {
+	let ee = webpki::EndEntityCert::try_from(cert.0.as_slice())?;
+	let names = ee.dns_names()?;
+
+	Ok(names
+		.map(|name| <&str>::from(name).to_ascii_lowercase())
+		.collect())
+}
+
+fn load_certified_key(cert_path: &str, key_path: &str) -> io::Result<CertifiedKey> {
+	let certs = load_certs(cert_path)?;
+	let key = load_private_key(key_path)?;
+
+	let signing_key = rustls::sign::any_supported_type(&key)
+		.map_err(|_| error(format!("unsupported private key type in file {}", key_path)))?;
+
+	match certs.first() {
+		None => {
+			return Err(error(format!(
+				"no certificates found in file {}",
+				cert_path
+			)))
+		}
+		Some(cert) => check_key_matches(cert, signing_key.as_ref()).map_err(|e| {
+			error(format!(
+				"private key in {} does not match certificate in {}: {}",
+				key_path, cert_path, e
+			))
+		})?,
+	}
+
+	Ok(CertifiedKey::new(certs, signing_key))
+}
+
+/// Signs a test message with the private key and verifies
+/// it against the public key of the end-entity certificate.
+fn check_key_matches(cert: &rustls::Certificate, key: &dyn SigningKey) -> io::Result<()> {
+	let signer = key
+		.choose_scheme(&[
+			SignatureScheme::ECDSA_NISTP256_SHA256,
+			SignatureScheme::ECDSA_NISTP384_SHA384,
+			SignatureScheme::ED25519,
+			SignatureScheme::RSA_PSS_SHA256,
+		])
+		.ok_or_else(|| error("no supported signature scheme for private key".into()))?;
+
+	let algorithm = match signer.scheme() {
+		SignatureScheme::ECDSA_NISTP256_SHA256 => &webpki::ECDSA_P256_SHA256,
+		SignatureScheme::ECDSA_NISTP384_SHA384 => &webpki::ECDSA_P384_SHA384,
+		SignatureScheme::ED25519 => &webpki::ED25519,
+		_ => &webpki::RSA_PSS_2048_8192_SHA256_LEGACY_KEY,
+	};
+
+	let message = b"wut-server certificate key check";
+	let signature = signer
+		.sign(message)
+		.map_err(|e| error(format!("failed to sign: {}", e)))?;
+
+	webpki::EndEntityCert::try_from(cert.0.as_slice())
+		.and_then(|ee| ee.verify_signature(algorithm, message, &signature))
+		.map_err(|e| error(format!("{:?}", e)))
+}
+
+pub(crate) fn load_certs(filename: &str) -> io::Result<Vec<rustls::Certificate>> {
+	let cert_file = fs::File::open(filename)
+		.map_err(|e| error(format!("failed to open {}: {}", filename, e)))?;
+	let mut reader = io::BufReader::new(cert_file);
+
+	let certs = rustls_pemfile::certs(&mut reader)
+		.map_err(|_| error("failed to load certificate".into()))?;
+	Ok(certs.into_iter().map(rustls::Certificate).collect())
+}
+
+fn load_private_key(filename: &str) -> io::Result<rustls::PrivateKey> {
+	let keyfile = fs::File::open(filename)
+		.map_err(|e| error(format!("failed to open {}: {}", filename, e)))?;
+	let mut reader = io::BufReader::new(keyfile);
+
+	let ec_keys = {
+		reader.seek(SeekFrom::Start(0))?;
+		rustls_pemfile::ec_private_keys(&mut reader)
+			.map_err(|_| error("failed to read EC private keys".into()))?
+	};
+
+	let pkcs8_keys = {
+		reader.seek(SeekFrom::Start(0))?;
+		rustls_pemfile::pkcs8_private_keys(&mut reader)
+			.map_err(|_| error("failed to read PKCS8 private keys".into()))?
+	};
+
+	let rsa_keys = {
+		reader.seek(SeekFrom::Start(0))?;
+		rustls_pemfile::rsa_private_keys(&mut reader)
+			.map_err(|_| error("failed to read RSA private keys".into()))?
+	};
+
+	let total_keys = ec_keys.len() + pkcs8_keys.len() + rsa_keys.len();
+
+	match (
+		ec_keys.first(),
+		pkcs8_keys.first(),
+		rsa_keys.first(),
+		total_keys,
+	) {
+		(Some(ec_key), _, _, 1) => Ok(rustls::PrivateKey(ec_key.clone())),
+		(_, Some(pkcs8_key), _, 1) => Ok(rustls::PrivateKey(pkcs8_key.clone())),
+		(_, _, Some(rsa_key), 1) => Ok(rustls::PrivateKey(rsa_key.clone())),
+		(_, _, _, 0) => Err(error(format!("no private keys found in file {}", filename))),
+		_ => Err(error(format!(
+			"expected a single private key in file {}",
+			filename
+		))),
+	}
+}
+
+/// What was negotiated on a TLS connection, as echoed on the `/tls` endpoint.
+#[derive(Clone, Debug, Serialize)]
+pub struct TlsInfo {
+	pub version: &'static str,
+	pub cipher: &'static str,
+	pub alpn: Option<String>,
+	/// Server name sent by the client, if any
+	pub sni: Option<String>,
+	/// Only known for TLS 1.3, since rustls does not expose whether a TLS 1.2
+	/// session was resumed
+	pub resumed: Option<bool>,
+}
+
+impl TlsInfo {
+	/// Captures the connection details once the handshake has completed.
+	pub fn new(conn: &ServerConnection) -> Self {
+		let (version, resumed) = match conn.protocol_version() {
+			Some(ProtocolVersion::TLSv1_2) => ("TLSv1.2", None),
+			Some(ProtocolVersion::TLSv1_3) => {
+				("TLSv1.3", Some(conn.received_resumption_data().is_some()))
+			}
+			_ => ("unknown", None),
+		};
+
+		TlsInfo {
+			version,
+			cipher: conn
+				.negotiated_cipher_suite()
+				.and_then(|suite| suite.suite().as_str())
+				.unwrap_or("unknown"),
+			alpn: conn
+				.alpn_protocol()
+				.map(|alpn| String::from_utf8_lossy(alpn).into_owned()),
+			sni: conn.server_name().map(str::to_owned),
+			resumed,
+		}
+	}
+
+	/// The details quinn exposes for a QUIC connection, which always uses TLS 1.3.
+	pub fn quic(data: &quinn::crypto::rustls::HandshakeData) -> Self {
+		TlsInfo {
+			version: "TLSv1.3",
+			cipher: "unknown",
+			alpn: data
+				.protocol
+				.as_ref()
+				.map(|alpn| String::from_utf8_lossy(alpn).into_owned()),
+			sni: data.server_name.clone(),
+			resumed: None,
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::path::{Path, PathBuf};
+
+	/// Writes a self-signed certificate for the names as `<file_name>.crt` and `.key`.
+	fn write_cert(dir: &Path, file_name: &str, names: &[&str]) -> CertPair {
+		let names = names
+			.iter()
+			.map(|name| name.to_string())
+			.collect::<Vec<_>>();
+		let cert = rcgen::generate_simple_self_signed(names).unwrap();
+		let pair = CertPair {
+			cert_path: dir
+				.join(format!("{}.crt", file_name))
+				.to_string_lossy()
+				.into_owned(),
+			key_path: dir
+				.join(format!("{}.key", file_name))
+				.to_string_lossy()
+				.into_owned(),
+		};
+		fs::write(&pair.cert_path, cert.serialize_pem().unwrap()).unwrap();
+		fs::write(&pair.key_path, cert.serialize_private_key_pem()).unwrap();
+		pair
+	}
+
+	fn temp_dir(name: &str) -> PathBuf {
+		let dir = std::env::temp_dir().join(format!("wut-tls-{}-{}", name, std::process::id()));
+		let _ = fs::remove_dir_all(&dir);
+		fs::create_dir_all(&dir).unwrap();
+		dir
+	}
+
+	fn sources(pairs: Vec<CertPair>) -> CertSources {
+		CertSources {
+			pairs,
+			dirs: Vec::new(),
+			default_name: None,
+			acme_pair: None,
+		}
+	}
+
+	fn served_names(store: &CertStore, key: &Arc<CertifiedKey>) -> Vec<String> {
+		cert_dns_names(&key.cert[0])
+			.unwrap()
+			.into_iter()
+			.filter(|name| store.by_name.contains_key(name))
+			.collect()
+	}
+
+	#[test]
+	fn lookup_names() {
+		let dir = temp_dir("lookup");
+		let exact = write_cert(&dir, "exact", &["www.example.com", "example.com"]);
+		let wildcard = write_cert(&dir, "wildcard", &["*.example.com"]);
+		let store = CertStore::load(&sources(vec![exact, wildcard])).unwrap();
+
+		let name_of =
+			|sni: &str| lookup(&store.by_name, sni).map(|key| served_names(&store, &key).join(","));
+
+		assert_eq!(
+			name_of("www.example.com").unwrap(),
+			"www.example.com,example.com"
+		);
+		assert_eq!(
+			name_of("WWW.Example.COM.").unwrap(),
+			"www.example.com,example.com"
+		);
+		assert_eq!(
+			name_of("example.com").unwrap(),
+			"www.example.com,example.com"
+		);
+		assert_eq!(name_of("a.example.com").unwrap(), "*.example.com");
+		assert_eq!(name_of("a.b.example.com"), None);
+		assert_eq!(name_of("example.org"), None);
+		assert_eq!(name_of("com"), None);
+
+		fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn wildcard_does_not_match_parent() {
+		let dir = temp_dir("wildcard");
+		let wildcard = write_cert(&dir, "wildcard", &["*.example.com"]);
+		let store = CertStore::load(&sources(vec![wildcard])).unwrap();
+
+		assert!(lookup(&store.by_name, "a.example.com").is_some());
+		assert!(lookup(&store.by_name, "example.com").is_none());
+		assert!(lookup(&store.by_name, "a.b.example.com").is_none());
+
+		fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn default_certificate() {
+		let dir = temp_dir("default");
+		write_cert(&dir, "a", &["a.example.com"]);
+		write_cert(&dir, "b", &["b.example.com"]);
+		let mut sources = sources(Vec::new());
+		sources.dirs.push(dir.to_string_lossy().into_owned());
+
+		// The first certificate in directory order is the default
+		let store = CertStore::load(&sources).unwrap();
+		let name_of = |sni| served_names(&store, &store.get(sni).unwrap());
+		assert_eq!(name_of(Some("b.example.com")), ["b.example.com"]);
+		assert_eq!(name_of(Some("unknown.example.org")), ["a.example.com"]);
+		assert_eq!(name_of(None), ["a.example.com"]);
+
+		sources.default_name = Some("b.example.com".into());
+		let store = CertStore::load(&sources).unwrap();
+		let name_of = |sni| served_names(&store, &store.get(sni).unwrap());
+		assert_eq!(name_of(Some("a.example.com")), ["a.example.com"]);
+		assert_eq!(name_of(Some("unknown.example.org")), ["b.example.com"]);
+		assert_eq!(name_of(None), ["b.example.com"]);
+
+		sources.default_name = Some("c.example.com".into());
+		let err = CertStore::load(&sources).err().unwrap();
+		assert_eq!(
+			err.to_string(),
+			"no certificate found for default name c.example.com"
+		);
+
+		fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn duplicate_names() {
+		let dir = temp_dir("duplicate");
+		let a = write_cert(&dir, "a", &["a.example.com", "www.example.com"]);
+		let b = write_cert(&dir, "b", &["b.example.com", "WWW.example.com"]);
+
+		let err = CertStore::load(&sources(vec![a.clone(), b.clone()]))
+			.err()
+			.unwrap();
+		assert_eq!(
+			err.to_string(),
+			format!(
+				"name www.example.com is claimed by both {} and {}",
+				a.cert_path, b.cert_path
+			)
+		);
+
+		fs::remove_dir_all(&dir).unwrap();
+	}
+}
@@ -0,0 +1,267 @@
+//! Zero-downtime upgrades. On `SIGUSR2`, the binary is started again with the same
+//! arguments and inherits all listening sockets, so connections keep being accepted
+//! while this process drains its own connections and exits.
+
+use crate::listener::Listener;
+use crate::systemd::{self, ListenFd, LISTEN_FDS_START};
+use anyhow::Result;
+use nix::fcntl::{fcntl, FcntlArg, FdFlag};
+use nix::unistd::{dup2, pipe};
+use std::env;
+use std::fs::File;
+use std::io;
+use std::net::{SocketAddr, TcpListener, UdpSocket};
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd};
+use std::path::PathBuf;
+use std::process;
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
+use tokio::net::unix::pipe::Receiver;
+use tokio::process::Command;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+use tokio::{select, time};
+
+/// Keys of the inherited sockets, one per line, starting at `LISTEN_FDS_START`
+const INHERITED_FDS_VAR: &str = "WUT_INHERITED_FDS";
+/// Descriptor the new process writes to once all listeners are up
+const READY_FD_VAR: &str = "WUT_READY_FD";
+
+/// Time the new process gets to start before the upgrade is aborted
+const READY_TIMEOUT: Duration = Duration::from_secs(30);
+
+static HANDED_OVER: LazyLock<watch::Sender<bool>> = LazyLock::new(|| watch::channel(false).0);
+
+/// Listening sockets inherited from systemd or the previous process, and those to
+/// pass on to the next one. Each socket is identified by a key like `stun:[::]:3478`.
+pub struct Sockets {
+	inherited: Vec<ListenFd>,
+	handover: Vec<(String, OwnedFd)>,
+	ready: Option<OwnedFd>,
+	/// Path of the binary, taken at startup since `/proc/self/exe` points to a deleted
+	/// file once a new binary was installed in its place
+	executable: PathBuf,
+}
+
+impl Sockets {
+	/// Takes the sockets passed by systemd or the previous process. This modifies the
+	/// environment, so it must be called before any threads are started.
+	pub fn inherit() -> Result<Self> {
+		let mut inherited: Vec<ListenFd> = systemd::listen_fds()?
+			.into_iter()
+			.map(|fd| ListenFd {
+				name: format!("systemd:{}", fd.name),
+				fd: fd.fd,
+			})
+			.collect();
+
+		let keys = env::var(INHERITED_FDS_VAR).ok();
+		let ready = env::var(READY_FD_VAR).ok();
+		env::remove_var(INHERITED_FDS_VAR);
+		env::remove_var(READY_FD_VAR);
+
+		for (i, key) in keys.iter().flat_map(|keys| keys.lines()).enumerate() {
+			inherited.push(ListenFd {
+				name: key.to_string(),
+				fd: take_fd(LISTEN_FDS_START + i as RawFd)?,
+			});
+		}
+
+		let ready = match ready.map(|fd| fd.parse()) {
+			Some(Ok(fd)) => Some(take_fd(fd)?),
+			Some(Err(_)) => anyhow::bail!("invalid {}", READY_FD_VAR),
+			None => None,
+		};
+
+		Ok(Sockets {
+			inherited,
+			handover: Vec::new(),
+			ready,
+			executable: env::current_exe()?,
+		})
+	}
+
+	/// Takes the inherited sockets for a key, which is empty if it has to be bound.
+	pub fn take(&mut self, key: &str) -> Vec<ListenFd> {
+		let (fds, rest) = self.inherited.drain(..).partition(|fd| fd.name == key);
+		self.inherited = rest;
+		fds
+	}
+
+	/// Adds a socket that is handed over in an upgrade.
+	pub fn register(&mut self, key: &str, fd: BorrowedFd) -> io::Result<()> {
+		self.handover
+			.push((key.to_string(), fd.try_clone_to_owned()?));
+		Ok(())
+	}
+
+	fn copy_handover(&self) -> io::Result<Vec<(String, OwnedFd)>> {
+		self.handover
+			.iter()
+			.map(|(key, fd)| Ok((key.clone(), fd.try_clone()?)))
+			.collect()
+	}
+
+	/// Removes the sockets of a key that was closed.
+	pub fn unregister(&mut self, key: &str) {
+		self.handover.retain(|(name, _)| name != key);
+	}
+
+	/// Takes over or binds a TCP listener.
+	pub fn tcp(&mut self, key: &str, addr: SocketAddr) -> Result<tokio::net::TcpListener> {
+		let listener = match self.take(key).pop() {
+			Some(fd) => TcpListener::from(fd.fd),
+			None => TcpListener::bind(addr)?,
+		};
+		listener.set_nonblocking(true)?;
+		self.register(key, listener.as_fd())?;
+		Ok(tokio::net::TcpListener::from_std(listener)?)
+	}
+
+	/// Takes over or binds a UDP socket.
+	pub fn udp(&mut self, key: &str, addr: SocketAddr) -> Result<UdpSocket> {
+		let socket = match self.take(key).pop() {
+			Some(fd) => UdpSocket::from(fd.fd),
+			None => UdpSocket::bind(addr)?,
+		};
+		socket.set_nonblocking(true)?;
+		self.register(key, socket.as_fd())?;
+		Ok(socket)
+	}
+
+	/// Reports that all listeners are up, to the previous process if this is an upgrade.
+	pub fn ready(&mut self) {
+		for fd in self.inherited.drain(..) {
+			warn!("Ignoring inherited socket {}", fd.name);
+		}
+
+		match self.ready.take() {
+			Some(ready) => {
+				// The service manager has to follow the new process for it to outlive the old one
+				systemd::notify(&format!("MAINPID={}\nREADY=1", process::id()));
+				if let Err(e) = io::Write::write_all(&mut File::from(ready), b"1") {
+					warn!("Failed to report readiness to the previous process: {}", e);
+				}
+			}
+			None => systemd::notify("READY=1"),
+		}
+	}
+}
+
+impl AsFd for Listener {
+	fn as_fd(&self) -> BorrowedFd<'_> {
+		match self {
+			Listener::Tcp(listener) => listener.as_fd(),
+			Listener::Unix(listener) => listener.as_fd(),
+		}
+	}
+}
+
+/// Takes ownership of an inherited descriptor, which is not passed on to child processes by default.
+fn take_fd(raw_fd: RawFd) -> Result<OwnedFd> {
+	fcntl(raw_fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC)).map_err(|e| {
+		anyhow::Error::msg(format!("invalid inherited descriptor {}: {}", raw_fd, e))
+	})?;
+	// SAFETY: the previous process passes ownership of the descriptors listed in the environment
+	Ok(unsafe { OwnedFd::from_raw_fd(raw_fd) })
+}
+
+/// Resolves once the sockets were handed over to a new process.
+pub async fn handed_over() {
+	let _ = HANDED_OVER
+		.subscribe()
+		.wait_for(|handed_over| *handed_over)
+		.await;
+}
+
+pub fn is_handed_over() -> bool {
+	*HANDED_OVER.borrow()
+}
+
+/// Starts a new process on `SIGUSR2`, and shuts this one down once it is ready.
+/// A failed upgrade is logged and this process keeps serving.
+pub async fn watch(sockets: Arc<Mutex<Sockets>>) {
+	let mut sigusr2 =
+		signal(SignalKind::user_defined2()).expect("failed to initialize SIGUSR2 handler");
+
+	while sigusr2.recv().await.is_some() {
+		info!("Received upgrade signal. Starting new process...");
+
+		match upgrade(&sockets).await {
+			Ok(pid) => {
+				info!(pid; "New process {} is ready. Handing over...", pid);
+				HANDED_OVER.send_replace(true);
+				return;
+			}
+			Err(e) => error!("Failed to upgrade: {}", e),
+		}
+	}
+}
+
+async fn upgrade(sockets: &Mutex<Sockets>) -> Result<u32> {
+	// Copied, since a reload can change the sockets while the new process starts
+	let (handover, executable) = {
+		let sockets = sockets.lock().unwrap();
+		(sockets.copy_handover()?, sockets.executable.clone())
+	};
+	let (ready_rx, ready_tx) = pipe()?;
+
+	// The sockets are moved to consecutive descriptors in the new process, followed by
+	// the readiness pipe. They are first copied above that range, so that none of them
+	// is overwritten before it was moved.
+	let ready_fd = LISTEN_FDS_START + handover.len() as RawFd;
+	let mut fds = Vec::new();
+	for fd in handover
+		.iter()
+		.map(|(_, fd)| fd.as_fd())
+		.chain([ready_tx.as_fd()])
+	{
+		let copy = fcntl(fd.as_raw_fd(), FcntlArg::F_DUPFD_CLOEXEC(ready_fd + 1))?;
+		// SAFETY: the descriptor was just created and is not owned by anything else
+		fds.push(unsafe { OwnedFd::from_raw_fd(copy) });
+	}
+	drop(ready_tx);
+
+	let keys: Vec<&str> = handover.iter().map(|(key, _)| key.as_str()).collect();
+	let raw_fds: Vec<RawFd> = fds.iter().map(|fd| fd.as_raw_fd()).collect();
+
+	let mut command = Command::new(executable);
+	command
+		.args(env::args_os().skip(1))
+		// The watchdog and socket variables name this process, so the new one would ignore them
+		.env_remove("WATCHDOG_PID")
+		.env_remove("LISTEN_PID")
+		.env_remove("LISTEN_FDS")
+		.env_remove("LISTEN_FDNAMES")
+		.env(INHERITED_FDS_VAR, keys.join("\n"))
+		.env(READY_FD_VAR, ready_fd.to_string());
+	// SAFETY: only dup2 is called between fork and exec, which is async-signal-safe
+	unsafe {
+		command.pre_exec(move || {
+			for (i, fd) in raw_fds.iter().enumerate() {
+				dup2(*fd, LISTEN_FDS_START + i as RawFd)?;
+			}
+			Ok(())
+		});
+	}
+
+	let mut child = command.spawn()?;
+	let pid = child.id().unwrap_or_default();
+	drop(fds);
+
+	let mut ready = Receiver::from_file(File::from(ready_rx))?;
+	let mut buf = [0; 1];
+
+	let e = select! {
+		res = ready.read(&mut buf) => match res {
+			Ok(1) => return Ok(pid),
+			Ok(_) => anyhow::Error::msg("new process exited before it was ready"),
+			Err(e) => e.into(),
+		},
+		_ = time::sleep(READY_TIMEOUT) => anyhow::Error::msg("new process did not become ready in time"),
+	};
+
+	let _ = child.kill().await;
+	Err(e)
+}